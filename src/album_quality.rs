@@ -0,0 +1,90 @@
+//! `dedupe_album_qualities`: keeps only the largest of album items that look
+//! like the same picture or recording in different qualities.
+//!
+//! Items are grouped by a heuristic key: the media type plus the aspect ratio
+//! for photos, aspect ratio and duration for videos, duration for audio.
+
+use std::{collections::HashMap, path::PathBuf, sync::Mutex};
+
+pub enum Decision {
+    Download,
+    /// A larger version of this item was already saved or is being saved.
+    Skip,
+    /// Download, then remove this smaller version saved before.
+    Replace(PathBuf),
+}
+
+struct Entry {
+    key: String,
+    size: u32,
+    /// `None` while the download is still running.
+    path: Option<PathBuf>,
+}
+
+#[derive(Default)]
+pub struct AlbumQualities {
+    albums: Mutex<HashMap<String, Vec<Entry>>>,
+}
+
+impl AlbumQualities {
+    pub fn decide(&self, media_group_id: &str, key: &str, size: u32) -> Decision {
+        let mut albums = self.albums.lock().unwrap();
+        let entries = albums.entry(media_group_id.to_owned()).or_default();
+        match entries.iter_mut().find(|entry| entry.key == key) {
+            Some(entry) if entry.size >= size => Decision::Skip,
+            Some(entry) => {
+                entry.size = size;
+                match entry.path.take() {
+                    Some(smaller) => Decision::Replace(smaller),
+                    None => Decision::Download,
+                }
+            }
+            None => {
+                entries.push(Entry {
+                    key: key.to_owned(),
+                    size,
+                    path: None,
+                });
+                Decision::Download
+            }
+        }
+    }
+
+    /// Records where the version of `size` bytes ended up.
+    pub fn saved(&self, media_group_id: &str, key: &str, size: u32, path: PathBuf) {
+        let mut albums = self.albums.lock().unwrap();
+        if let Some(entry) = albums
+            .get_mut(media_group_id)
+            .and_then(|entries| entries.iter_mut().find(|entry| entry.key == key))
+        {
+            if entry.size == size {
+                entry.path = Some(path);
+            }
+        }
+    }
+
+    /// Forgets a download of `size` bytes that failed, so a retry isn't
+    /// skipped in favor of a version that was never saved.
+    pub fn failed(&self, media_group_id: &str, key: &str, size: u32) {
+        let mut albums = self.albums.lock().unwrap();
+        if let Some(entries) = albums.get_mut(media_group_id) {
+            entries.retain(|entry| entry.key != key || entry.size != size || entry.path.is_some());
+        }
+    }
+}
+
+pub fn photo_key(width: u32, height: u32) -> String {
+    format!("photo:{:.2}", aspect_ratio(width, height))
+}
+
+pub fn video_key(width: u32, height: u32, duration: u32) -> String {
+    format!("video:{:.2}:{duration}", aspect_ratio(width, height))
+}
+
+pub fn audio_key(duration: u32) -> String {
+    format!("audio:{duration}")
+}
+
+fn aspect_ratio(width: u32, height: u32) -> f64 {
+    f64::from(width) / f64::from(height.max(1))
+}
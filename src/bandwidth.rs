@@ -0,0 +1,160 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::AsyncWrite,
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::Sleep,
+};
+
+/// Whether `max_bytes_per_sec` is shared by all downloads or applies to each
+/// of them separately.
+#[derive(Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BandwidthScope {
+    #[default]
+    Global,
+    PerDownload,
+}
+
+/// Token bucket refilled at `rate` bytes per second, holding at most one
+/// second worth of tokens.
+pub struct TokenBucket {
+    rate: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec.max(1) as f64;
+        Self {
+            rate,
+            state: Mutex::new(BucketState {
+                tokens: rate,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes tokens for up to `want` bytes, or says how long to wait until a
+    /// reasonable amount is available.
+    fn take(&self, want: usize) -> Result<usize, Duration> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.refilled_at).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.rate).min(self.rate);
+        state.refilled_at = now;
+
+        if state.tokens >= 1.0 {
+            let n = want.min(state.tokens as usize);
+            state.tokens -= n as f64;
+            return Ok(n);
+        }
+        // wait for a batch instead of waking up for every byte
+        let batch = (want as f64).min(self.rate / 10.0).max(1.0);
+        Err(Duration::from_secs_f64((batch - state.tokens) / self.rate))
+    }
+
+    fn refund(&self, n: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.tokens = (state.tokens + n as f64).min(self.rate);
+    }
+}
+
+/// Passes writes through to `inner` no faster than `bucket` allows.
+pub struct RateLimitedWriter<W> {
+    inner: W,
+    bucket: Arc<TokenBucket>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<W> RateLimitedWriter<W> {
+    pub fn new(inner: W, bucket: Arc<TokenBucket>) -> Self {
+        Self {
+            inner,
+            bucket,
+            sleep: None,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for RateLimitedWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        loop {
+            if let Some(sleep) = &mut this.sleep {
+                if sleep.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                this.sleep = None;
+            }
+            match this.bucket.take(buf.len()) {
+                Ok(allowed) => {
+                    let result = Pin::new(&mut this.inner).poll_write(cx, &buf[..allowed]);
+                    let written = match &result {
+                        Poll::Ready(Ok(n)) => *n,
+                        _ => 0,
+                    };
+                    this.bucket.refund(allowed - written);
+                    return result;
+                }
+                Err(wait) => this.sleep = Some(Box::pin(tokio::time::sleep(wait))),
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Bounds the total size of the downloads running at once, see
+/// `max_inflight_bytes`. Sizes are counted in KiB so they fit semaphore permits.
+pub struct InflightLimit {
+    permits: Arc<Semaphore>,
+    capacity: u32,
+}
+
+impl InflightLimit {
+    pub fn new(max_bytes: u64) -> Self {
+        let capacity = (max_bytes / 1024).clamp(1, u64::from(u32::MAX)) as u32;
+        Self {
+            permits: Arc::new(Semaphore::new(capacity as usize)),
+            capacity,
+        }
+    }
+
+    /// Waits until a download of `size` bytes fits. Files larger than the whole
+    /// limit wait for everything else to finish and then run alone.
+    pub async fn acquire(&self, size: u64) -> OwnedSemaphorePermit {
+        let weight = size.div_ceil(1024).clamp(1, u64::from(self.capacity)) as u32;
+        self.permits
+            .clone()
+            .acquire_many_owned(weight)
+            .await
+            .expect("the semaphore is never closed")
+    }
+}
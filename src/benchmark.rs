@@ -0,0 +1,60 @@
+//! `benchmark` subcommand: downloads one file repeatedly through the same
+//! client and copy path the bot uses and reports throughput and latency, to
+//! help tune a host.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{copy_telegram_file, inspect::InspectingWriter, TelegramBot};
+
+pub const DEFAULT_ITERATIONS: usize = 10;
+
+pub async fn run(bot: &impl TelegramBot, file_id: &str, iterations: usize) -> Result<()> {
+    if iterations == 0 {
+        bail!("Iteration count must be at least 1");
+    }
+    let file = bot.get_file(file_id).await.context("get_file failed")?;
+    println!(
+        "Downloading {file_id} ({} bytes) {iterations} times",
+        file.meta.size
+    );
+
+    let mut latencies = Vec::with_capacity(iterations);
+    let mut total_bytes = 0u64;
+    for iteration in 1..=iterations {
+        let mut sink = InspectingWriter::new(tokio::io::sink(), 0);
+        let started = Instant::now();
+        copy_telegram_file(bot, &file.path, &mut sink)
+            .await
+            .with_context(|| format!("Download {iteration} failed"))?;
+        let elapsed = started.elapsed();
+        println!(
+            "#{iteration}: {} bytes in {:.3}s",
+            sink.bytes_written(),
+            elapsed.as_secs_f64()
+        );
+        total_bytes += sink.bytes_written();
+        latencies.push(elapsed);
+    }
+
+    let total_time: Duration = latencies.iter().sum();
+    latencies.sort();
+    println!(
+        "Throughput: {:.2} MiB/s",
+        total_bytes as f64 / (1024.0 * 1024.0) / total_time.as_secs_f64().max(f64::EPSILON)
+    );
+    for percentile in [50, 90, 99] {
+        println!(
+            "p{percentile}: {:.3}s",
+            percentile_of(&latencies, percentile).as_secs_f64()
+        );
+    }
+    Ok(())
+}
+
+/// Nearest-rank percentile of the already sorted `sorted`.
+fn percentile_of(sorted: &[Duration], percentile: usize) -> Duration {
+    let rank = (percentile * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
@@ -0,0 +1,74 @@
+//! A map that forgets its oldest entries, for state kept per media group that
+//! would otherwise grow for as long as the bot runs.
+
+use std::collections::{HashMap, VecDeque};
+
+pub struct BoundedMap<V> {
+    capacity: usize,
+    entries: HashMap<String, V>,
+    /// Keys from the oldest to the newest entry.
+    order: VecDeque<String>,
+}
+
+impl<V> BoundedMap<V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Inserts or replaces the entry of `key` as the newest one, dropping the
+    /// oldest entries past the capacity.
+    pub fn insert(&mut self, key: String, value: V) {
+        if self.entries.insert(key.clone(), value).is_some() {
+            self.order.retain(|other| other != &key);
+        }
+        self.order.push_back(key);
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<V> {
+        let value = self.entries.remove(key)?;
+        self.order.retain(|other| other != key);
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oldest_entries_are_dropped() {
+        let mut map = BoundedMap::new(2);
+        map.insert("a".to_owned(), 1);
+        map.insert("b".to_owned(), 2);
+        map.insert("c".to_owned(), 3);
+        assert!(!map.contains("a"));
+        assert!(map.contains("b") && map.contains("c"));
+    }
+
+    #[test]
+    fn replaced_entries_become_the_newest() {
+        let mut map = BoundedMap::new(2);
+        map.insert("a".to_owned(), 1);
+        map.insert("b".to_owned(), 2);
+        map.insert("a".to_owned(), 3);
+        map.insert("c".to_owned(), 4);
+        assert!(!map.contains("b"));
+        assert_eq!(map.remove("a"), Some(3));
+        assert_eq!(map.remove("a"), None);
+        map.insert("d".to_owned(), 5);
+        assert!(map.contains("c") && map.contains("d"));
+    }
+}
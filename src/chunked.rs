@@ -0,0 +1,103 @@
+//! `parallel_chunks`: downloads a file from the Bot API server with several
+//! concurrent Range requests instead of one stream.
+
+use std::{io::SeekFrom, path::Path};
+
+use anyhow::{bail, Context, Result};
+use futures::{future, StreamExt};
+use reqwest::{header, StatusCode, Url};
+use teloxide::Bot;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+/// Files are only split into chunks of at least this size, below it the
+/// extra requests cost more than they gain.
+const MIN_CHUNK_BYTES: u64 = 1024 * 1024;
+
+pub struct ChunkedDownloader {
+    client: reqwest::Client,
+    /// `<api url>/file/bot<token>/`, never log it.
+    base: Url,
+    chunks: u64,
+}
+
+impl ChunkedDownloader {
+    pub fn new(bot: &Bot, chunks: usize) -> Result<Self> {
+        let base = bot
+            .api_url()
+            .join(&format!("file/bot{}/", bot.token()))
+            .context("Invalid Bot API url")?;
+        Ok(Self {
+            client: bot.client().clone(),
+            base,
+            chunks: chunks as u64,
+        })
+    }
+
+    /// How many chunks a file of `size` bytes is split into, 1 when it isn't
+    /// worth it.
+    pub fn chunks_for(&self, size: u64) -> u64 {
+        self.chunks.min(size / MIN_CHUNK_BYTES).max(1)
+    }
+
+    /// Downloads the file at `file_path` on the server, `size` bytes long, into
+    /// `dst`, which is created with its full size up front and filled at the
+    /// offsets of the chunks as they arrive.
+    pub async fn download(&self, file_path: &str, size: u64, dst: &Path) -> Result<()> {
+        let url = self.base.join(file_path).context("Invalid file path")?;
+        let file = tokio::fs::File::create(dst)
+            .await
+            .with_context(|| format!("Failed to create {}", dst.display()))?;
+        file.set_len(size).await?;
+        drop(file);
+
+        let chunks = self.chunks_for(size);
+        let chunk_len = size.div_ceil(chunks);
+        let ranges = (0..chunks)
+            .map(|i| (i * chunk_len, ((i + 1) * chunk_len).min(size)))
+            .filter(|(start, end)| start < end);
+        future::try_join_all(ranges.map(|(start, end)| self.chunk(&url, start, end, dst))).await?;
+
+        let written = tokio::fs::metadata(dst).await?.len();
+        if written != size {
+            bail!("Chunked download has {written} of {size} bytes");
+        }
+        Ok(())
+    }
+
+    /// Writes bytes `start..end` of the file at `url` to the same place in `dst`.
+    async fn chunk(&self, url: &Url, start: u64, end: u64, dst: &Path) -> Result<()> {
+        let response = self
+            .client
+            .get(url.clone())
+            .header(header::RANGE, format!("bytes={start}-{}", end - 1))
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(reqwest::Error::without_url)?;
+        // a server ignoring Range sends the whole file, which can't be used here
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            bail!("The Bot API server doesn't support Range requests");
+        }
+
+        let mut file = tokio::fs::OpenOptions::new().write(true).open(dst).await?;
+        file.seek(SeekFrom::Start(start)).await?;
+        let mut received = 0;
+        let mut body = response.bytes_stream();
+        while let Some(bytes) = body.next().await {
+            let bytes = bytes.map_err(reqwest::Error::without_url)?;
+            received += bytes.len() as u64;
+            if received > end - start {
+                bail!("Got more than the requested bytes {start}-{}", end - 1);
+            }
+            file.write_all(&bytes).await?;
+        }
+        file.flush().await?;
+        if received != end - start {
+            bail!(
+                "Incomplete chunk: got {received} of {} bytes at {start}",
+                end - start
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Pauses downloads for `cooldown` once `threshold` of them failed in a row
+/// within `window`, so an unreachable API server isn't hit by every update.
+pub struct CircuitBreaker {
+    threshold: usize,
+    window: Duration,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Default)]
+struct BreakerState {
+    failures: VecDeque<Instant>,
+    open_until: Option<Instant>,
+    tripped: bool,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, window: Duration, cooldown: Duration) -> Self {
+        Self {
+            threshold: threshold.max(1) as usize,
+            window,
+            cooldown,
+            state: Default::default(),
+        }
+    }
+
+    /// Waits out the cool-down if the breaker is open.
+    pub async fn wait_until_closed(&self) {
+        let open_until = self.state.lock().unwrap().open_until;
+        if let Some(open_until) = open_until {
+            tokio::time::sleep_until(open_until.into()).await;
+        }
+    }
+
+    /// Records the outcome of a download, a skipped one says nothing about
+    /// the server and leaves the streak of failures as it is.
+    pub fn record<T>(&self, result: &anyhow::Result<Option<T>>) {
+        match result {
+            Err(_) => self.record_failure(),
+            Ok(Some(_)) => self.record_success(),
+            Ok(None) => {}
+        }
+    }
+
+    fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.tripped {
+            log::info!("Download succeeded, resuming normal operation");
+        }
+        *state = BreakerState::default();
+    }
+
+    fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        state.failures.push_back(now);
+        while state
+            .failures
+            .front()
+            .is_some_and(|&failed_at| now.duration_since(failed_at) > self.window)
+        {
+            state.failures.pop_front();
+        }
+
+        let open = state.open_until.is_some_and(|open_until| open_until > now);
+        if !open && state.failures.len() >= self.threshold {
+            log::warn!(
+                "{} downloads failed in a row, pausing downloads for {:?}",
+                state.failures.len(),
+                self.cooldown
+            );
+            state.open_until = Some(now + self.cooldown);
+            state.failures.clear();
+            state.tripped = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_open(breaker: &CircuitBreaker) -> bool {
+        breaker.state.lock().unwrap().open_until.is_some()
+    }
+
+    #[test]
+    fn skips_do_not_reset_the_failures() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60), Duration::from_secs(60));
+        breaker.record::<()>(&Err(anyhow::anyhow!("unreachable")));
+        breaker.record::<()>(&Ok(None));
+        assert!(!is_open(&breaker));
+        breaker.record::<()>(&Err(anyhow::anyhow!("unreachable")));
+        assert!(is_open(&breaker));
+
+        breaker.record(&Ok(Some(())));
+        assert!(!is_open(&breaker));
+    }
+}
@@ -0,0 +1,79 @@
+//! `clean-sidecars` subcommand: finds the sidecars left behind by media files
+//! that were deleted by hand and removes them.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+/// Suffixes `sidecar::sidecar_path` and `sidecar::context_path` append to the
+/// full media file name.
+const SUFFIXES: &[&str] = &[".meta.json", ".context.json"];
+
+/// Lists the orphaned sidecars under `media_directory`, and removes them when
+/// `apply` is set.
+pub async fn run(media_directory: &Path, apply: bool) -> Result<()> {
+    let orphans = find(media_directory).await?;
+    for orphan in &orphans {
+        println!("{}", orphan.display());
+        if apply {
+            tokio::fs::remove_file(orphan)
+                .await
+                .with_context(|| format!("Failed to remove {}", orphan.display()))?;
+        }
+    }
+    if apply {
+        println!("Removed {} orphaned sidecars", orphans.len());
+    } else {
+        println!(
+            "Found {} orphaned sidecars. Run with --apply to remove them",
+            orphans.len()
+        );
+    }
+    Ok(())
+}
+
+async fn find(root: &Path) -> Result<Vec<PathBuf>> {
+    let mut orphans = Vec::new();
+    let mut pending = vec![root.to_owned()];
+    while let Some(dir) = pending.pop() {
+        let mut names = Vec::new();
+        let mut entries = tokio::fs::read_dir(&dir)
+            .await
+            .with_context(|| format!("Failed to read {}", dir.display()))?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_type().await?.is_dir() {
+                pending.push(entry.path());
+            } else if let Ok(name) = entry.file_name().into_string() {
+                names.push(name);
+            }
+        }
+        for name in &names {
+            if is_orphan(name, &names) {
+                orphans.push(dir.join(name));
+            }
+        }
+    }
+    orphans.sort();
+    Ok(orphans)
+}
+
+fn is_orphan(name: &str, names: &[String]) -> bool {
+    if let Some(media) = SUFFIXES.iter().find_map(|suffix| name.strip_suffix(suffix)) {
+        return !names.iter().any(|name| name == media);
+    }
+    // `sidecar::nfo_path` replaces the extension, any other file with the same
+    // stem can be the video
+    if let Some(stem) = name.strip_suffix(".nfo") {
+        return !names.iter().any(|other| {
+            other != name
+                && other
+                    .strip_prefix(stem)
+                    .is_some_and(|rest| rest.starts_with('.') && !is_sidecar(other))
+        });
+    }
+    false
+}
+
+fn is_sidecar(name: &str) -> bool {
+    SUFFIXES.iter().any(|suffix| name.ends_with(suffix))
+}
@@ -0,0 +1,426 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use teloxide::{
+    prelude::*,
+    types::{MessageId, Recipient},
+    utils::command::BotCommands,
+};
+
+use crate::{
+    dead_letter, gallery, index, save_message_media, storage::Storage, AppState, TelegramBot,
+};
+
+#[derive(BotCommands, Clone)]
+#[command(rename_rule = "snake_case", description = "Admin commands:")]
+pub enum AdminCommand {
+    #[command(description = "rebuild the index of downloaded files from media_directory.")]
+    Reindex,
+    #[command(description = "save the media of a post given its t.me link.")]
+    Fetch(String),
+    #[command(
+        description = "downloads of a channel (id or title) since a date (2024-05-01) or for a period (7d, 12h), from the database."
+    )]
+    Stats(String),
+    #[command(description = "zero the download, error and byte counters.")]
+    ResetStats,
+    #[command(description = "regenerate the index.html galleries of media_directory.")]
+    Gallery,
+    #[command(
+        description = "compact the download database, /vacuum prune also drops entries whose file is gone."
+    )]
+    Vacuum(String),
+    #[command(description = "stop downloading, new posts are skipped and logged.")]
+    Pause,
+    #[command(description = "continue downloading after /pause.")]
+    Resume,
+    #[command(description = "whether downloading is paused, and the download counters.")]
+    Status,
+}
+
+pub fn is_admin(msg: &Message, app_state: &AppState) -> bool {
+    msg.from()
+        .is_some_and(|user| app_state.config.admin_ids.contains(&user.id.0))
+}
+
+pub async fn handle_admin_command<B: TelegramBot>(
+    bot: Arc<B>,
+    msg: Message,
+    command: AdminCommand,
+    app_state: Arc<AppState>,
+) -> Result<()> {
+    match command {
+        AdminCommand::Reindex => {
+            // scanning a big archive takes a while, don't hold up the dispatcher
+            tokio::spawn(async move {
+                let media_directory = PathBuf::from(&app_state.config.media_directory);
+                let reply = match index::rebuild(&app_state.index, media_directory).await {
+                    Ok(count) => format!("Reindex complete, {count} files indexed"),
+                    Err(e) => {
+                        log::error!("Reindex failed: {e:#}");
+                        format!("Reindex failed: {e:#}")
+                    }
+                };
+                if let Err(e) = bot.send_message(msg.chat.id, reply).await {
+                    log::error!("Failed to reply to admin command: {e}");
+                }
+            });
+        }
+        AdminCommand::Fetch(link) => {
+            let reply = match fetch_linked_message(&bot, &msg, &link, app_state).await {
+                Ok(()) => "Saved".to_owned(),
+                Err(e) => {
+                    log::error!("Fetching {link} failed: {e:#}");
+                    format!("Fetching {link} failed: {e:#}")
+                }
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+        AdminCommand::Stats(args) => {
+            let reply = match channel_stats(&args, &app_state).await {
+                Ok(reply) => reply,
+                Err(e) => format!("{e:#}"),
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+        AdminCommand::ResetStats => {
+            let stats = &app_state.stats;
+            let downloads = stats.downloads.swap(0, Ordering::Relaxed);
+            let errors = stats.errors.swap(0, Ordering::Relaxed);
+            let bytes = stats.bytes.swap(0, Ordering::Relaxed);
+            log::info!("Stats reset at {downloads} downloads, {errors} errors, {bytes} bytes");
+            bot.send_message(
+                msg.chat.id,
+                format!("Stats reset, were {downloads} downloads, {errors} errors, {bytes} bytes"),
+            )
+            .await?;
+        }
+        AdminCommand::Gallery => {
+            tokio::spawn(async move {
+                let media_directory = PathBuf::from(&app_state.config.media_directory);
+                let reply = match gallery::generate_all(&media_directory).await {
+                    Ok(count) => format!("Generated {count} galleries"),
+                    Err(e) => {
+                        log::error!("Generating galleries failed: {e:#}");
+                        format!("Generating galleries failed: {e:#}")
+                    }
+                };
+                if let Err(e) = bot.send_message(msg.chat.id, reply).await {
+                    log::error!("Failed to reply to admin command: {e}");
+                }
+            });
+        }
+        AdminCommand::Vacuum(mode) => {
+            let Some(database) = app_state.database.clone() else {
+                bot.send_message(msg.chat.id, "No database_path configured")
+                    .await?;
+                return Ok(());
+            };
+            let prune_missing = match mode.trim() {
+                "" => false,
+                "prune" => true,
+                other => {
+                    bot.send_message(
+                        msg.chat.id,
+                        format!("Unknown argument {other:?}, expected nothing or \"prune\""),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+            };
+            if prune_missing && !matches!(app_state.storage, Storage::Local(_)) {
+                bot.send_message(msg.chat.id, "Pruning only works with local storage")
+                    .await?;
+                return Ok(());
+            }
+            tokio::spawn(async move {
+                let reply = match database.compact(prune_missing).await {
+                    Ok(pruned) if prune_missing => {
+                        log::info!("Database compacted, pruned {pruned} missing files");
+                        format!("Database compacted, pruned {pruned} entries of missing files")
+                    }
+                    Ok(_) => {
+                        log::info!("Database compacted");
+                        "Database compacted".to_owned()
+                    }
+                    Err(e) => {
+                        log::error!("Compacting the database failed: {e:#}");
+                        format!("Compacting the database failed: {e:#}")
+                    }
+                };
+                if let Err(e) = bot.send_message(msg.chat.id, reply).await {
+                    log::error!("Failed to reply to admin command: {e}");
+                }
+            });
+        }
+        AdminCommand::Pause => {
+            let was_paused = app_state.paused.swap(true, Ordering::Relaxed);
+            log::warn!("Downloads paused");
+            let reply = if was_paused {
+                "Already paused"
+            } else {
+                "Paused, posts arriving now are skipped and logged"
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+        AdminCommand::Resume => {
+            let was_paused = app_state.paused.swap(false, Ordering::Relaxed);
+            log::warn!("Downloads resumed");
+            let reply = if was_paused { "Resumed" } else { "Not paused" };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+        AdminCommand::Status => {
+            bot.send_message(msg.chat.id, status(&app_state)).await?;
+        }
+    }
+    Ok(())
+}
+
+fn status(app_state: &AppState) -> String {
+    let stats = &app_state.stats;
+    let state = if app_state.paused.load(Ordering::Relaxed) {
+        "Paused, new posts are skipped until /resume"
+    } else {
+        "Downloading"
+    };
+    let mut status = format!(
+        "{state}\nUp for {}s, {} downloads, {} errors, {} bytes",
+        stats.started_at.elapsed().as_secs(),
+        stats.downloads.load(Ordering::Relaxed),
+        stats.errors.load(Ordering::Relaxed),
+        stats.bytes.load(Ordering::Relaxed),
+    );
+    if let Some(max) = app_state.config.max_downloads_per_session {
+        let left = max.saturating_sub(app_state.session_downloads.load(Ordering::Relaxed));
+        // the session is the run of the bot, nothing else resets the count
+        status.push_str(&format!(
+            "\n{left} of {max} downloads left this session, resets when the bot restarts"
+        ));
+    }
+    status
+}
+
+async fn channel_stats(args: &str, app_state: &AppState) -> Result<String> {
+    let database = app_state
+        .database
+        .as_ref()
+        .context("No database_path configured")?;
+    let args = args.trim();
+    if args.is_empty() {
+        bail!("Usage: /stats <channel id or title> [<since>]");
+    }
+    // the channel title can contain spaces, the period can't
+    let (channel, since) = match args.rsplit_once(' ') {
+        Some((channel, since)) => match parse_since(since) {
+            Some(since) => (channel.trim(), Some(since)),
+            None => (args, None),
+        },
+        None => (args, None),
+    };
+    let (count, bytes) = database.channel_stats(channel, since).await?;
+    let period = since.map_or_else(
+        || "in total".to_owned(),
+        |since| format!("since {}", since.format("%Y-%m-%d %H:%M UTC")),
+    );
+    Ok(format!(
+        "{channel}: {count} downloads, {:.1} MiB {period}",
+        bytes as f64 / (1024.0 * 1024.0)
+    ))
+}
+
+/// `2024-05-01` or a period back from now like `7d`, `12h` or `30m`.
+fn parse_since(since: &str) -> Option<DateTime<Utc>> {
+    if let Ok(date) = NaiveDate::parse_from_str(since, "%Y-%m-%d") {
+        return Some(date.and_hms_opt(0, 0, 0)?.and_utc());
+    }
+    let split = since.len().checked_sub(1)?;
+    let (amount, unit) = since.split_at_checked(split)?;
+    let amount: u64 = amount.parse().ok()?;
+    let unit_secs = match unit {
+        "d" => 24 * 60 * 60,
+        "h" => 60 * 60,
+        "m" => 60,
+        _ => return None,
+    };
+    let period = Duration::from_secs(amount.checked_mul(unit_secs)?);
+    Utc::now().checked_sub_signed(chrono::Duration::from_std(period).ok()?)
+}
+
+/// Bots can't read chat history, so the post is forwarded into the admin's
+/// chat and the forwarded copy is saved. This only works for chats the bot is
+/// a member of, and not for chats with protected content.
+async fn fetch_linked_message<B: TelegramBot>(
+    bot: &Arc<B>,
+    msg: &Message,
+    link: &str,
+    app_state: Arc<AppState>,
+) -> Result<()> {
+    let (chat, message_id) = parse_message_link(link).with_context(|| {
+        format!("Not a message link: {link:?}, expected https://t.me/c/<chat>/<message>")
+    })?;
+    forward_and_save(bot, msg.chat.id, chat, message_id, app_state).await
+}
+
+async fn forward_and_save<B: TelegramBot>(
+    bot: &Arc<B>,
+    to: ChatId,
+    chat: Recipient,
+    message_id: MessageId,
+    app_state: Arc<AppState>,
+) -> Result<()> {
+    let forwarded = bot.forward_message(to, chat, message_id).await.context(
+        "Can't access the message. Is the bot a member of the chat, and are forwards \
+             allowed? Posts of channels with protected content can't be fetched",
+    )?;
+    save_message_media(bot.clone(), &forwarded, app_state).await?;
+    Ok(())
+}
+
+/// Re-attempts every record in `dead_letter_dir` the way `/fetch` does, with
+/// the chat of the first admin as the forwarding target. Records are removed
+/// once their post is saved and kept when it can't be fetched anymore.
+pub async fn retry_dead_letters<B: TelegramBot>(bot: Arc<B>, app_state: Arc<AppState>) {
+    let (Some(dead_letter_dir), Some(&admin_id)) = (
+        &app_state.config.dead_letter_dir,
+        app_state.config.admin_ids.first(),
+    ) else {
+        return;
+    };
+    let dead_letters = match dead_letter::load_all(Path::new(dead_letter_dir)).await {
+        Ok(dead_letters) => dead_letters,
+        Err(e) => {
+            log::error!("Failed to load dead letters: {e:#}");
+            return;
+        }
+    };
+    if !dead_letters.is_empty() {
+        log::info!("Retrying {} failed downloads", dead_letters.len());
+    }
+    for (path, dead_letter) in dead_letters {
+        let result = forward_and_save(
+            &bot,
+            ChatId(admin_id as i64),
+            Recipient::Id(ChatId(dead_letter.chat_id)),
+            MessageId(dead_letter.message_id),
+            app_state.clone(),
+        )
+        .await;
+        match result {
+            Ok(()) => {
+                log::info!("Retried {} successfully", dead_letter.unique_id);
+                if let Err(e) = tokio::fs::remove_file(&path).await {
+                    log::error!("Failed to remove dead letter {}: {e}", path.display());
+                }
+            }
+            Err(e) => log::warn!(
+                "Retrying {} failed, keeping {}: {e:#}",
+                dead_letter.unique_id,
+                path.display()
+            ),
+        }
+    }
+}
+
+/// Parses `https://t.me/c/<chat>/<message>` links of private chats and
+/// `https://t.me/<username>/<message>` links of public ones. Links to a topic
+/// have the thread id in between, the message id is always last.
+fn parse_message_link(link: &str) -> Option<(Recipient, MessageId)> {
+    let link = link.trim();
+    let link = link.split(['?', '#']).next()?;
+    let path = ["https://", "http://", ""]
+        .iter()
+        .find_map(|scheme| link.strip_prefix(scheme))?;
+    let path = ["t.me/", "telegram.me/"]
+        .iter()
+        .find_map(|host| path.strip_prefix(host))?;
+
+    let segments: Vec<&str> = path.trim_end_matches('/').split('/').collect();
+    let message_id = MessageId(segments.last()?.parse().ok()?);
+    let chat = match segments.as_slice() {
+        ["c", chat, .., _] => {
+            let chat: i64 = chat.parse().ok()?;
+            // links drop the -100 prefix of channel and supergroup ids
+            Recipient::Id(ChatId(-1_000_000_000_000 - chat))
+        }
+        ["c", ..] => return None,
+        [username, _] | [username, _, _] => Recipient::ChannelUsername(format!("@{username}")),
+        _ => return None,
+    };
+    Some((chat, message_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{test_config, test_state};
+
+    #[test]
+    fn status_reports_the_paused_flag() {
+        let app_state = test_state(test_config(""));
+        assert!(status(&app_state).starts_with("Downloading\n"));
+        app_state.paused.store(true, Ordering::Relaxed);
+        assert!(status(&app_state).starts_with("Paused"));
+    }
+
+    #[test]
+    fn status_reports_the_session_budget() {
+        let app_state = test_state(test_config(""));
+        assert!(!status(&app_state).contains("left this session"));
+        let app_state = test_state(test_config("max_downloads_per_session = 10"));
+        app_state.session_downloads.store(3, Ordering::Relaxed);
+        assert!(status(&app_state)
+            .ends_with("\n7 of 10 downloads left this session, resets when the bot restarts"));
+        app_state.session_downloads.store(12, Ordering::Relaxed);
+        assert!(status(&app_state).contains("\n0 of 10 downloads left"));
+    }
+
+    fn parse(link: &str) -> Option<(String, i32)> {
+        let (chat, message_id) = parse_message_link(link)?;
+        let chat = match chat {
+            Recipient::Id(chat_id) => chat_id.0.to_string(),
+            Recipient::ChannelUsername(username) => username,
+        };
+        Some((chat, message_id.0))
+    }
+
+    #[test]
+    fn private_chat_links() {
+        let expected = Some(("-1001234567890".to_owned(), 42));
+        assert_eq!(parse("https://t.me/c/1234567890/42"), expected);
+        assert_eq!(parse("t.me/c/1234567890/42/"), expected);
+        assert_eq!(
+            parse(" http://telegram.me/c/1234567890/42?single "),
+            expected
+        );
+        // topic links have the thread in between
+        assert_eq!(parse("https://t.me/c/1234567890/7/42"), expected);
+    }
+
+    #[test]
+    fn public_chat_links() {
+        let expected = Some(("@channel".to_owned(), 42));
+        assert_eq!(parse("https://t.me/channel/42"), expected);
+        assert_eq!(parse("https://t.me/channel/42#comments"), expected);
+        assert_eq!(parse("https://t.me/channel/7/42"), expected);
+    }
+
+    #[test]
+    fn malformed_links_are_rejected() {
+        for link in [
+            "",
+            "https://example.com/c/1234567890/42",
+            "https://t.me/channel",
+            "https://t.me/channel/latest",
+            "https://t.me/c/42",
+            "https://t.me/c/private/42",
+            "https://t.me/a/b/c/42",
+        ] {
+            assert_eq!(parse(link), None, "{link:?}");
+        }
+    }
+}
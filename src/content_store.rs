@@ -0,0 +1,75 @@
+//! `storage_layout = "content_addressed"`: every file is stored once under
+//! `blobs/<sha256>` and the usual path becomes a symlink to it.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Directory of `media_directory` holding the file contents.
+pub const BLOBS_DIR: &str = "blobs";
+
+#[derive(Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageLayout {
+    /// Files are saved where their name puts them.
+    #[default]
+    Plain,
+    ContentAddressed,
+}
+
+/// Moves the file at `path` into the blob store, unless a blob with the same
+/// `sha256` is already there, and leaves a link to the blob at `path`.
+/// Returns the blob when this call created it.
+pub async fn store(media_directory: &Path, path: &Path, sha256: &str) -> Result<Option<PathBuf>> {
+    let blobs = media_directory.join(BLOBS_DIR);
+    tokio::fs::create_dir_all(&blobs)
+        .await
+        .with_context(|| format!("Failed to create {}", blobs.display()))?;
+    let blob = blobs.join(sha256);
+    let created = if tokio::fs::try_exists(&blob).await? {
+        log::info!(
+            "{} has the same content as {}",
+            path.display(),
+            blob.display()
+        );
+        tokio::fs::remove_file(path).await?;
+        false
+    } else {
+        tokio::fs::rename(path, &blob)
+            .await
+            .with_context(|| format!("Failed to move {} to the blob store", path.display()))?;
+        true
+    };
+    link(&blob, path)
+        .await
+        .with_context(|| format!("Failed to link {} to {}", path.display(), blob.display()))?;
+    Ok(created.then_some(blob))
+}
+
+#[cfg(unix)]
+async fn link(blob: &Path, path: &Path) -> std::io::Result<()> {
+    // relative, so the archive can be moved or mounted elsewhere as a whole
+    let target = relative_to(blob, path.parent().unwrap_or(Path::new("")));
+    tokio::fs::symlink(target, path).await
+}
+
+/// Creating symlinks needs administrator rights or developer mode on Windows,
+/// the named file is a copy there.
+#[cfg(not(unix))]
+async fn link(blob: &Path, path: &Path) -> std::io::Result<()> {
+    tokio::fs::copy(blob, path).await.map(|_| ())
+}
+
+/// `target` as seen from `dir`, both under the same root.
+#[cfg(unix)]
+fn relative_to(target: &Path, dir: &Path) -> std::path::PathBuf {
+    let common = target
+        .components()
+        .zip(dir.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut relative: std::path::PathBuf = dir.components().skip(common).map(|_| "..").collect();
+    relative.extend(target.components().skip(common));
+    relative
+}
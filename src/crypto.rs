@@ -0,0 +1,331 @@
+//! At-rest encryption of downloaded files.
+//!
+//! Files are encrypted with XChaCha20-Poly1305 using the STREAM construction
+//! (`aead::stream`, big-endian 32-bit counter), so arbitrarily large files are
+//! processed in fixed-size chunks and never held in memory as a whole.
+//!
+//! Layout of an encrypted file:
+//!
+//! ```text
+//! MAGIC (4 bytes) | nonce prefix (19 bytes) | chunk 0 | chunk 1 | ... | last chunk
+//! ```
+//!
+//! Every chunk but the last holds exactly `CHUNK_SIZE` bytes of plaintext plus
+//! a 16 byte tag; the last chunk holds the remainder (possibly empty) and is
+//! sealed with the "last block" flag, so truncation is detected on decryption.
+//!
+//! Key management: the key is 32 bytes given as 64 hex characters, either in
+//! the `ENCRYPTION_KEY` environment variable or in the file pointed to by the
+//! `encryption_key_file` config option. A fresh random nonce prefix is drawn
+//! from the OS RNG for every file and stored in its header, so one key can
+//! safely be reused for the whole archive. Losing the key means losing the
+//! archive: keep a copy of it somewhere other than the media directory.
+
+use std::{
+    env, io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{ready, Context as TaskContext, Poll},
+};
+
+use anyhow::{bail, Context, Result};
+use chacha20poly1305::{
+    aead::{
+        rand_core::RngCore,
+        stream::{DecryptorBE32, EncryptorBE32},
+        KeyInit, OsRng,
+    },
+    Key, XChaCha20Poly1305,
+};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+pub const ENCRYPTION_KEY_ENV: &str = "ENCRYPTION_KEY";
+pub const ENCRYPTED_EXTENSION: &str = "enc";
+
+const MAGIC: &[u8; 4] = b"TGE1";
+const NONCE_PREFIX_SIZE: usize = 19;
+const TAG_SIZE: usize = 16;
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Loads the key from `ENCRYPTION_KEY`, falling back to `key_file`.
+pub fn load_key(key_file: Option<&str>) -> Result<Option<Key>> {
+    let hex_key = if let Some(hex_key) = env::var_os(ENCRYPTION_KEY_ENV) {
+        hex_key
+            .into_string()
+            .map_err(|_| anyhow::anyhow!("{ENCRYPTION_KEY_ENV} is not valid unicode"))?
+    } else if let Some(key_file) = key_file {
+        std::fs::read_to_string(key_file)
+            .with_context(|| format!("Failed to read encryption key file: {key_file}"))?
+    } else {
+        return Ok(None);
+    };
+
+    let bytes = hex::decode(hex_key.trim()).context("Encryption key must be hex encoded")?;
+    if bytes.len() != 32 {
+        bail!(
+            "Encryption key must be 32 bytes (64 hex characters), got {} bytes",
+            bytes.len()
+        );
+    }
+    Ok(Some(*Key::from_slice(&bytes)))
+}
+
+/// Encrypts everything written to it into `inner`.
+///
+/// `poll_shutdown` must be called to seal the last chunk.
+pub struct EncryptedWriter<W> {
+    inner: W,
+    encryptor: Option<EncryptorBE32<XChaCha20Poly1305>>,
+    plaintext: Vec<u8>,
+    ciphertext: Vec<u8>,
+    flushed: usize,
+}
+
+impl<W: AsyncWrite + Unpin> EncryptedWriter<W> {
+    pub fn new(inner: W, key: &Key) -> Self {
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+        OsRng.fill_bytes(&mut nonce_prefix);
+
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&nonce_prefix);
+
+        Self {
+            inner,
+            encryptor: Some(EncryptorBE32::from_aead(
+                XChaCha20Poly1305::new(key),
+                nonce_prefix.as_ref().into(),
+            )),
+            plaintext: Vec::with_capacity(CHUNK_SIZE),
+            ciphertext: header,
+            flushed: 0,
+        }
+    }
+
+    fn poll_write_ciphertext(&mut self, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        while self.flushed < self.ciphertext.len() {
+            let n =
+                ready!(Pin::new(&mut self.inner).poll_write(cx, &self.ciphertext[self.flushed..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.flushed += n;
+        }
+        self.ciphertext.clear();
+        self.flushed = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    fn encrypt_chunk(&mut self, last: bool) -> io::Result<()> {
+        let encryptor = self
+            .encryptor
+            .take()
+            .ok_or_else(|| io::Error::other("write after shutdown"))?;
+        let chunk = if last {
+            encryptor.encrypt_last(self.plaintext.as_slice())
+        } else {
+            let mut encryptor = encryptor;
+            let chunk = encryptor.encrypt_next(self.plaintext.as_slice());
+            self.encryptor = Some(encryptor);
+            chunk
+        }
+        .map_err(|_| io::Error::other("encryption failed"))?;
+
+        self.ciphertext.extend_from_slice(&chunk);
+        self.plaintext.clear();
+        Ok(())
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for EncryptedWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_ciphertext(cx))?;
+        if this.plaintext.len() == CHUNK_SIZE {
+            this.encrypt_chunk(false)?;
+            ready!(this.poll_write_ciphertext(cx))?;
+        }
+
+        let n = buf.len().min(CHUNK_SIZE - this.plaintext.len());
+        this.plaintext.extend_from_slice(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_ciphertext(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.encryptor.is_some() {
+            ready!(this.poll_write_ciphertext(cx))?;
+            this.encrypt_chunk(true)?;
+        }
+        ready!(this.poll_write_ciphertext(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// Decrypts a file written by [`EncryptedWriter`]. The plaintext only appears
+/// at `output` once every chunk was authenticated.
+pub async fn decrypt_file(key: &Key, input: &Path, output: &Path) -> Result<()> {
+    let src = tokio::fs::File::open(input)
+        .await
+        .with_context(|| format!("Failed to open file: {}", input.display()))?;
+    let mut partial = output.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    let mut dst = tokio::fs::File::create(&partial)
+        .await
+        .with_context(|| format!("Failed to create file: {}", partial.display()))?;
+
+    let mut decrypted = decrypt_stream(key, src, &mut dst).await;
+    if decrypted.is_ok() {
+        decrypted = dst.shutdown().await.map_err(Into::into);
+    }
+    drop(dst);
+    if decrypted.is_ok() {
+        decrypted = tokio::fs::rename(&partial, output)
+            .await
+            .with_context(|| format!("Failed to move the plaintext to {}", output.display()));
+    }
+    if decrypted.is_err() {
+        if let Err(e) = tokio::fs::remove_file(&partial).await {
+            log::error!("Failed to remove {}: {e}", partial.display());
+        }
+    }
+    decrypted
+}
+
+async fn decrypt_stream(
+    key: &Key,
+    src: impl AsyncRead + Unpin,
+    dst: &mut (impl AsyncWrite + Unpin),
+) -> Result<()> {
+    let mut src = BufReader::new(src);
+
+    let mut header = [0u8; MAGIC.len() + NONCE_PREFIX_SIZE];
+    src.read_exact(&mut header)
+        .await
+        .context("File is too short to be encrypted")?;
+    if &header[..MAGIC.len()] != MAGIC {
+        bail!("File was not encrypted by this bot");
+    }
+    let mut decryptor =
+        DecryptorBE32::from_aead(XChaCha20Poly1305::new(key), header[MAGIC.len()..].into());
+
+    let mut chunk = vec![0u8; CHUNK_SIZE + TAG_SIZE];
+    loop {
+        let n = read_full(&mut src, &mut chunk).await?;
+        let last = n < chunk.len() || src.fill_buf().await?.is_empty();
+        if last {
+            let plaintext = decryptor
+                .decrypt_last(&chunk[..n])
+                .map_err(|_| anyhow::anyhow!("Decryption failed: wrong key or corrupted file"))?;
+            dst.write_all(&plaintext).await?;
+            return Ok(());
+        }
+        let plaintext = decryptor
+            .decrypt_next(chunk.as_slice())
+            .map_err(|_| anyhow::anyhow!("Decryption failed: wrong key or corrupted file"))?;
+        dst.write_all(&plaintext).await?;
+    }
+}
+
+async fn read_full(src: &mut (impl AsyncRead + Unpin), buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = src.read(&mut buf[filled..]).await?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn encrypt(key: &Key, plaintext: &[u8]) -> Vec<u8> {
+        let mut writer = EncryptedWriter::new(Vec::new(), key);
+        writer.write_all(plaintext).await.unwrap();
+        writer.shutdown().await.unwrap();
+        writer.inner
+    }
+
+    async fn decrypt(key: &Key, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let mut plaintext = Vec::new();
+        decrypt_stream(key, ciphertext, &mut plaintext).await?;
+        Ok(plaintext)
+    }
+
+    #[tokio::test]
+    async fn round_trip_at_chunk_boundaries() {
+        let key = Key::from([7; 32]);
+        for len in [
+            0,
+            1,
+            CHUNK_SIZE - 1,
+            CHUNK_SIZE,
+            CHUNK_SIZE + 1,
+            2 * CHUNK_SIZE,
+            3 * CHUNK_SIZE,
+        ] {
+            let plaintext: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let ciphertext = encrypt(&key, &plaintext).await;
+            let chunks = len.div_ceil(CHUNK_SIZE).max(1);
+            assert_eq!(
+                ciphertext.len(),
+                MAGIC.len() + NONCE_PREFIX_SIZE + len + chunks * TAG_SIZE,
+                "{len} bytes"
+            );
+            assert_eq!(
+                decrypt(&key, &ciphertext).await.unwrap(),
+                plaintext,
+                "{len} bytes"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn truncation_at_a_chunk_boundary_is_detected() {
+        let key = Key::from([7; 32]);
+        let ciphertext = encrypt(&key, &vec![1; 2 * CHUNK_SIZE]).await;
+        let first_chunk = MAGIC.len() + NONCE_PREFIX_SIZE + CHUNK_SIZE + TAG_SIZE;
+        assert!(decrypt(&key, &ciphertext[..first_chunk]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn wrong_key_is_rejected() {
+        let ciphertext = encrypt(&Key::from([7; 32]), b"secret").await;
+        assert!(decrypt(&Key::from([8; 32]), &ciphertext).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn failed_decryption_leaves_no_plaintext() {
+        let dir =
+            std::env::temp_dir().join(format!("tg_download_bot_test_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (input, output) = (dir.join("secret.enc"), dir.join("secret"));
+        let ciphertext = encrypt(&Key::from([7; 32]), &vec![1; 2 * CHUNK_SIZE]).await;
+        std::fs::write(&input, ciphertext).unwrap();
+
+        assert!(decrypt_file(&Key::from([8; 32]), &input, &output)
+            .await
+            .is_err());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        decrypt_file(&Key::from([7; 32]), &input, &output)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), vec![1; 2 * CHUNK_SIZE]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use chrono::{Days, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+use crate::AppState;
+
+/// A download listed in the `daily_manifest` of the day it was saved.
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    /// Relative to `media_directory`.
+    pub path: PathBuf,
+    pub caption: Option<String>,
+    pub unique_id: String,
+    pub size: u64,
+}
+
+/// Downloads not written to a manifest yet, by the day they were saved on.
+#[derive(Default)]
+pub struct DailyManifest {
+    pending: Mutex<BTreeMap<NaiveDate, Vec<Entry>>>,
+}
+
+impl DailyManifest {
+    pub fn record(&self, timezone: Tz, entry: Entry) {
+        let today = Utc::now().with_timezone(&timezone).date_naive();
+        self.pending
+            .lock()
+            .unwrap()
+            .entry(today)
+            .or_default()
+            .push(entry);
+    }
+
+    /// Writes the days before `until`, or every day when `None`.
+    pub async fn flush(&self, media_directory: &Path, until: Option<NaiveDate>) -> Result<()> {
+        let days = {
+            let mut pending = self.pending.lock().unwrap();
+            match until {
+                Some(until) => {
+                    let later = pending.split_off(&until);
+                    std::mem::replace(&mut *pending, later)
+                }
+                None => std::mem::take(&mut *pending),
+            }
+        };
+        for (day, entries) in days {
+            write(media_directory, day, entries).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the manifest of the day that just ended at every midnight of the
+/// configured timezone.
+pub async fn run(app_state: Arc<AppState>) {
+    let timezone = app_state.config.timezone.0;
+    let media_directory = PathBuf::from(&app_state.config.media_directory);
+    loop {
+        let now = Utc::now().with_timezone(&timezone);
+        let tomorrow = now.date_naive() + Days::new(1);
+        // midnight can be skipped by a DST change, the day still ends then
+        let next_midnight = tomorrow
+            .and_hms_opt(0, 0, 0)
+            .and_then(|midnight| timezone.from_local_datetime(&midnight).earliest())
+            .map_or(Duration::from_secs(3600), |at| {
+                (at - now).to_std().unwrap_or_default()
+            });
+        tokio::time::sleep(next_midnight).await;
+        let today = Utc::now().with_timezone(&timezone).date_naive();
+        if let Err(e) = app_state
+            .daily_manifest
+            .flush(&media_directory, Some(today))
+            .await
+        {
+            log::error!("Failed to write the daily manifest: {e:#}");
+        }
+    }
+}
+
+/// Adds `entries` to `manifest-YYYY-MM-DD.json`, which already has the
+/// downloads of the day from before a restart.
+async fn write(media_directory: &Path, day: NaiveDate, entries: Vec<Entry>) -> Result<()> {
+    let path = media_directory.join(format!("manifest-{}.json", day.format("%Y-%m-%d")));
+    let mut all: Vec<Entry> = match tokio::fs::read(&path).await {
+        Ok(contents) => serde_json::from_slice(&contents)
+            .with_context(|| format!("Failed to read {}", path.display()))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    all.extend(entries);
+    tokio::fs::write(&path, serde_json::to_vec_pretty(&all)?)
+        .await
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    log::info!("Wrote {} downloads to {}", all.len(), path.display());
+    Ok(())
+}
@@ -0,0 +1,231 @@
+//! Optional SQLite index of all downloads, for querying the archive and for
+//! duplicate detection that survives renamed or moved files.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+
+/// Schema migrations, `MIGRATIONS[n]` upgrades a database at version `n`.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE downloads (
+        unique_id TEXT NOT NULL PRIMARY KEY,
+        file_id TEXT NOT NULL,
+        path TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        caption TEXT,
+        date TEXT NOT NULL,
+        media_type TEXT NOT NULL,
+        chat_id INTEGER NOT NULL,
+        channel TEXT NOT NULL
+    )",
+    "ALTER TABLE downloads ADD COLUMN phash TEXT",
+    "ALTER TABLE downloads ADD COLUMN run_id TEXT",
+];
+
+pub struct DownloadRecord {
+    pub unique_id: String,
+    pub file_id: String,
+    pub path: PathBuf,
+    pub size: u64,
+    pub caption: Option<String>,
+    pub date: chrono::DateTime<chrono::Utc>,
+    pub media_type: String,
+    pub chat_id: i64,
+    pub channel: String,
+    pub phash: Option<String>,
+    /// Run of the bot that saved the file.
+    pub run_id: String,
+}
+
+pub struct Database {
+    connection: Mutex<Connection>,
+}
+
+impl Database {
+    /// Opens or creates the database at `path`, bringing its schema up to date.
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut connection = Connection::open(path)
+            .with_context(|| format!("Failed to open database: {}", path.display()))?;
+
+        let version: i64 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        let transaction = connection.transaction()?;
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            transaction
+                .execute_batch(migration)
+                .with_context(|| format!("Database migration {} failed", i + 1))?;
+        }
+        transaction.pragma_update(None, "user_version", MIGRATIONS.len() as i64)?;
+        transaction.commit()?;
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    pub async fn insert(self: &Arc<Self>, record: DownloadRecord) -> Result<()> {
+        let database = self.clone();
+        tokio::task::spawn_blocking(move || {
+            database.connection.lock().unwrap().execute(
+                "INSERT OR REPLACE INTO downloads
+                    (unique_id, file_id, path, size, caption, date, media_type, chat_id, channel, phash, run_id)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                params![
+                    record.unique_id,
+                    record.file_id,
+                    record.path.to_string_lossy(),
+                    record.size as i64,
+                    record.caption,
+                    record.date.to_rfc3339(),
+                    record.media_type,
+                    record.chat_id,
+                    record.channel,
+                    record.phash,
+                    record.run_id,
+                ],
+            )
+        })
+        .await?
+        .context("Failed to record download in database")?;
+        Ok(())
+    }
+
+    pub async fn contains(self: &Arc<Self>, unique_id: &str) -> Result<bool> {
+        let database = self.clone();
+        let unique_id = unique_id.to_owned();
+        let found = tokio::task::spawn_blocking(move || {
+            database
+                .connection
+                .lock()
+                .unwrap()
+                .query_row(
+                    "SELECT 1 FROM downloads WHERE unique_id = ?1",
+                    [unique_id],
+                    |_| Ok(()),
+                )
+                .optional()
+        })
+        .await?
+        .context("Database lookup failed")?;
+        Ok(found.is_some())
+    }
+
+    /// Every recorded download.
+    pub async fn downloads(self: &Arc<Self>) -> Result<Vec<DownloadRecord>> {
+        let database = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let connection = database.connection.lock().unwrap();
+            let mut statement = connection.prepare(
+                "SELECT unique_id, file_id, path, size, caption, date, media_type, chat_id,
+                    channel, phash, run_id FROM downloads",
+            )?;
+            let rows = statement.query_map([], |row| {
+                let date: String = row.get(5)?;
+                Ok(DownloadRecord {
+                    unique_id: row.get(0)?,
+                    file_id: row.get(1)?,
+                    path: PathBuf::from(row.get::<_, String>(2)?),
+                    size: row.get::<_, i64>(3)? as u64,
+                    caption: row.get(4)?,
+                    date: chrono::DateTime::parse_from_rfc3339(&date)
+                        .map_err(|e| {
+                            rusqlite::Error::FromSqlConversionFailure(
+                                5,
+                                rusqlite::types::Type::Text,
+                                Box::new(e),
+                            )
+                        })?
+                        .with_timezone(&chrono::Utc),
+                    media_type: row.get(6)?,
+                    chat_id: row.get(7)?,
+                    channel: row.get(8)?,
+                    phash: row.get(9)?,
+                    // downloads from before run ids were recorded have none
+                    run_id: row.get::<_, Option<String>>(10)?.unwrap_or_default(),
+                })
+            })?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()
+        })
+        .await?
+        .context("Database query failed")
+    }
+
+    pub async fn update_path(self: &Arc<Self>, unique_id: &str, path: &Path) -> Result<()> {
+        let database = self.clone();
+        let unique_id = unique_id.to_owned();
+        let path = path.to_string_lossy().into_owned();
+        tokio::task::spawn_blocking(move || {
+            database.connection.lock().unwrap().execute(
+                "UPDATE downloads SET path = ?1 WHERE unique_id = ?2",
+                params![path, unique_id],
+            )
+        })
+        .await?
+        .context("Failed to update download in database")?;
+        Ok(())
+    }
+
+    /// Removes the downloads whose file no longer exists when `prune_missing`
+    /// is set, then rebuilds the database file with `VACUUM`. Returns the number
+    /// of removed downloads.
+    pub async fn compact(self: &Arc<Self>, prune_missing: bool) -> Result<usize> {
+        let database = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let connection = database.connection.lock().unwrap();
+            let mut pruned = 0;
+            if prune_missing {
+                let missing = {
+                    let mut statement =
+                        connection.prepare("SELECT unique_id, path FROM downloads")?;
+                    let rows = statement.query_map([], |row| {
+                        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                    })?;
+                    let mut missing = Vec::new();
+                    for row in rows {
+                        let (unique_id, path) = row?;
+                        if !Path::new(&path).exists() {
+                            missing.push(unique_id);
+                        }
+                    }
+                    missing
+                };
+                for unique_id in missing {
+                    pruned += connection
+                        .execute("DELETE FROM downloads WHERE unique_id = ?1", [unique_id])?;
+                }
+            }
+            connection.execute_batch("VACUUM")?;
+            Ok::<_, rusqlite::Error>(pruned)
+        })
+        .await?
+        .context("Database maintenance failed")
+    }
+
+    /// Number and total size of the downloads from `channel`, given as chat id
+    /// or title, since `since`.
+    pub async fn channel_stats(
+        self: &Arc<Self>,
+        channel: &str,
+        since: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Result<(u64, u64)> {
+        let database = self.clone();
+        let chat_id = channel.parse::<i64>().ok();
+        let channel = channel.to_owned();
+        // dates are stored as RFC 3339 in UTC, which sorts like the dates do
+        let since = since.map_or_else(String::new, |since| since.to_rfc3339());
+        let (count, bytes) = tokio::task::spawn_blocking(move || {
+            database.connection.lock().unwrap().query_row(
+                "SELECT COUNT(*), COALESCE(SUM(size), 0) FROM downloads
+                    WHERE (chat_id = ?1 OR channel = ?2) AND date >= ?3",
+                params![chat_id, channel, since],
+                |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)),
+            )
+        })
+        .await?
+        .context("Database query failed")?;
+        Ok((count as u64, bytes as u64))
+    }
+}
@@ -0,0 +1,116 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use teloxide::types::Message;
+
+use crate::MediaFile;
+
+/// Record of a download that failed for good, written as
+/// `<dead_letter_dir>/<unique_id>.json` so it can be re-attempted later.
+#[derive(Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub file_id: String,
+    pub unique_id: String,
+    pub file_name: Option<String>,
+    pub default_ext: String,
+    pub chat_id: i64,
+    pub message_id: i32,
+    pub media_group_id: Option<String>,
+    pub error: String,
+    pub failed_at: DateTime<Utc>,
+}
+
+impl DeadLetter {
+    pub fn new(message: &Message, media: &MediaFile<'_>, error: &anyhow::Error) -> Self {
+        // copies forwarded by /fetch or a retry point back to the original post
+        let (chat_id, message_id) = match (
+            message.forward_from_chat(),
+            message.forward_from_message_id(),
+        ) {
+            (Some(chat), Some(message_id)) => (chat.id.0, message_id),
+            _ => (message.chat.id.0, message.id.0),
+        };
+        Self {
+            file_id: media.file_meta.id.clone(),
+            unique_id: media.file_meta.unique_id.clone(),
+            file_name: media.file_name.map(str::to_owned),
+            default_ext: media.default_ext.to_owned(),
+            chat_id,
+            message_id,
+            media_group_id: message.media_group_id().map(str::to_owned),
+            error: format!("{error:#}"),
+            failed_at: Utc::now(),
+        }
+    }
+}
+
+pub async fn write(dead_letter_dir: &Path, dead_letter: &DeadLetter) -> Result<()> {
+    tokio::fs::create_dir_all(dead_letter_dir)
+        .await
+        .context("Create dir all failed")?;
+    let path = dead_letter_dir.join(format!("{}.json", dead_letter.unique_id));
+    tokio::fs::write(&path, serde_json::to_vec_pretty(dead_letter)?)
+        .await
+        .with_context(|| format!("Failed to write dead letter: {}", path.display()))?;
+    log::warn!("Recorded failed download in {}", path.display());
+    Ok(())
+}
+
+/// Record of a `post_download_command` that kept failing, written as
+/// `<dead_letter_dir>/hooks/<unique_id>.json`. `command` is the rendered
+/// command line, ready to be run again.
+#[derive(Serialize)]
+pub struct HookDeadLetter {
+    pub command: Vec<String>,
+    pub path: String,
+    pub unique_id: String,
+    pub error: String,
+    pub failed_at: DateTime<Utc>,
+}
+
+pub async fn write_hook(dead_letter_dir: &Path, dead_letter: &HookDeadLetter) -> Result<()> {
+    // kept apart so load_all only sees failed downloads
+    let hooks_dir = dead_letter_dir.join("hooks");
+    tokio::fs::create_dir_all(&hooks_dir)
+        .await
+        .context("Create dir all failed")?;
+    let path = hooks_dir.join(format!("{}.json", dead_letter.unique_id));
+    tokio::fs::write(&path, serde_json::to_vec_pretty(dead_letter)?)
+        .await
+        .with_context(|| format!("Failed to write hook dead letter: {}", path.display()))?;
+    log::warn!("Recorded failed post-download hook in {}", path.display());
+    Ok(())
+}
+
+/// Removes the record of `unique_id`, if there is one.
+pub async fn remove(dead_letter_dir: &Path, unique_id: &str) {
+    let path = dead_letter_dir.join(format!("{unique_id}.json"));
+    match tokio::fs::remove_file(&path).await {
+        Ok(()) => log::info!("Removed dead letter {}, the file was saved", path.display()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => log::error!("Failed to remove dead letter {}: {e}", path.display()),
+    }
+}
+
+/// Reads every record in `dead_letter_dir` along with its path.
+pub async fn load_all(dead_letter_dir: &Path) -> Result<Vec<(PathBuf, DeadLetter)>> {
+    let mut dead_letters = Vec::new();
+    let mut entries = match tokio::fs::read_dir(dead_letter_dir).await {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(dead_letters),
+        entries => entries.context("Failed to read dead_letter_dir")?,
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let contents = tokio::fs::read(&path).await?;
+        match serde_json::from_slice(&contents) {
+            Ok(dead_letter) => dead_letters.push((path, dead_letter)),
+            Err(e) => log::warn!("Skipping unreadable dead letter {}: {e}", path.display()),
+        }
+    }
+    Ok(dead_letters)
+}
@@ -0,0 +1,118 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use teloxide::{prelude::*, types::MessageId};
+
+use crate::{AppState, TelegramBot};
+
+/// How long an album has to go without new parts before it counts as complete.
+/// Telegram delivers the parts of an album within a second or two.
+const ALBUM_SETTLE_TIME: Duration = Duration::from_secs(10);
+
+/// Posts waiting to be deleted with `delete_after_download`. Single posts are
+/// deleted right after they were saved, albums once every part was saved.
+#[derive(Default)]
+pub struct Deletions {
+    albums: Mutex<HashMap<String, PendingAlbum>>,
+}
+
+#[derive(Default)]
+struct PendingAlbum {
+    in_progress: usize,
+    saved: Vec<MessageId>,
+    failed: bool,
+}
+
+impl Deletions {
+    /// Called before a post is processed, so an album isn't deleted while some
+    /// of its parts are still downloading.
+    pub fn begin(&self, message: &Message) {
+        if let Some(media_group_id) = message.media_group_id() {
+            let mut albums = self.albums.lock().unwrap();
+            albums
+                .entry(media_group_id.to_owned())
+                .or_default()
+                .in_progress += 1;
+        }
+    }
+
+    /// Called after a post was processed, `archived` when its file was saved.
+    pub fn finish<B: TelegramBot>(
+        &self,
+        bot: Arc<B>,
+        message: &Message,
+        archived: bool,
+        app_state: Arc<AppState>,
+    ) {
+        let chat_id = message.chat.id;
+        let Some(media_group_id) = message.media_group_id() else {
+            if archived {
+                tokio::spawn(delete_posts(bot, chat_id, vec![message.id]));
+            }
+            return;
+        };
+
+        let mut albums = self.albums.lock().unwrap();
+        let album = albums.entry(media_group_id.to_owned()).or_default();
+        album.in_progress = album.in_progress.saturating_sub(1);
+        if archived {
+            album.saved.push(message.id);
+        } else {
+            album.failed = true;
+        }
+        if album.in_progress == 0 {
+            let media_group_id = media_group_id.to_owned();
+            tokio::spawn(async move {
+                tokio::time::sleep(ALBUM_SETTLE_TIME).await;
+                let album = {
+                    let mut albums = app_state.deletions.albums.lock().unwrap();
+                    // a later part is still downloading, it schedules the deletion
+                    if albums
+                        .get(&media_group_id)
+                        .is_none_or(|album| album.in_progress > 0)
+                    {
+                        return;
+                    }
+                    albums.remove(&media_group_id)
+                };
+                match album {
+                    Some(album) if album.failed => log::warn!(
+                        "Not deleting album {media_group_id}, not all of its parts were saved"
+                    ),
+                    Some(album) => delete_posts(bot, chat_id, album.saved).await,
+                    None => {}
+                }
+            });
+        }
+    }
+}
+
+async fn delete_posts<B: TelegramBot>(bot: Arc<B>, chat_id: ChatId, message_ids: Vec<MessageId>) {
+    for message_id in message_ids {
+        log::warn!("Deleting post {message_id} of {chat_id} after download");
+        if let Err(e) = bot.delete_message(chat_id, message_id).await {
+            log::error!("Failed to delete post {message_id} of {chat_id}: {e}");
+        }
+    }
+}
+
+/// Makes sure the bot may delete posts in every watched channel before
+/// `delete_after_download` is trusted with them.
+pub async fn check_permissions(bot: &Bot, chat_ids: impl Iterator<Item = i64>) -> Result<()> {
+    let me = bot.get_me().await.context("Failed to get the bot user")?;
+    for chat_id in chat_ids {
+        let member = bot
+            .get_chat_member(ChatId(chat_id), me.id)
+            .await
+            .with_context(|| format!("Failed to get the bot's permissions in {chat_id}"))?;
+        if !member.kind.can_delete_messages() {
+            bail!("delete_after_download is set but the bot can't delete posts in {chat_id}");
+        }
+    }
+    log::warn!("delete_after_download is on, saved posts will be deleted from the channels");
+    Ok(())
+}
@@ -0,0 +1,97 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Result;
+
+use crate::gallery;
+
+/// Rolls downloads over into numbered siblings (`dir`, `dir-2`, `dir-3`, ...)
+/// once a directory holds `max_files_per_dir` files. The state is taken from
+/// what's on disk the first time a directory is used, so it carries over
+/// restarts without a separate counter file.
+pub struct DirLimit {
+    max_files: u64,
+    dirs: Mutex<HashMap<PathBuf, ActiveDir>>,
+}
+
+struct ActiveDir {
+    number: u32,
+    files: u64,
+}
+
+impl DirLimit {
+    pub fn new(max_files: u64) -> Self {
+        Self {
+            max_files,
+            dirs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Directory the next file meant for `dir` goes into.
+    pub async fn pick(&self, dir: &Path) -> Result<PathBuf> {
+        let known = self.dirs.lock().unwrap().contains_key(dir);
+        let scanned = if known {
+            None
+        } else {
+            let dir = dir.to_owned();
+            Some(tokio::task::spawn_blocking(move || scan(&dir)).await??)
+        };
+        let mut dirs = self.dirs.lock().unwrap();
+        let active = dirs.entry(dir.to_owned()).or_insert_with(|| {
+            scanned.unwrap_or(ActiveDir {
+                number: 1,
+                files: 0,
+            })
+        });
+        if active.files >= self.max_files {
+            active.number += 1;
+            active.files = 0;
+            log::info!(
+                "{} is full, continuing in {}",
+                numbered(dir, active.number - 1).display(),
+                numbered(dir, active.number).display()
+            );
+        }
+        active.files += 1;
+        Ok(numbered(dir, active.number))
+    }
+}
+
+/// Finds the last numbered sibling of `dir` and counts the media files in it.
+fn scan(dir: &Path) -> io::Result<ActiveDir> {
+    let mut number = 1;
+    while numbered(dir, number + 1).is_dir() {
+        number += 1;
+    }
+    let entries = match std::fs::read_dir(numbered(dir, number)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(ActiveDir { number, files: 0 }),
+        Err(e) => return Err(e),
+    };
+    let mut files = 0;
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_file()
+            && !gallery::is_companion_file(&entry.file_name().to_string_lossy())
+        {
+            files += 1;
+        }
+    }
+    Ok(ActiveDir { number, files })
+}
+
+fn numbered(dir: &Path, number: u32) -> PathBuf {
+    if number == 1 {
+        return dir.to_owned();
+    }
+    let mut name = dir
+        .file_name()
+        .map_or_else(OsString::new, ToOwned::to_owned);
+    name.push(format!("-{number}"));
+    dir.with_file_name(name)
+}
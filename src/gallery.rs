@@ -0,0 +1,161 @@
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+
+use crate::{sidecar, AppState};
+
+/// Downloads finishing within this time of each other share one regeneration.
+const DEBOUNCE: Duration = Duration::from_secs(5);
+
+const GALLERY_FILE: &str = "index.html";
+
+/// Regenerates the gallery of `dir` shortly, unless that's already scheduled.
+pub fn schedule(app_state: Arc<AppState>, dir: PathBuf) {
+    if !app_state
+        .gallery_pending
+        .lock()
+        .unwrap()
+        .insert(dir.clone())
+    {
+        return;
+    }
+    tokio::spawn(async move {
+        tokio::time::sleep(DEBOUNCE).await;
+        app_state.gallery_pending.lock().unwrap().remove(&dir);
+        if let Err(e) = generate(&dir).await {
+            log::error!("Failed to generate gallery of {}: {e:#}", dir.display());
+        }
+    });
+}
+
+/// Regenerates the galleries of `root` and every directory below it, returning
+/// how many were written.
+pub async fn generate_all(root: &Path) -> Result<usize> {
+    let mut pending = vec![root.to_owned()];
+    let mut count = 0;
+    while let Some(dir) = pending.pop() {
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_type().await?.is_dir() {
+                pending.push(entry.path());
+            }
+        }
+        generate(&dir).await?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Writes `index.html` into `dir`, listing its subdirectories and media with
+/// the captions from the `write_metadata` sidecars.
+pub async fn generate(dir: &Path) -> Result<()> {
+    let mut dirs = Vec::new();
+    let mut files = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .with_context(|| format!("Failed to read {}", dir.display()))?;
+    while let Some(entry) = entries.next_entry().await? {
+        let Some(name) = entry.file_name().to_str().map(str::to_owned) else {
+            continue;
+        };
+        if entry.file_type().await?.is_dir() {
+            dirs.push(name);
+        } else if !is_companion_file(&name) {
+            files.push(name);
+        }
+    }
+    dirs.sort();
+    files.sort();
+
+    let title = dir
+        .file_name()
+        .map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n\
+         <style>body{{font-family:sans-serif}}figure{{display:inline-block;width:240px;\
+         vertical-align:top;margin:8px}}img,video{{max-width:240px;max-height:240px}}\
+         figcaption{{font-size:small;white-space:pre-wrap;overflow-wrap:anywhere}}</style>\n\
+         </head>\n<body>\n<h1>{}</h1>\n<ul>\n",
+        sidecar::escape_xml(&title),
+        sidecar::escape_xml(&title),
+    );
+    for name in &dirs {
+        let _ = writeln!(
+            html,
+            "<li><a href=\"{}/{GALLERY_FILE}\">{}/</a></li>",
+            percent_encode(name),
+            sidecar::escape_xml(name)
+        );
+    }
+    html.push_str("</ul>\n");
+    for name in &files {
+        let href = percent_encode(name);
+        let caption = caption(&dir.join(name)).await.unwrap_or_default();
+        let preview = match media_kind(name) {
+            Some("image") => format!("<img src=\"{href}\" loading=\"lazy\" alt=\"\">"),
+            Some("video") => format!("<video src=\"{href}\" controls preload=\"none\"></video>"),
+            Some("audio") => format!("<audio src=\"{href}\" controls preload=\"none\"></audio>"),
+            _ => String::new(),
+        };
+        let _ = writeln!(
+            html,
+            "<figure>{preview}<figcaption><a href=\"{href}\">{}</a>\n{}</figcaption></figure>",
+            sidecar::escape_xml(name),
+            sidecar::escape_xml(&caption)
+        );
+    }
+    html.push_str("</body>\n</html>\n");
+
+    tokio::fs::write(dir.join(GALLERY_FILE), html)
+        .await
+        .with_context(|| format!("Failed to write the gallery of {}", dir.display()))
+}
+
+/// Files the bot writes next to the media, not media themselves.
+pub fn is_companion_file(name: &str) -> bool {
+    name == GALLERY_FILE
+        || name == "captions.txt"
+        || name.starts_with('.')
+        || [".json", ".nfo", ".part", ".chunks", ".tmp"]
+            .iter()
+            .any(|suffix| name.ends_with(suffix))
+}
+
+fn media_kind(name: &str) -> Option<&'static str> {
+    let extension = Path::new(name).extension()?.to_str()?.to_ascii_lowercase();
+    match extension.as_str() {
+        "jpg" | "jpeg" | "png" | "gif" | "webp" => Some("image"),
+        "mp4" | "webm" | "mov" | "mkv" => Some("video"),
+        "mp3" | "m4a" | "ogg" | "oga" | "opus" | "flac" | "wav" => Some("audio"),
+        _ => None,
+    }
+}
+
+async fn caption(media_path: &Path) -> Option<String> {
+    let contents = tokio::fs::read(sidecar::sidecar_path(media_path))
+        .await
+        .ok()?;
+    let metadata: serde_json::Value = serde_json::from_slice(&contents).ok()?;
+    metadata.get("caption")?.as_str().map(str::to_owned)
+}
+
+/// Keeps file names with spaces, `#` or `?` working as relative links.
+fn percent_encode(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => {
+                let _ = write!(encoded, "%{byte:02X}");
+            }
+        }
+    }
+    encoded
+}
@@ -0,0 +1,240 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+
+use tokio::process::Command;
+
+use crate::{
+    dead_letter,
+    retry::{self, RetryPolicy},
+    template,
+};
+
+/// Runs `post_download_command` for a finished download in the background.
+///
+/// Tokens are substituted per argument and the command is executed directly,
+/// not through a shell, so captions can't inject anything. Failures that may
+/// go away are retried with `policy`, then logged and recorded in
+/// `dead_letter_dir`, they never affect the download itself.
+pub fn spawn_post_download_hook(
+    command: &[String],
+    path: &Path,
+    unique_id: &str,
+    caption: Option<&str>,
+    message_link: Option<&str>,
+    policy: RetryPolicy,
+    dead_letter_dir: Option<PathBuf>,
+) {
+    let args = render_args(command, path, unique_id, caption, message_link);
+    let path = path.display().to_string();
+    let unique_id = unique_id.to_owned();
+
+    tokio::spawn(async move {
+        let Some((program, program_args)) = args.split_first() else {
+            return;
+        };
+        let result = retry::retry(
+            policy,
+            "Post-download hook",
+            retry::classify_hook_error,
+            || run_hook(program, program_args),
+        )
+        .await;
+        let Err(e) = result else {
+            log::debug!("Post-download hook finished for {path}");
+            return;
+        };
+        log::error!("Post-download hook for {path} failed: {e:#}");
+        if let Some(dead_letter_dir) = dead_letter_dir {
+            let dead_letter = dead_letter::HookDeadLetter {
+                command: args,
+                path,
+                unique_id,
+                error: format!("{e:#}"),
+                failed_at: Utc::now(),
+            };
+            if let Err(e) = dead_letter::write_hook(&dead_letter_dir, &dead_letter).await {
+                log::error!("Failed to write hook dead letter: {e:#}");
+            }
+        }
+    });
+}
+
+async fn run_hook(program: &str, args: &[String]) -> Result<()> {
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .with_context(|| format!("Failed to run {program}"))?;
+    if !output.status.success() {
+        bail!(
+            "{program} failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Runs `scan_command` on a finished download and waits for it, `false` means
+/// the scanner flagged the file by exiting with a nonzero status.
+pub async fn scan_file(
+    command: &[String],
+    path: &Path,
+    unique_id: &str,
+    caption: Option<&str>,
+    message_link: Option<&str>,
+) -> Result<bool> {
+    let args = render_args(command, path, unique_id, caption, message_link);
+    let Some((program, args)) = args.split_first() else {
+        return Ok(true);
+    };
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .with_context(|| format!("Failed to run scan command {program}"))?;
+    if !output.status.success() {
+        log::warn!(
+            "Scan of {} failed with {}: {}",
+            path.display(),
+            output.status,
+            String::from_utf8_lossy(&output.stdout).trim()
+        );
+    }
+    Ok(output.status.success())
+}
+
+/// Fails when ffprobe finds the media at `path` unreadable, e.g. a video cut off
+/// or corrupted on the way.
+pub async fn ffprobe(ffprobe: &str, path: &Path) -> Result<()> {
+    let output = Command::new(ffprobe)
+        .args([
+            "-v",
+            "error",
+            "-show_entries",
+            "format=duration",
+            "-of",
+            "csv=p=0",
+        ])
+        .arg(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .with_context(|| format!("Failed to run {ffprobe}"))?;
+    let errors = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() || !errors.trim().is_empty() {
+        bail!(
+            "ffprobe found {} broken ({}): {}",
+            path.display(),
+            output.status,
+            errors.trim()
+        );
+    }
+    Ok(())
+}
+
+/// Runs a `conversions` command turning `input` into `output` and waits for it.
+pub async fn convert(command: &[String], input: &Path, output: &Path) -> Result<()> {
+    let input_arg = input.display().to_string();
+    let output_arg = output.display().to_string();
+    let args: Vec<String> = command
+        .iter()
+        .map(|arg| {
+            template::render(arg, |token| match token {
+                "input" => Some(input_arg.clone()),
+                "output" => Some(output_arg.clone()),
+                _ => None,
+            })
+        })
+        .collect();
+    let Some((program, args)) = args.split_first() else {
+        bail!("Empty conversion command");
+    };
+    let finished = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .with_context(|| format!("Failed to run conversion command {program}"))?;
+    if !finished.status.success() {
+        bail!(
+            "{program} failed with {}: {}",
+            finished.status,
+            String::from_utf8_lossy(&finished.stderr).trim()
+        );
+    }
+    if !tokio::fs::try_exists(output).await.unwrap_or(false) {
+        bail!("{program} didn't write {}", output.display());
+    }
+    Ok(())
+}
+
+/// Runs `ocr_command` on a photo and returns what it printed, trimmed.
+pub async fn ocr(command: &[String], path: &Path) -> Result<String> {
+    let path_arg = path.display().to_string();
+    let args: Vec<String> = command
+        .iter()
+        .map(|arg| {
+            template::render(arg, |token| match token {
+                "path" => Some(path_arg.clone()),
+                _ => None,
+            })
+        })
+        .collect();
+    let Some((program, args)) = args.split_first() else {
+        bail!("Empty OCR command");
+    };
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .with_context(|| format!("Failed to run OCR command {program}"))?;
+    if !output.status.success() {
+        bail!(
+            "{program} failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+fn render_args(
+    command: &[String],
+    path: &Path,
+    unique_id: &str,
+    caption: Option<&str>,
+    message_link: Option<&str>,
+) -> Vec<String> {
+    let path = path.display().to_string();
+    command
+        .iter()
+        .map(|arg| {
+            template::render(arg, |token| match token {
+                "path" => Some(path.clone()),
+                "unique_id" => Some(unique_id.to_owned()),
+                "caption" => Some(caption.unwrap_or("").to_owned()),
+                "message_link" => Some(message_link.unwrap_or("").to_owned()),
+                _ => None,
+            })
+        })
+        .collect()
+}
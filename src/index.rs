@@ -0,0 +1,163 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::{content_store::BLOBS_DIR, crypto::ENCRYPTED_EXTENSION};
+
+const MIN_UNIQUE_ID_LEN: usize = 8;
+
+/// Maps the `unique_id` of every downloaded file to where it was saved.
+#[derive(Default)]
+pub struct DownloadIndex {
+    entries: Mutex<HashMap<String, PathBuf>>,
+}
+
+impl DownloadIndex {
+    pub fn contains(&self, unique_id: &str) -> bool {
+        self.entries.lock().unwrap().contains_key(unique_id)
+    }
+
+    pub fn insert(&self, unique_id: String, path: PathBuf) {
+        self.entries.lock().unwrap().insert(unique_id, path);
+    }
+
+    pub fn replace(&self, entries: HashMap<String, PathBuf>) {
+        *self.entries.lock().unwrap() = entries;
+    }
+}
+
+/// Walks `media_directory` and collects the unique ids encoded in the names
+/// of the files found. Files that weren't named by the default scheme are
+/// skipped. Blocking, run it on a blocking thread.
+pub fn scan(media_directory: &Path) -> io::Result<HashMap<String, PathBuf>> {
+    let mut entries = HashMap::new();
+    if !media_directory.exists() {
+        return Ok(entries);
+    }
+
+    let mut pending = vec![media_directory.to_owned()];
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                // blobs are named after their content, the links to them are indexed
+                if dir != media_directory || entry.file_name() != BLOBS_DIR {
+                    pending.push(entry.path());
+                }
+            } else if file_type.is_file() || file_type.is_symlink() {
+                if let Some(unique_id) = entry
+                    .file_name()
+                    .to_str()
+                    .and_then(unique_id_from_file_name)
+                {
+                    entries.insert(unique_id.to_owned(), entry.path());
+                }
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Extracts the unique id from names like `[caption]_<unique_id>.jpg`,
+/// `title:[title]_<unique_id>{page:2}.jpg` or `<unique_id>.jpg` for media
+/// without a title.
+fn unique_id_from_file_name(file_name: &str) -> Option<&str> {
+    let file_name = file_name
+        .strip_suffix(ENCRYPTED_EXTENSION)
+        .and_then(|name| name.strip_suffix('.'))
+        .unwrap_or(file_name);
+    let stem = match file_name.rsplit_once('.') {
+        // named after the video it describes
+        Some((_, "nfo")) => return None,
+        Some((stem, _)) => stem,
+        None => file_name,
+    };
+
+    let rest = stem.rsplit_once("]_").map_or(stem, |(_, rest)| rest);
+    let unique_id = rest
+        .split_once('{')
+        .map_or(rest, |(unique_id, _)| unique_id);
+
+    is_unique_id(unique_id).then_some(unique_id)
+}
+
+/// Whether `s` looks like a `file_unique_id`: base64url, never shorter than
+/// [`MIN_UNIQUE_ID_LEN`] and always with some uppercase letters, unlike the
+/// page numbers of album files or names like `captions` and `index`.
+fn is_unique_id(s: &str) -> bool {
+    s.len() >= MIN_UNIQUE_ID_LEN
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        && s.chars().any(|c| c.is_ascii_uppercase())
+}
+
+/// Replaces the contents of `index` with a fresh scan of `media_directory`,
+/// returning the number of entries found.
+pub async fn rebuild(index: &DownloadIndex, media_directory: PathBuf) -> anyhow::Result<usize> {
+    let entries = tokio::task::spawn_blocking(move || scan(&media_directory)).await??;
+    let len = entries.len();
+    index.replace(entries);
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unique_ids_are_read_from_the_default_names() {
+        for (name, unique_id) in [
+            ("AQADkK4xG8TzqUp-.jpg", "AQADkK4xG8TzqUp-"),
+            ("[Holiday]_AgADtwUAAolC0VE.mp4", "AgADtwUAAolC0VE"),
+            ("title:[Trip]_AgADPQADO9sAAU{page:2}.jpg", "AgADPQADO9sAAU"),
+            ("[a]_b]_AQADkK4xG8TzqUp.jpg.enc", "AQADkK4xG8TzqUp"),
+        ] {
+            assert_eq!(unique_id_from_file_name(name), Some(unique_id), "{name}");
+        }
+    }
+
+    #[test]
+    fn other_files_are_not_indexed() {
+        for name in [
+            "captions.txt",
+            "1.jpg",
+            "2.jpg",
+            "10.mp4.enc",
+            "index.html",
+            "manifest-2024-01-02.json",
+            "AQADkK4xG8TzqUp-.jpg.meta.json",
+            "AQADkK4xG8TzqUp-.jpg.context.json",
+            "AQADkK4xG8TzqUp-.nfo",
+            "[caption]_.jpg",
+        ] {
+            assert_eq!(unique_id_from_file_name(name), None, "{name}");
+        }
+    }
+
+    #[test]
+    fn album_pages_and_sidecars_are_skipped_by_scan() {
+        let root =
+            std::env::temp_dir().join(format!("tg_download_bot_index_{}", std::process::id()));
+        let album = root.join("Trip");
+        std::fs::create_dir_all(&album).unwrap();
+        for name in ["1.jpg", "2.jpg", "captions.txt"] {
+            std::fs::write(album.join(name), "").unwrap();
+        }
+        std::fs::write(root.join("[Holiday]_AgADtwUAAolC0VE.mp4"), "").unwrap();
+        std::fs::write(root.join("[Holiday]_AgADtwUAAolC0VE.nfo"), "").unwrap();
+
+        let entries = scan(&root).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+        assert_eq!(
+            entries,
+            HashMap::from([(
+                "AgADtwUAAolC0VE".to_owned(),
+                root.join("[Holiday]_AgADtwUAAolC0VE.mp4")
+            )])
+        );
+    }
+}
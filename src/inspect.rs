@@ -0,0 +1,93 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use tokio::io::AsyncWrite;
+
+/// How much of the start of a file is kept for content type detection.
+pub const SNIFF_LEN: usize = 8 * 1024;
+
+/// Usual extension of the media MIME types Telegram reports for videos and
+/// audio.
+pub fn mime_extension(mime_type: &str) -> Option<&'static str> {
+    Some(match mime_type {
+        "video/mp4" => "mp4",
+        "video/quicktime" => "mov",
+        "video/webm" => "webm",
+        "video/x-matroska" => "mkv",
+        "video/mpeg" => "mpg",
+        "video/x-msvideo" => "avi",
+        "audio/mpeg" | "audio/mp3" => "mp3",
+        "audio/mp4" | "audio/x-m4a" => "m4a",
+        "audio/ogg" => "ogg",
+        "audio/opus" => "opus",
+        "audio/flac" | "audio/x-flac" => "flac",
+        "audio/wav" | "audio/x-wav" => "wav",
+        _ => return None,
+    })
+}
+
+/// Whether two extensions stand for the same file type, e.g. `jpg` and `JPEG`.
+pub fn same_extension(a: &str, b: &str) -> bool {
+    const ALIASES: &[(&str, &str)] = &[("jpg", "jpeg"), ("tif", "tiff"), ("mpg", "mpeg")];
+    let (a, b) = (a.to_ascii_lowercase(), b.to_ascii_lowercase());
+    a == b
+        || ALIASES
+            .iter()
+            .any(|&(x, y)| (a == x && b == y) || (a == y && b == x))
+}
+
+/// Passes writes through to `inner` while counting the bytes and keeping a
+/// copy of the first `sniff_limit` of them, so the file type can be detected
+/// from its magic bytes once the download is done.
+pub struct InspectingWriter<W> {
+    inner: W,
+    head: Vec<u8>,
+    sniff_limit: usize,
+    written: u64,
+}
+
+impl<W> InspectingWriter<W> {
+    pub fn new(inner: W, sniff_limit: usize) -> Self {
+        Self {
+            inner,
+            head: Vec::with_capacity(sniff_limit),
+            sniff_limit,
+            written: 0,
+        }
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.written
+    }
+
+    /// Extension matching the magic bytes seen so far, if they are recognized.
+    pub fn sniffed_extension(&self) -> Option<&'static str> {
+        infer::get(&self.head).map(|kind| kind.extension())
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for InspectingWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        let captured = n.min(this.sniff_limit - this.head.len());
+        this.head.extend_from_slice(&buf[..captured]);
+        this.written += n as u64;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -1,256 +1,5624 @@
 use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashSet},
     env,
-    path::{Path, PathBuf},
-    sync::{Arc, Mutex},
-    time::Duration,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
-use anyhow::{Context, Ok, Result};
+use anyhow::{bail, Context, Ok, Result};
+use chacha20poly1305::Key;
+use chrono::DateTime;
+use chrono_tz::Tz;
 use config::{Config, FileFormat};
-use log::warn;
+use log::{warn, LevelFilter};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use regex::RegexSet;
 use reqwest::Url;
 use secrecy::{ExposeSecret, SecretString};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize, Serializer};
+use sha2::{Digest, Sha256};
 use teloxide::{
+    adaptors::throttle::Limits,
     net::Download,
     prelude::*,
-    types::{FileMeta, MediaKind, MessageCommon, MessageKind},
+    types::{
+        Chat, FileMeta, MediaKind, MessageCommon, MessageKind, PhotoSize, Recipient, UpdateKind,
+    },
+    update_listeners::Polling,
+    DownloadError, RequestError,
 };
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::{io::AsyncWriteExt, sync::Semaphore};
+use unicode_normalization::UnicodeNormalization as _;
+
+use crate::{
+    album_quality::{AlbumQualities, Decision},
+    bandwidth::{BandwidthScope, InflightLimit, RateLimitedWriter, TokenBucket},
+    bounded::BoundedMap,
+    chunked::ChunkedDownloader,
+    circuit_breaker::CircuitBreaker,
+    commands::AdminCommand,
+    content_store::StorageLayout,
+    crypto::EncryptedWriter,
+    daily_manifest::DailyManifest,
+    database::{Database, DownloadRecord},
+    dead_letter::DeadLetter,
+    deletion::Deletions,
+    dir_limit::DirLimit,
+    index::DownloadIndex,
+    inspect::InspectingWriter,
+    reactions::{Outcome, Reactions, Reactor},
+    retry::RetryPolicy,
+    seq::SeqCounter,
+    storage::{LocalStorage, Storage, StorageKind, WebDavConfig},
+    telemetry::Telemetry,
+};
+
+mod album_quality;
+mod bandwidth;
+mod benchmark;
+mod bounded;
+mod chunked;
+mod circuit_breaker;
+mod clean_sidecars;
+mod commands;
+mod content_store;
+mod crypto;
+mod daily_manifest;
+mod database;
+mod dead_letter;
+mod deletion;
+mod dir_limit;
+mod gallery;
+mod hooks;
+mod index;
+mod inspect;
+mod migrate;
+mod orient;
+mod phash;
+mod reactions;
+mod retry;
+mod seq;
+mod sidecar;
+mod storage;
+mod telemetry;
+mod template;
 
 const CONFIG_PATH_ENV: &str = "CONFIG_PATH";
 const TELEGRAM_BOT_API_URL_ENV: &str = "TELEGRAM_BOT_API_URL";
+const VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    " (",
+    env!("BUILD_GIT_COMMIT"),
+    " built ",
+    env!("BUILD_TIMESTAMP"),
+    ")"
+);
+/// Timeout of every HTTP request to the Bot API, long enough for big downloads.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(600);
+/// Albums past `album_grace_secs` that are remembered.
+const MAX_EVICTED_MEDIA_GROUPS: usize = 1000;
+/// Albums whose caption is remembered for the filters of their later items.
+const MAX_TRACKED_MEDIA_GROUPS: usize = 10_000;
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    env_logger::init();
+
+    let mut args = env::args().skip(1);
+    if let Some(command) = args.next() {
+        return match command.as_str() {
+            "--version" | "-V" => {
+                println!("{} {VERSION}", env!("CARGO_PKG_NAME"));
+                Ok(())
+            }
+            "--print-config" => {
+                let app_config = read_config().context("Config read failed")?;
+                print!("{}", toml::to_string(&app_config)?);
+                Ok(())
+            }
+            "decrypt" => {
+                let (Some(input), Some(output)) = (args.next(), args.next()) else {
+                    bail!("Usage: decrypt <input.enc> <output>");
+                };
+                decrypt(Path::new(&input), Path::new(&output)).await
+            }
+            "migrate" => {
+                let apply = match args.next().as_deref() {
+                    None => false,
+                    Some("--apply") => true,
+                    Some(_) => bail!("Usage: migrate [--apply]"),
+                };
+                let app_config = read_config().context("Config read failed")?;
+                migrate::run(&app_config, apply).await
+            }
+            "clean-sidecars" => {
+                let apply = match args.next().as_deref() {
+                    None => false,
+                    Some("--apply") => true,
+                    Some(_) => bail!("Usage: clean-sidecars [--apply]"),
+                };
+                let app_config = read_config().context("Config read failed")?;
+                clean_sidecars::run(Path::new(&app_config.media_directory), apply).await
+            }
+            "benchmark" => {
+                let Some(file_id) = args.next() else {
+                    bail!("Usage: benchmark <file_id> [iterations]");
+                };
+                let iterations = match args.next() {
+                    Some(iterations) => iterations.parse().context("Invalid iteration count")?,
+                    None => benchmark::DEFAULT_ITERATIONS,
+                };
+                let app_config = read_config().context("Config read failed")?;
+                let client = build_client(&app_config).context("Client creation failed")?;
+                let bot = build_bot(&app_config, client).context("Bot creation failed")?;
+                benchmark::run(&bot, &file_id, iterations).await
+            }
+            _ => bail!("Unknown command: {command}"),
+        };
+    }
+
     let app_config = read_config().context("Config read failed")?;
+    let encryption_key = crypto::load_key(app_config.encryption_key_file.as_deref())
+        .context("Encryption key load failed")?;
+
+    let telemetry = match app_config.otel_endpoint.clone() {
+        Some(endpoint) => Some(
+            Telemetry::init(endpoint)
+                .await
+                .context("OpenTelemetry setup failed")?,
+        ),
+        None => None,
+    };
 
-    run_bot(app_config).await;
+    let result = run_bot(app_config, encryption_key).await;
 
-    Ok(())
+    if let Some(telemetry) = telemetry {
+        telemetry.shutdown().await;
+    }
+    result
 }
 
-#[derive(Deserialize)]
+async fn decrypt(input: &Path, output: &Path) -> Result<()> {
+    let key_file = if env::var_os(crypto::ENCRYPTION_KEY_ENV).is_some() {
+        None
+    } else {
+        read_config()
+            .context("Config read failed")?
+            .encryption_key_file
+    };
+    let key = crypto::load_key(key_file.as_deref())?.with_context(|| {
+        format!(
+            "No encryption key configured, set {} or encryption_key_file",
+            crypto::ENCRYPTION_KEY_ENV
+        )
+    })?;
+
+    crypto::decrypt_file(&key, input, output).await
+}
+
+#[derive(Deserialize, Serialize)]
 struct AppConfig {
+    #[serde(serialize_with = "serialize_redacted")]
     bot_token: SecretString,
-    channel_id: i64,
+    channel_id: ChannelRef,
     media_directory: String,
+    /// What a relative `media_directory` is resolved against instead of the
+    /// working directory.
+    media_directory_base: Option<String>,
+    #[serde(default)]
+    heartbeat_secs: Option<u64>,
+    /// Where SIGUSR1 writes the state dump, the log when unset.
+    state_dump_path: Option<String>,
+    /// How often the bot's access to the watched chats is checked again.
+    access_check_secs: Option<u64>,
+    /// Exits when no update arrived for this long, after at least one did.
+    watchdog_secs: Option<u64>,
+    #[serde(default)]
+    spoiler_subdir: Option<String>,
+    #[serde(default)]
+    spoiler_prefix: Option<String>,
+    #[serde(default)]
+    encryption_key_file: Option<String>,
+    #[serde(default)]
+    filename_template: Option<String>,
+    /// Used instead of `filename_template` for audio with performer and title.
+    audio_filename_template: Option<String>,
+    #[serde(default)]
+    allow_nonunique_template: bool,
+    /// Where the `{seq}` counter is kept, `<media_directory>/.seq` by default.
+    seq_file: Option<String>,
+    #[serde(default)]
+    admin_ids: Vec<u64>,
+    #[serde(default)]
+    skip_duplicates: bool,
+    #[serde(default)]
+    ca_cert_path: Option<String>,
+    #[serde(default)]
+    danger_accept_invalid_certs: bool,
+    /// Logs every `getFile` call and file download.
+    #[serde(default)]
+    debug_http: bool,
+    /// reqwest connection pool settings, its defaults when unset.
+    pool_max_idle_per_host: Option<usize>,
+    pool_idle_timeout_secs: Option<u64>,
+    tcp_keepalive_secs: Option<u64>,
+    tcp_nodelay: Option<bool>,
+    #[serde(default)]
+    sniff_extension: bool,
+    /// Like `sniff_extension`, but for every extension, not only the default.
+    #[serde(default)]
+    correct_extension_after_download: bool,
+    /// Takes the extension from the file name the sender gave, otherwise from
+    /// the MIME type or the media type.
+    #[serde(default = "default_true")]
+    trust_file_name_extension: bool,
+    #[serde(default = "default_unhandled_update_log_level")]
+    unhandled_update_log_level: LevelFilter,
+    #[serde(default)]
+    post_download_command: Vec<String>,
+    #[serde(default)]
+    reactions: Reactions,
+    /// Retries of a failing `post_download_command`, with the usual backoff.
+    #[serde(default)]
+    hook_max_retries: u32,
+    #[serde(default)]
+    require_caption: bool,
+    /// Ignores posts forwarded from elsewhere.
+    #[serde(default)]
+    skip_forwards: bool,
+    /// Only media whose caption matches one of these regexes is downloaded.
+    #[serde(default)]
+    caption_include_patterns: Vec<String>,
+    /// Videos shorter than this are skipped.
+    min_video_duration_secs: Option<u32>,
+    /// Videos whose shorter side has fewer pixels than this are skipped.
+    min_video_resolution: Option<u32>,
+    #[serde(default)]
+    storage: StorageKind,
+    #[serde(default)]
+    storage_layout: StorageLayout,
+    #[serde(default)]
+    webdav: Option<WebDavConfig>,
+    #[serde(default)]
+    photo_size: PhotoSizeSelection,
+    #[serde(default)]
+    otel_endpoint: Option<String>,
+    #[serde(default)]
+    dead_letter_dir: Option<String>,
+    #[serde(default)]
+    retry_dead_letters_on_start: bool,
+    #[serde(default)]
+    album_folders: bool,
+    /// How long after its last part an album still takes new parts, forever
+    /// when unset.
+    album_grace_secs: Option<u64>,
+    /// Keeps an `album.json` listing the parts in every album folder.
+    #[serde(default)]
+    album_manifest: bool,
+    max_media_group_items: Option<u32>,
+    /// Items of an album downloaded at most, the rest is skipped.
+    max_items_per_album: Option<usize>,
+    #[serde(default)]
+    oversized_media_groups: OversizedMediaGroups,
+    /// Least time between the get_file calls of the items of one album.
+    album_get_file_spacing_ms: Option<u64>,
+    #[serde(default = "default_max_retries")]
+    max_retries: u32,
+    #[serde(default = "default_retry_base_delay_ms")]
+    retry_base_delay_ms: u64,
+    #[serde(default = "default_retry_jitter")]
+    retry_jitter: f64,
+    #[serde(default)]
+    message_max_retries: u32,
+    #[serde(default)]
+    date_subdirs: bool,
+    #[serde(default)]
+    timezone: Timezone,
+    #[serde(default)]
+    save_non_media: bool,
+    /// Saves files of media kinds the bot has no dedicated handling for.
+    #[serde(default)]
+    download_unknown_media: bool,
+    #[serde(default = "default_unknown_media_dir")]
+    unknown_media_dir: String,
+    #[serde(default = "default_max_title_len")]
+    max_title_len: usize,
+    /// Title used for media without caption and file name.
+    empty_title_label: Option<String>,
+    /// Treats titles without letters or digits, e.g. only emoji, as empty.
+    #[serde(default)]
+    drop_symbol_only_titles: bool,
+    /// Longest file name in bytes, 255 on most filesystems.
+    #[serde(default = "default_max_file_name_bytes")]
+    max_file_name_bytes: usize,
+    /// Longest path of a saved file in bytes, 260 on Windows and 4096
+    /// elsewhere when unset.
+    max_path_len: Option<usize>,
+    /// Replaces `/` and `\` in titles so they can't split a path.
+    #[serde(default = "default_path_separator_replacement")]
+    path_separator_replacement: String,
+    #[serde(default)]
+    write_metadata: bool,
+    /// Adds `message_link` to the `write_metadata` sidecar.
+    #[serde(default)]
+    metadata_message_link: bool,
+    /// Adds the `run_id` of the bot run to the `write_metadata` sidecar.
+    #[serde(default)]
+    metadata_run_id: bool,
+    #[serde(default)]
+    write_context: bool,
+    #[serde(default)]
+    write_nfo: bool,
+    /// Keeps an `index.html` of the media in every download directory.
+    #[serde(default)]
+    generate_gallery: bool,
+    /// Leaves a failed download as `<file>.part` instead of removing it.
+    #[serde(default)]
+    keep_partial_on_failure: bool,
+    /// Starts even though another instance holds the lock on `media_directory`.
+    #[serde(default)]
+    allow_shared_directory: bool,
+    /// All-or-nothing downloads, see config.example.toml for the steps.
+    #[serde(default)]
+    strict_pipeline: bool,
+    #[serde(default)]
+    caption_log: bool,
+    /// CSV file that gets a row for every download.
+    csv_export: Option<String>,
+    /// Writes the downloads of every day to `manifest-YYYY-MM-DD.json`.
+    #[serde(default)]
+    daily_manifest: bool,
+    /// Deletes posts from the channel once their media was saved.
+    #[serde(default)]
+    delete_after_download: bool,
+    #[serde(default)]
+    album_duplicates: AlbumDuplicates,
+    #[serde(default)]
+    protected_content: ProtectedContent,
+    /// What happens to files the cloud Bot API won't serve.
+    #[serde(default)]
+    oversized_on_cloud_api: OversizedOnCloudApi,
+    #[serde(default)]
+    unicode_normalization: UnicodeNormalization,
+    #[serde(default)]
+    throttle: bool,
+    #[serde(default)]
+    cache_me: bool,
+    #[serde(default)]
+    channel_folders: bool,
+    max_bytes_per_sec: Option<u64>,
+    /// Concurrent Range requests a big file is downloaded with.
+    parallel_chunks: Option<usize>,
+    /// Most bytes of files downloading at the same time, by their reported size.
+    max_inflight_bytes: Option<u64>,
+    /// Files per directory before downloads continue in `<dir>-2`, `<dir>-3`, ...
+    max_files_per_dir: Option<u64>,
+    hash_sharding: Option<HashSharding>,
+    #[serde(default)]
+    bandwidth_scope: BandwidthScope,
+    #[serde(default = "default_max_retries")]
+    fs_max_retries: u32,
+    file_mode: Option<u32>,
+    dir_mode: Option<u32>,
+    /// Remembers the directories created this session instead of calling
+    /// `create_dir_all` for every download.
+    #[serde(default)]
+    cache_created_dirs: bool,
+    #[serde(default = "default_sample_rate")]
+    sample_rate: f64,
+    sample_seed: Option<u64>,
+    /// Files saved before the bot stops downloading until restarted.
+    max_downloads_per_session: Option<u64>,
+    /// Media type (`photo`, `video`, `audio`) to the extension used when the
+    /// file name doesn't have one.
+    #[serde(default)]
+    default_extensions: std::collections::HashMap<String, String>,
+    breaker_failures: Option<u32>,
+    #[serde(default = "default_breaker_window_secs")]
+    breaker_window_secs: u64,
+    #[serde(default = "default_breaker_cooldown_secs")]
+    breaker_cooldown_secs: u64,
+    #[serde(default)]
+    detect_language: bool,
+    database_path: Option<String>,
+    #[serde(default)]
+    save_audio_covers: bool,
+    /// Also saves the sizes of a photo other than the `photo_size` one.
+    #[serde(default)]
+    save_all_photo_sizes: bool,
+    /// strftime pattern of the date tokens of `filename_template`.
+    #[serde(default = "default_date_format")]
+    date_format: String,
+    #[serde(default)]
+    dedupe_album_qualities: bool,
+    /// Media type (`video`, `audio`, `other`) to whether the caption or the
+    /// file name of the media is used for its filename.
+    #[serde(default)]
+    name_source: std::collections::HashMap<String, NameSource>,
+    /// Where the title of a file comes from, the first one with a value wins.
+    /// Replaces `name_source` when set.
+    #[serde(default)]
+    name_sources: Vec<TitleSource>,
+    /// Virus scanner run on every download, a nonzero exit status moves the
+    /// file to `quarantine_dir`.
+    #[serde(default)]
+    scan_command: Vec<String>,
+    /// Stores a perceptual hash of photos in the sidecar and the database.
+    #[serde(default)]
+    compute_phash: bool,
+    /// OCR program run on downloaded photos, its output is the `ocr_text`.
+    #[serde(default)]
+    ocr_command: Vec<String>,
+    /// Characters of the OCR text kept for the `{ocr}` token.
+    #[serde(default = "default_ocr_token_len")]
+    ocr_token_len: usize,
+    /// Rotates photos by their EXIF orientation and strips the tag.
+    #[serde(default)]
+    auto_orient_photos: bool,
+    /// Checks downloaded videos with ffprobe, broken ones count as failed.
+    #[serde(default)]
+    verify_with_ffprobe: bool,
+    #[serde(default = "default_ffprobe_path")]
+    ffprobe_path: String,
+    /// Extension of a saved file (`webp`) to the conversion run on it.
+    #[serde(default)]
+    conversions: std::collections::HashMap<String, Conversion>,
+    #[serde(default)]
+    preserve_sender_paths: bool,
+    /// MIME type prefix (`image/`, `audio/ogg`) to the subdirectory matching
+    /// files are saved in.
+    #[serde(default)]
+    mime_routing: std::collections::HashMap<String, String>,
+    size_tier_routing: Option<SizeTierRouting>,
+    /// How long a `getUpdates` long poll waits for new updates.
+    #[serde(default = "default_poll_timeout_secs")]
+    poll_timeout_secs: u64,
+    /// Most updates fetched per `getUpdates` call, 1 to 100.
+    poll_limit: Option<u8>,
+    /// Skips the updates Telegram queued while the bot wasn't running.
+    #[serde(default)]
+    drop_pending_updates: bool,
+    quarantine_dir: Option<String>,
+    /// Channels watched in addition to `channel_id`.
+    #[serde(default)]
+    channels: Vec<ChannelConfig>,
+    /// Per channel limit of downloads running at the same time, for channels
+    /// without their own `max_concurrent_downloads`.
+    max_concurrent_downloads: Option<usize>,
 }
 
-struct AppState {
-    config: AppConfig,
-    media_group_page_numbers: Mutex<std::collections::HashMap<String, MediaGroupData>>,
+#[derive(Deserialize, Serialize)]
+struct ChannelConfig {
+    id: i64,
+    max_concurrent_downloads: Option<usize>,
+    /// Overrides the top-level `default_extensions` for this channel.
+    #[serde(default)]
+    default_extensions: std::collections::HashMap<String, String>,
 }
 
-#[derive(Debug, Clone)]
-struct MediaGroupData {
-    page_number: u32,
-    title: String,
+/// Converts a saved file with `command`, which writes `{output}` with the new
+/// `extension` from `{input}`.
+#[derive(Deserialize, Serialize)]
+struct Conversion {
+    extension: String,
+    command: Vec<String>,
 }
 
-fn read_config() -> Result<AppConfig> {
-    let config = Config::builder()
-        .add_source(config::File::new(
-            &env::var(CONFIG_PATH_ENV)
-                .with_context(|| format!("{CONFIG_PATH_ENV} environment variable not set"))?,
-            FileFormat::Toml,
-        ))
-        .build()?;
+/// Spreads files over `depth` levels of directories named after the first
+/// hex digits of a hash of their unique id, `width` digits per level.
+#[derive(Deserialize, Serialize)]
+struct HashSharding {
+    #[serde(default = "default_shard_depth")]
+    depth: usize,
+    #[serde(default = "default_shard_width")]
+    width: usize,
+}
 
-    Ok(config
-        .try_deserialize::<AppConfig>()
-        .context("Failed to parse config values")?)
+impl HashSharding {
+    fn shard(&self, unique_id: &str) -> Vec<String> {
+        let hash = hex::encode(Sha256::digest(unique_id.as_bytes()));
+        (0..self.depth)
+            .map(|level| hash[level * self.width..(level + 1) * self.width].to_owned())
+            .collect()
+    }
 }
 
-async fn run_bot(app_config: AppConfig) {
-    env_logger::init();
-    log::info!("Starting media downloader bot...");
+/// Subdirectories by file size, e.g. to put large files on another disk.
+#[derive(Deserialize, Serialize)]
+struct SizeTierRouting {
+    #[serde(default)]
+    tiers: Vec<SizeTier>,
+    /// Used when Telegram doesn't report the size.
+    unknown: Option<String>,
+}
 
-    let client = teloxide::net::default_reqwest_settings()
-        .timeout(Duration::from_secs(600))
-        .build()
-        .expect("Client creation failed");
-    let mut tg = Bot::with_client(app_config.bot_token.expose_secret(), client);
+#[derive(Deserialize, Serialize)]
+struct SizeTier {
+    min_bytes: u64,
+    subdir: String,
+}
 
-    if let Some(url) = env::var_os(TELEGRAM_BOT_API_URL_ENV) {
-        tg = tg.set_api_url(
-            Url::parse(url.to_str().expect("Unicode string expected"))
-                .expect("Bot api must be a url"),
-        );
+impl SizeTierRouting {
+    /// Subdirectory of the tier with the highest `min_bytes` at most `size`.
+    fn subdir(&self, size: u32) -> Option<&str> {
+        if size == 0 {
+            return self.unknown.as_deref();
+        }
+        self.tiers
+            .iter()
+            .filter(|tier| tier.min_bytes <= u64::from(size))
+            .max_by_key(|tier| tier.min_bytes)
+            .map(|tier| tier.subdir.as_str())
     }
+}
 
-    let tg = Arc::new(tg);
+/// `channel_id` as the numeric chat id, or as the `@username` of a public
+/// channel that is looked up once at startup.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(try_from = "RawChannelRef", untagged)]
+enum ChannelRef {
+    Id(i64),
+    Username(String),
+}
 
-    let handler = Update::filter_channel_post().branch(
-        dptree::filter(|msg: Message, app_state: Arc<AppState>| {
-            app_state.config.channel_id == msg.chat.id.0
-        })
-        .endpoint(handle_media_message),
-    );
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawChannelRef {
+    Id(i64),
+    Text(String),
+}
 
-    let app_state = Arc::new(AppState {
-        config: app_config,
-        media_group_page_numbers: Default::default(),
-    });
-    Dispatcher::builder(tg.clone(), handler)
-        .dependencies(dptree::deps![app_state, tg.clone()])
-        .default_handler(|upd| async move {
-            warn!("unhandled update: {:?}", upd);
-        })
-        .error_handler(LoggingErrorHandler::with_custom_text(
-            "an error has occurred in the dispatcher",
-        ))
-        .build()
-        .dispatch()
-        .await;
+impl TryFrom<RawChannelRef> for ChannelRef {
+    type Error = anyhow::Error;
+
+    fn try_from(value: RawChannelRef) -> Result<Self> {
+        match value {
+            RawChannelRef::Id(id) => Ok(Self::Id(id)),
+            // CHANNEL_ID from the environment is always a string
+            RawChannelRef::Text(text) => match text.trim().parse() {
+                Result::Ok(id) => Ok(Self::Id(id)),
+                Err(_) if text.starts_with('@') && text.len() > 1 => Ok(Self::Username(text)),
+                Err(_) => bail!("channel_id \"{text}\" is neither a chat id nor an @username"),
+            },
+        }
+    }
 }
 
-async fn handle_media_message(
-    bot: Arc<Bot>,
-    message: Message,
-    app_state: Arc<AppState>,
-) -> Result<()> {
-    let media_kind = if let MessageKind::Common {
-        0: MessageCommon { media_kind, .. },
-        ..
-    } = &message.kind
-    {
-        media_kind
-    } else {
-        return Ok(());
-    };
+impl ChannelRef {
+    /// Only valid after `resolve_channel_id`.
+    fn id(&self) -> i64 {
+        match self {
+            Self::Id(id) => *id,
+            Self::Username(username) => panic!("channel_id {username} was never resolved"),
+        }
+    }
+}
 
-    let media_group_id = message.media_group_id().map(|s| s.to_owned());
+/// IANA timezone used whenever a date ends up in a path or filename.
+#[derive(Deserialize, Serialize, Default, Clone, Copy)]
+#[serde(try_from = "String", into = "String")]
+struct Timezone(Tz);
 
-    match media_kind {
-        MediaKind::Photo(photo) => {
-            let max_size = photo
-                .photo
-                .iter()
-                .max_by_key(|photo| photo.file.size)
-                .unwrap();
+impl From<Timezone> for String {
+    fn from(timezone: Timezone) -> Self {
+        timezone.0.name().to_owned()
+    }
+}
 
-            download_and_save_file(
-                bot,
-                &max_size.file,
-                photo.caption.as_deref(),
-                "jpg",
-                app_state,
-                media_group_id,
+impl TryFrom<String> for Timezone {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse().map(Timezone).map_err(|_| {
+            anyhow::anyhow!(
+                "Unknown timezone \"{value}\", expected an IANA name like Europe/Berlin"
             )
-            .await
-            .context("Failed download photo")?;
+        })
+    }
+}
+
+/// Keeps secrets out of `--print-config`.
+pub fn serialize_redacted<T, S: Serializer>(_: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str("<redacted>")
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_retry_base_delay_ms() -> u64 {
+    1000
+}
+
+fn default_poll_timeout_secs() -> u64 {
+    10
+}
+
+fn default_retry_jitter() -> f64 {
+    0.25
+}
+
+fn default_breaker_window_secs() -> u64 {
+    60
+}
+
+fn default_breaker_cooldown_secs() -> u64 {
+    300
+}
+
+fn default_date_format() -> String {
+    "%Y-%m-%d".to_owned()
+}
+
+fn default_sample_rate() -> f64 {
+    1.0
+}
+
+fn default_ocr_token_len() -> usize {
+    50
+}
+
+fn default_max_title_len() -> usize {
+    100
+}
+
+fn default_ffprobe_path() -> String {
+    "ffprobe".to_owned()
+}
+
+fn default_unknown_media_dir() -> String {
+    "unknown".to_owned()
+}
+
+fn default_shard_depth() -> usize {
+    2
+}
+
+fn default_shard_width() -> usize {
+    2
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_max_file_name_bytes() -> usize {
+    255
+}
+
+fn default_path_separator_replacement() -> String {
+    "-".to_owned()
+}
+
+impl AppConfig {
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: self.max_retries,
+            base_delay: Duration::from_millis(self.retry_base_delay_ms),
+            jitter: self.retry_jitter,
         }
-        MediaKind::Video(video) => {
-            download_and_save_file(
-                bot,
-                &video.video.file,
-                video
-                    .caption
-                    .as_deref()
-                    .or(video.video.file_name.as_deref()),
-                "mp4",
-                app_state,
-                media_group_id,
-            )
-            .await
-            .context("Failed download video")?;
+    }
+
+    fn hook_retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: self.hook_max_retries,
+            ..self.retry_policy()
         }
-        MediaKind::Audio(audio) => {
-            download_and_save_file(
-                bot,
-                &audio.audio.file,
-                audio
-                    .caption
-                    .as_deref()
-                    .or(audio.audio.file_name.as_deref()),
-                "mp3",
-                app_state,
-                media_group_id,
-            )
-            .await
-            .context("Failed download audio")?;
+    }
+
+    /// Retries of a whole post, on top of the retries of single requests.
+    fn message_retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: self.message_max_retries,
+            ..self.retry_policy()
+        }
+    }
+
+    fn uses_seq(&self) -> bool {
+        [&self.filename_template, &self.audio_filename_template]
+            .into_iter()
+            .flatten()
+            .any(|template| template::tokens(template).any(|token| token == "seq"))
+    }
+
+    fn uses_ocr_token(&self) -> bool {
+        self.filename_template
+            .as_deref()
+            .is_some_and(|template| template::tokens(template).any(|token| token == "ocr"))
+    }
+
+    fn seq_file(&self) -> PathBuf {
+        match &self.seq_file {
+            Some(path) => PathBuf::from(path),
+            None => Path::new(&self.media_directory).join(".seq"),
+        }
+    }
+
+    /// By how many bytes `path` or its file name are longer than allowed.
+    fn path_excess(&self, path: &Path) -> usize {
+        let max_path_len = self
+            .max_path_len
+            .unwrap_or(if cfg!(windows) { 260 } else { 4096 });
+        let name_len = path.file_name().map_or(0, |name| name.len());
+        name_len
+            .saturating_sub(self.max_file_name_bytes)
+            .max(path.as_os_str().len().saturating_sub(max_path_len))
+    }
+
+    /// Makes titles usable as a single path segment.
+    fn replace_path_separators(&self, name: &str) -> String {
+        name.replace(['/', '\\'], &self.path_separator_replacement)
+    }
+
+    /// Every watched channel with its concurrency limit, if it has one.
+    fn channel_limits(&self) -> impl Iterator<Item = (i64, Option<usize>)> + '_ {
+        std::iter::once((self.channel_id.id(), self.max_concurrent_downloads)).chain(
+            self.channels.iter().map(|channel| {
+                (
+                    channel.id,
+                    channel
+                        .max_concurrent_downloads
+                        .or(self.max_concurrent_downloads),
+                )
+            }),
+        )
+    }
+
+    fn watches(&self, chat_id: i64) -> bool {
+        self.channel_id.id() == chat_id || self.channels.iter().any(|channel| channel.id == chat_id)
+    }
+
+    /// The caption or the file name of a media, whichever `name_source` prefers
+    /// and is present.
+    fn media_name<'a>(
+        &self,
+        media_type: &str,
+        caption: Option<&'a str>,
+        file_name: Option<&'a str>,
+    ) -> Option<&'a str> {
+        match self
+            .name_source
+            .get(media_type)
+            .copied()
+            .unwrap_or_default()
+        {
+            NameSource::CaptionFirst => caption.or(file_name),
+            NameSource::FileNameFirst => file_name.or(caption),
+        }
+    }
+
+    /// Splits a file name sent by a client, which may be a relative path like
+    /// `folder/video.mp4`, into the directory to keep (only with
+    /// `preserve_sender_paths`) and the basename used for naming.
+    fn sender_file_name<'a>(
+        &self,
+        file_name: Option<&'a str>,
+    ) -> (Option<&'a str>, Option<&'a str>) {
+        let Some(file_name) = file_name else {
+            return (None, None);
+        };
+        let (dir, name) = match file_name.rsplit_once(['/', '\\']) {
+            Some((dir, name)) => (Some(dir), name),
+            None => (None, file_name),
+        };
+        (
+            dir.filter(|_| self.preserve_sender_paths),
+            Some(name).filter(|name| !name.is_empty()),
+        )
+    }
+
+    /// Subdirectory of the longest `mime_routing` prefix matching `mime_type`.
+    fn mime_subdir(&self, mime_type: &str) -> Option<&str> {
+        self.mime_routing
+            .iter()
+            .filter(|(prefix, _)| mime_type.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, subdir)| subdir.as_str())
+    }
+
+    fn default_extension<'a>(
+        &'a self,
+        chat_id: i64,
+        media_type: &str,
+        builtin: &'a str,
+    ) -> &'a str {
+        self.channels
+            .iter()
+            .find(|channel| channel.id == chat_id)
+            .and_then(|channel| channel.default_extensions.get(media_type))
+            .or_else(|| self.default_extensions.get(media_type))
+            .map_or(builtin, String::as_str)
+    }
+
+    fn fs_retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: self.fs_max_retries,
+            ..self.retry_policy()
         }
-        _ => (),
     }
-    Ok(())
 }
 
-async fn download_and_save_file(
-    bot: Arc<Bot>,
-    file_meta: &FileMeta,
-    file_name: Option<&str>,
-    ext: &str,
-    app_state: Arc<AppState>,
-    media_group_id: Option<String>,
-) -> Result<()> {
-    let media_group = if let Some(media_group_id) = &media_group_id {
-        let mut map = app_state.media_group_page_numbers.lock().unwrap();
-        let page_number = map.entry(media_group_id.clone()).or_insert(MediaGroupData {
-            page_number: 0,
-            title: file_name
-                .map(Path::new)
-                .and_then(|p| p.file_stem().and_then(|s| s.to_str()))
-                .unwrap_or(media_group_id)
-                .to_owned(),
-        });
-        page_number.page_number += 1;
-        Some(page_number.clone())
-    } else {
-        None
-    };
+#[derive(Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum NameSource {
+    #[default]
+    CaptionFirst,
+    FileNameFirst,
+}
 
-    let file = bot.get_file(file_meta.id.clone()).send().await?;
-    let (filename, extension) = get_filename_and_extension(file_meta, file_name, ext, media_group);
-    let mut file_path = PathBuf::from(app_state.config.media_directory.clone());
-    file_path.push(format!("{}.{}", filename, extension));
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum TitleSource {
+    /// `<performer> - <track title>` of tagged audio.
+    PerformerTitle,
+    Caption,
+    FileName,
+    /// No title at all, the name is only the unique id.
+    UniqueId,
+}
 
-    tokio::fs::create_dir_all(&file_path.parent().expect("Parent missing"))
-        .await
-        .context("Create dir all failed")?;
-    let mut dst = tokio::fs::File::create(&file_path)
-        .await
-        .context(format!("Failed to create file: {}", file_path.display()))?;
-    if Path::new(&file.path).is_absolute() {
-        let mut absolute_file = tokio::fs::File::open(file.path).await?;
-        let mut buf = Vec::new();
-        absolute_file.read_to_end(&mut buf).await?;
-        dst.write_all(&buf).await?;
-    } else if let Err(e) = bot.download_file(&file.path, &mut dst).await {
-        log::error!("Failed to download file: {}", e);
-    } else {
-        log::info!("Downloaded and saved file: {}", file_path.display());
+/// What happens to posts of channels with "Restrict saving content" enabled.
+#[derive(Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ProtectedContent {
+    #[default]
+    Skip,
+    Download,
+}
+
+/// What happens to files bigger than [`CLOUD_API_MAX_FILE_SIZE`] without a
+/// local Bot API server.
+#[derive(Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum OversizedOnCloudApi {
+    #[default]
+    Skip,
+    Attempt,
+}
+
+/// Biggest file the cloud Bot API lets bots download with `get_file`.
+const CLOUD_API_MAX_FILE_SIZE: u32 = 20 * 1024 * 1024;
+
+/// Unicode normalization form of titles in paths. macOS filesystems use NFD,
+/// most others keep whatever they're given, so the same caption can end up as
+/// two different-looking names.
+#[derive(Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum UnicodeNormalization {
+    #[default]
+    Nfc,
+    Nfd,
+    None,
+}
+
+impl UnicodeNormalization {
+    fn apply<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        match self {
+            Self::Nfc => text.nfc().collect::<String>().into(),
+            Self::Nfd => text.nfd().collect::<String>().into(),
+            Self::None => text.into(),
+        }
     }
-    Ok(())
 }
 
-fn get_filename_and_extension(
-    file_meta: &FileMeta,
-    file_name: Option<&str>,
-    default_ext: &str,
-    media_group_data: Option<MediaGroupData>,
-) -> (String, String) {
-    let ext = file_name
-        .map(Path::new)
-        .and_then(|p| p.extension().and_then(|e| e.to_str()))
-        .unwrap_or(default_ext);
+/// What happens once a media group grows past `max_media_group_items`.
+#[derive(Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum OversizedMediaGroups {
+    /// Keep numbering the items of the album.
+    #[default]
+    Continue,
+    /// Start a new album, with its own folder and numbering, every
+    /// `max_media_group_items` items.
+    Split,
+}
 
-    let prefix = if let Some(ref x) = media_group_data {
-        format!("title:[{}]", x.title)
-    } else {
-        let stem = file_name
-            .unwrap_or("")
-            .to_owned();
-        format!("[{stem}]")
-    };
-    let page_part =
-        media_group_data.map_or_else(String::new, |data| format!("{{page:{}}}", data.page_number));
+/// What happens to a file that shows up a second time in the same album.
+#[derive(Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum AlbumDuplicates {
+    /// Skip it, its page number stays unused.
+    #[default]
+    SkipAndGap,
+    /// Skip it without using up a page number.
+    SkipAndRenumber,
+    /// Save it again with a `_dupN` suffix.
+    KeepWithSuffix,
+}
 
-    let unique_id = &file_meta.unique_id;
-    let filename = format!("{prefix}_{unique_id}{page_part}");
+/// Which of the sizes Telegram offers for a photo gets downloaded:
+/// `"smallest"`, `"largest"` or `"<=Npx"` (the largest one whose longer side
+/// is at most N pixels, the smallest one when none is).
+#[derive(Deserialize, Serialize, Default, Clone, Copy)]
+#[serde(try_from = "String", into = "String")]
+enum PhotoSizeSelection {
+    Smallest,
+    #[default]
+    Largest,
+    AtMostPixels(u32),
+}
+
+impl From<PhotoSizeSelection> for String {
+    fn from(selection: PhotoSizeSelection) -> Self {
+        match selection {
+            PhotoSizeSelection::Smallest => "smallest".to_owned(),
+            PhotoSizeSelection::Largest => "largest".to_owned(),
+            PhotoSizeSelection::AtMostPixels(pixels) => format!("<={pixels}px"),
+        }
+    }
+}
 
-    // remove forward slashes
-    let filename = filename.replace("/", "\\");
+impl TryFrom<String> for PhotoSizeSelection {
+    type Error = anyhow::Error;
 
-    (filename, ext.to_owned())
+    fn try_from(value: String) -> Result<Self> {
+        Ok(match value.as_str() {
+            "smallest" => PhotoSizeSelection::Smallest,
+            "largest" => PhotoSizeSelection::Largest,
+            _ => {
+                let pixels = value
+                    .strip_prefix("<=")
+                    .and_then(|value| value.strip_suffix("px"))
+                    .with_context(|| {
+                        format!("photo_size must be smallest, largest or <=Npx, got {value}")
+                    })?;
+                PhotoSizeSelection::AtMostPixels(
+                    pixels
+                        .trim()
+                        .parse()
+                        .with_context(|| format!("Invalid pixel count in photo_size: {value}"))?,
+                )
+            }
+        })
+    }
+}
+
+impl PhotoSizeSelection {
+    fn select(self, sizes: &[PhotoSize]) -> Option<&PhotoSize> {
+        let longer_side = |size: &&PhotoSize| size.width.max(size.height);
+        match self {
+            PhotoSizeSelection::Smallest => sizes.iter().min_by_key(|size| size.file.size),
+            PhotoSizeSelection::Largest => sizes.iter().max_by_key(|size| size.file.size),
+            PhotoSizeSelection::AtMostPixels(pixels) => sizes
+                .iter()
+                .filter(|size| longer_side(size) <= pixels)
+                .max_by_key(longer_side)
+                .or_else(|| sizes.iter().min_by_key(longer_side)),
+        }
+    }
+}
+
+fn default_unhandled_update_log_level() -> LevelFilter {
+    LevelFilter::Warn
+}
+
+struct AppState {
+    config: AppConfig,
+    /// Albums that got a part within `album_grace_secs`, all albums without it.
+    media_group_page_numbers: Mutex<std::collections::HashMap<String, MediaGroupData>>,
+    /// Albums past `album_grace_secs`, so parts that arrive later (a channel
+    /// editing its album) still continue the numbering and land in the same
+    /// folder. Both are lost on restart.
+    evicted_media_groups: Mutex<BoundedMap<MediaGroupData>>,
+    stats: Stats,
+    encryption_key: Option<Key>,
+    index: DownloadIndex,
+    /// Albums with a caption on an earlier item, for `require_caption`.
+    captioned_media_groups: Mutex<BoundedMap<()>>,
+    /// Compiled `caption_include_patterns`, `None` when there are none.
+    caption_include: Option<RegexSet>,
+    /// Albums whose caption matched `caption_include_patterns`.
+    included_media_groups: Mutex<BoundedMap<()>>,
+    /// Album folders handed out so far, as full paths.
+    album_folder_names: Mutex<HashSet<PathBuf>>,
+    storage: Storage,
+    /// Shared by all downloads when `bandwidth_scope` is global.
+    bandwidth: Option<Arc<TokenBucket>>,
+    /// Decides which messages are processed when `sample_rate` is below 1.
+    sampler: Mutex<StdRng>,
+    breaker: Option<CircuitBreaker>,
+    /// Set when any `reactions` are configured.
+    reactor: Option<Reactor>,
+    chunked: Option<ChunkedDownloader>,
+    database: Option<Arc<Database>>,
+    inflight: Option<InflightLimit>,
+    /// Download slots of the channels with a concurrency limit.
+    download_slots: std::collections::HashMap<i64, Arc<Semaphore>>,
+    album_qualities: AlbumQualities,
+    /// When the last update of any kind arrived, `None` before the first one.
+    last_update_at: Mutex<Option<Instant>>,
+    /// Serializes appends to the `captions.txt` files.
+    caption_log_lock: tokio::sync::Mutex<()>,
+    csv_export_lock: tokio::sync::Mutex<()>,
+    album_manifest_lock: tokio::sync::Mutex<()>,
+    deletions: Deletions,
+    /// Set by `/pause`, posts arriving meanwhile are skipped.
+    paused: AtomicBool,
+    /// Only opened when `filename_template` uses `{seq}`.
+    seq: Option<SeqCounter>,
+    /// Directories with a gallery regeneration scheduled.
+    gallery_pending: Mutex<HashSet<PathBuf>>,
+    dir_limit: Option<DirLimit>,
+    /// Files saved since startup, unlike `stats` never reset.
+    session_downloads: AtomicU64,
+    /// Set once `max_downloads_per_session` was reached and logged.
+    session_cap_reached: AtomicBool,
+    /// Directories created so far, with `cache_created_dirs`.
+    created_dirs: Mutex<HashSet<PathBuf>>,
+    /// Random UUID of this run, to tell apart the downloads of different runs.
+    run_id: String,
+    daily_manifest: DailyManifest,
+}
+
+struct Stats {
+    started_at: Instant,
+    downloads: AtomicU64,
+    errors: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            started_at: Instant::now(),
+            downloads: Default::default(),
+            errors: Default::default(),
+            bytes: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct MediaGroupData {
+    page_number: u32,
+    title: String,
+    /// Directory the album is saved into when `album_folders` is enabled.
+    folder: Option<String>,
+    /// Unique ids of the album items seen so far.
+    unique_ids: Vec<String>,
+    last_part_at: Instant,
+    /// Saved parts, for the `album_manifest`.
+    files: Vec<sidecar::AlbumFile>,
+    /// Pages handed out so far, by message.
+    parts: Vec<AlbumPart>,
+    /// When the next item may call get_file, with `album_get_file_spacing_ms`.
+    next_get_file_at: Instant,
+    /// Captions of the parts by message id, merged for the `album_manifest`.
+    captions: BTreeMap<i32, String>,
+    /// Whether the album got its reaction already.
+    reacted: bool,
+}
+
+impl MediaGroupData {
+    /// The distinct non-empty captions of the parts in message order.
+    fn merged_caption(&self) -> Option<String> {
+        let mut captions: Vec<&str> = Vec::new();
+        for caption in self.captions.values().map(|caption| caption.trim()) {
+            if !caption.is_empty() && !captions.contains(&caption) {
+                captions.push(caption);
+            }
+        }
+        (!captions.is_empty()).then(|| captions.join("\n\n"))
+    }
+
+    fn manifest(&self, media_group_id: &str) -> sidecar::AlbumManifest {
+        sidecar::AlbumManifest {
+            media_group_id: media_group_id.to_owned(),
+            title: self.title.clone(),
+            caption: self.merged_caption(),
+            files: self.files.clone(),
+        }
+    }
+
+    /// Time slot for the get_file call of an item about to be downloaded.
+    fn reserve_get_file(&mut self, spacing: Option<Duration>) -> Option<Instant> {
+        let spacing = spacing?;
+        let at = self.next_get_file_at.max(Instant::now());
+        self.next_get_file_at = at + spacing;
+        Some(at)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct AlbumPart {
+    message_id: i32,
+    page_number: u32,
+    duplicate_copy: Option<usize>,
+}
+
+/// Prefix of the environment variables that set any option over the config
+/// file, e.g. `TG_DOWNLOAD_BOT_MAX_TITLE_LEN`; `__` separates nested keys.
+const ENV_PREFIX: &str = "TG_DOWNLOAD_BOT";
+
+/// The required options, which can also be set through these shorter
+/// environment variables.
+const ENV_CONFIG_KEYS: &[(&str, &str)] = &[
+    ("BOT_TOKEN", "bot_token"),
+    ("CHANNEL_ID", "channel_id"),
+    ("MEDIA_DIRECTORY", "media_directory"),
+];
+
+fn read_config() -> Result<AppConfig> {
+    let mut builder = Config::builder();
+    match env::var(CONFIG_PATH_ENV).ok() {
+        Some(paths) => {
+            // later files override earlier ones, only the base config has to exist
+            for (i, path) in config_paths(&paths).enumerate() {
+                builder =
+                    builder.add_source(config::File::new(path, FileFormat::Toml).required(i == 0));
+            }
+        }
+        None => {
+            // without a config file everything required has to come from the environment
+            let missing: Vec<_> = ENV_CONFIG_KEYS
+                .iter()
+                .filter(|&&(var, key)| {
+                    env::var_os(var).is_none() && env::var_os(prefixed_env_var(key)).is_none()
+                })
+                .map(|&(var, _)| var)
+                .collect();
+            if !missing.is_empty() {
+                bail!(
+                    "{CONFIG_PATH_ENV} environment variable not set, and {} missing to run \
+                     without a config file",
+                    missing.join(", ")
+                );
+            }
+        }
+    }
+    builder = builder.add_source(config_environment());
+    for &(var, key) in ENV_CONFIG_KEYS {
+        if let Some(value) = env::var_os(var) {
+            let value = value
+                .into_string()
+                .map_err(|_| anyhow::anyhow!("{var} is not valid unicode"))?;
+            builder = builder.set_override(key, value)?;
+        }
+    }
+    let config = builder.build()?;
+
+    let mut app_config = config
+        .try_deserialize::<AppConfig>()
+        .context("Failed to parse config values")?;
+    expand_config_env_vars(&mut app_config)?;
+    resolve_media_directory(&mut app_config)?;
+    validate_config(&app_config)?;
+    Ok(app_config)
+}
+
+/// Options from the `ENV_PREFIX` environment variables.
+fn config_environment() -> config::Environment {
+    config::Environment::with_prefix(ENV_PREFIX)
+        .prefix_separator("_")
+        .separator("__")
+        .try_parsing(true)
+}
+
+/// The `ENV_PREFIX` environment variable of the top-level option `key`.
+fn prefixed_env_var(key: &str) -> String {
+    format!("{ENV_PREFIX}_{}", key.to_uppercase())
+}
+
+/// The files listed in `CONFIG_PATH`. Only `,` separates them, `:` is part
+/// of Windows paths.
+fn config_paths(paths: &str) -> impl Iterator<Item = &str> {
+    paths.split(',').filter(|path| !path.is_empty())
+}
+
+/// Makes `media_directory` absolute, so where files land doesn't depend on
+/// the directory the service was started from.
+fn resolve_media_directory(app_config: &mut AppConfig) -> Result<()> {
+    let media_directory = Path::new(&app_config.media_directory);
+    let resolved = match &app_config.media_directory_base {
+        _ if media_directory.is_absolute() => return Ok(()),
+        Some(base) if !Path::new(base).is_absolute() => {
+            bail!("media_directory_base must be an absolute path, got {base}")
+        }
+        Some(base) => Path::new(base).join(media_directory),
+        None => std::path::absolute(media_directory).with_context(|| {
+            format!(
+                "Failed to resolve media_directory {} against the working directory",
+                app_config.media_directory
+            )
+        })?,
+    };
+    let resolved = resolved
+        .into_os_string()
+        .into_string()
+        .map_err(|_| anyhow::anyhow!("Resolved media_directory is not valid unicode"))?;
+    log::info!(
+        "Resolved relative media_directory {} to {resolved}",
+        app_config.media_directory
+    );
+    app_config.media_directory = resolved;
+    Ok(())
+}
+
+fn expand_config_env_vars(app_config: &mut AppConfig) -> Result<()> {
+    app_config.media_directory =
+        expand_env_vars(&app_config.media_directory).context("Failed to expand media_directory")?;
+    if let Some(base) = &mut app_config.media_directory_base {
+        *base = expand_env_vars(base).context("Failed to expand media_directory_base")?;
+    }
+    if let Some(subdir) = &mut app_config.spoiler_subdir {
+        *subdir = expand_env_vars(subdir).context("Failed to expand spoiler_subdir")?;
+    }
+    if let Some(key_file) = &mut app_config.encryption_key_file {
+        *key_file = expand_env_vars(key_file).context("Failed to expand encryption_key_file")?;
+    }
+    if let Some(seq_file) = &mut app_config.seq_file {
+        *seq_file = expand_env_vars(seq_file).context("Failed to expand seq_file")?;
+    }
+    if let Some(dead_letter_dir) = &mut app_config.dead_letter_dir {
+        *dead_letter_dir =
+            expand_env_vars(dead_letter_dir).context("Failed to expand dead_letter_dir")?;
+    }
+    if let Some(quarantine_dir) = &mut app_config.quarantine_dir {
+        *quarantine_dir =
+            expand_env_vars(quarantine_dir).context("Failed to expand quarantine_dir")?;
+    }
+    if let Some(ca_cert_path) = &mut app_config.ca_cert_path {
+        *ca_cert_path = expand_env_vars(ca_cert_path).context("Failed to expand ca_cert_path")?;
+    }
+    Ok(())
+}
+
+/// Replaces `${NAME}` with the value of the environment variable `NAME` and
+/// `$$` with a literal `$`. Unset variables are an error.
+fn expand_env_vars(value: &str) -> Result<String> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after_dollar = &rest[start + 1..];
+        if let Some(tail) = after_dollar.strip_prefix('$') {
+            expanded.push('$');
+            rest = tail;
+        } else if let Some(braced) = after_dollar.strip_prefix('{') {
+            let (name, tail) = braced
+                .split_once('}')
+                .with_context(|| format!("Unterminated ${{ in \"{value}\""))?;
+            let var = env::var(name)
+                .with_context(|| format!("Environment variable {name} is not set"))?;
+            expanded.push_str(&var);
+            rest = tail;
+        } else {
+            expanded.push('$');
+            rest = after_dollar;
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+fn validate_config(app_config: &AppConfig) -> Result<()> {
+    reactions::validate(&app_config.reactions)?;
+    if app_config.parallel_chunks.is_some_and(|chunks| chunks < 2) {
+        bail!("parallel_chunks must be at least 2");
+    }
+    if app_config.parallel_chunks.is_some() && app_config.max_bytes_per_sec.is_some() {
+        // the chunks bypass the rate limit, the limit would be silently ignored
+        bail!("parallel_chunks can't be combined with max_bytes_per_sec");
+    }
+    if let Some(template) = &app_config.filename_template {
+        template::validate_filename_template(
+            template,
+            app_config.allow_nonunique_template,
+            "filename_template",
+        )?;
+    }
+    if let Some(template) = &app_config.audio_filename_template {
+        template::validate_filename_template(
+            template,
+            app_config.allow_nonunique_template,
+            "audio_filename_template",
+        )?;
+    }
+    if chrono::format::StrftimeItems::new(&app_config.date_format)
+        .any(|item| item == chrono::format::Item::Error)
+    {
+        bail!("Invalid date_format \"{}\"", app_config.date_format);
+    }
+    if app_config
+        .poll_limit
+        .is_some_and(|limit| !(1..=100).contains(&limit))
+    {
+        bail!("poll_limit must be between 1 and 100");
+    }
+    if app_config
+        .watchdog_secs
+        .is_some_and(|secs| secs <= app_config.poll_timeout_secs)
+    {
+        bail!("watchdog_secs must be longer than poll_timeout_secs");
+    }
+    if app_config.poll_timeout_secs >= HTTP_TIMEOUT.as_secs() {
+        bail!(
+            "poll_timeout_secs must be below the HTTP timeout of {} seconds",
+            HTTP_TIMEOUT.as_secs()
+        );
+    }
+    for (prefix, subdir) in &app_config.mime_routing {
+        if !Path::new(subdir)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            bail!("mime_routing subdirectory {subdir:?} of {prefix:?} must be a relative path without ..");
+        }
+    }
+    if let Some(routing) = &app_config.size_tier_routing {
+        let subdirs = routing.tiers.iter().map(|tier| &tier.subdir);
+        for subdir in subdirs.chain(&routing.unknown) {
+            if !Path::new(subdir)
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+            {
+                bail!(
+                    "size_tier_routing subdirectory {subdir:?} must be a relative path without .."
+                );
+            }
+        }
+    }
+    if !Path::new(&app_config.unknown_media_dir)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        bail!("unknown_media_dir must be a relative path without ..");
+    }
+    if app_config.path_separator_replacement.contains(['/', '\\']) {
+        bail!("path_separator_replacement can't contain a path separator");
+    }
+    if !(0.0..=1.0).contains(&app_config.retry_jitter) {
+        bail!(
+            "retry_jitter must be between 0.0 and 1.0, got {}",
+            app_config.retry_jitter
+        );
+    }
+    if !(0.0..=1.0).contains(&app_config.sample_rate) {
+        bail!(
+            "sample_rate must be between 0.0 and 1.0, got {}",
+            app_config.sample_rate
+        );
+    }
+    let channel_extensions = app_config
+        .channels
+        .iter()
+        .flat_map(|channel| channel.default_extensions.keys());
+    for media_type in app_config
+        .default_extensions
+        .keys()
+        .chain(channel_extensions)
+    {
+        if !["photo", "video", "audio"].contains(&media_type.as_str()) {
+            bail!("Unknown media type {media_type} in default_extensions, expected photo, video or audio");
+        }
+    }
+    for media_type in app_config.name_source.keys() {
+        if !["video", "audio"].contains(&media_type.as_str()) {
+            bail!("Unknown media type {media_type} in name_source, expected video or audio");
+        }
+    }
+    for arg in &app_config.post_download_command {
+        template::validate_tokens(arg, template::HOOK_TOKENS, "post_download_command")?;
+    }
+    if app_config.retry_dead_letters_on_start
+        && (app_config.dead_letter_dir.is_none() || app_config.admin_ids.is_empty())
+    {
+        bail!("retry_dead_letters_on_start requires dead_letter_dir and admin_ids");
+    }
+    if app_config.uses_seq()
+        && app_config.storage != StorageKind::Local
+        && app_config.seq_file.is_none()
+    {
+        bail!("{{seq}} with WebDAV storage requires a local seq_file");
+    }
+    if app_config.compute_phash && app_config.storage != StorageKind::Local {
+        bail!("compute_phash only works with local storage");
+    }
+    if app_config.auto_orient_photos && app_config.storage != StorageKind::Local {
+        bail!("auto_orient_photos only works with local storage");
+    }
+    if app_config.verify_with_ffprobe && app_config.storage != StorageKind::Local {
+        bail!("verify_with_ffprobe only works with local storage");
+    }
+    if let Some(max_files_per_dir) = app_config.max_files_per_dir {
+        if max_files_per_dir == 0 {
+            bail!("max_files_per_dir must be at least 1");
+        }
+        if app_config.storage != StorageKind::Local {
+            bail!("max_files_per_dir only works with local storage");
+        }
+    }
+    if let Some(sharding) = &app_config.hash_sharding {
+        // a SHA-256 has 64 hex digits
+        if sharding.depth == 0
+            || sharding.width == 0
+            || sharding
+                .depth
+                .checked_mul(sharding.width)
+                .is_none_or(|len| len > 64)
+        {
+            bail!("hash_sharding needs a depth and width of at least 1, with depth * width at most 64");
+        }
+    }
+    if app_config.max_media_group_items == Some(0) {
+        bail!("max_media_group_items must be at least 1");
+    }
+    if app_config.album_manifest && !app_config.album_folders {
+        bail!("album_manifest requires album_folders");
+    }
+    if app_config.generate_gallery && app_config.storage != StorageKind::Local {
+        bail!("generate_gallery only works with local storage");
+    }
+    if app_config.caption_log && app_config.storage != StorageKind::Local {
+        bail!("caption_log only works with local storage");
+    }
+    if app_config.strict_pipeline && app_config.storage != StorageKind::Local {
+        bail!("strict_pipeline only works with local storage");
+    }
+    if app_config.storage_layout == StorageLayout::ContentAddressed
+        && app_config.storage != StorageKind::Local
+    {
+        bail!("storage_layout = \"content_addressed\" only works with local storage");
+    }
+    if app_config.daily_manifest && app_config.storage != StorageKind::Local {
+        bail!("daily_manifest only works with local storage");
+    }
+    if !app_config.scan_command.is_empty() {
+        if app_config.quarantine_dir.is_none() {
+            bail!("scan_command requires quarantine_dir");
+        }
+        if app_config.storage != StorageKind::Local {
+            bail!("scan_command only works with local storage");
+        }
+    }
+    for arg in &app_config.scan_command {
+        template::validate_tokens(arg, template::HOOK_TOKENS, "scan_command")?;
+    }
+    if !app_config.ocr_command.is_empty() && app_config.storage != StorageKind::Local {
+        bail!("ocr_command only works with local storage");
+    }
+    for arg in &app_config.ocr_command {
+        template::validate_tokens(arg, template::OCR_TOKENS, "ocr_command")?;
+    }
+    if !app_config.conversions.is_empty() && app_config.storage != StorageKind::Local {
+        bail!("conversions only work with local storage");
+    }
+    for (from, conversion) in &app_config.conversions {
+        if conversion.command.is_empty() {
+            bail!("Conversion of .{from} has no command");
+        }
+        if conversion.extension.is_empty()
+            || conversion.extension.contains(['.', '/', '\\'])
+            || conversion.extension == *from
+        {
+            bail!(
+                "Conversion of .{from} has an invalid extension \"{}\"",
+                conversion.extension
+            );
+        }
+        for arg in &conversion.command {
+            template::validate_tokens(arg, template::CONVERSION_TOKENS, "conversions")?;
+        }
+    }
+    Ok(())
+}
+
+async fn run_bot(mut app_config: AppConfig, encryption_key: Option<Key>) -> Result<()> {
+    log::info!("Starting media downloader bot {VERSION}...");
+    if app_config.storage_layout == StorageLayout::ContentAddressed && encryption_key.is_some() {
+        // every encryption of a file is different, nothing would be deduplicated
+        bail!("storage_layout = \"content_addressed\" doesn't work with encryption");
+    }
+
+    let client = build_client(&app_config).context("Client creation failed")?;
+    let storage = Storage::new(
+        app_config.storage,
+        app_config.webdav.as_ref(),
+        &app_config.media_directory,
+        client.clone(),
+        LocalStorage {
+            retry: app_config.fs_retry_policy(),
+            file_mode: app_config.file_mode,
+            dir_mode: app_config.dir_mode,
+        },
+    )?;
+    // held until the bot stops, the OS releases it even after a crash
+    let _directory_lock = lock_media_directory(&app_config)?;
+    let tg = build_bot(&app_config, client).context("Bot creation failed")?;
+    resolve_channel_id(&tg, &mut app_config).await?;
+    check_watched_chats(&tg, &app_config).await;
+    if app_config.delete_after_download {
+        deletion::check_permissions(&tg, app_config.channel_limits().map(|(chat_id, _)| chat_id))
+            .await?;
+    }
+    let (throttle, cache_me) = (app_config.throttle, app_config.cache_me);
+
+    let bandwidth = app_config
+        .max_bytes_per_sec
+        .filter(|_| app_config.bandwidth_scope == BandwidthScope::Global)
+        .map(|rate| Arc::new(TokenBucket::new(rate)));
+
+    let sample_seed = app_config.sample_seed;
+    let download_slots = app_config
+        .channel_limits()
+        .filter_map(|(chat_id, limit)| Some((chat_id, Arc::new(Semaphore::new(limit?.max(1))))))
+        .collect();
+    let database = match &app_config.database_path {
+        Some(path) => Some(Arc::new(
+            Database::open(Path::new(path)).context("Database setup failed")?,
+        )),
+        None => None,
+    };
+    let inflight = app_config.max_inflight_bytes.map(InflightLimit::new);
+    let dir_limit = app_config.max_files_per_dir.map(DirLimit::new);
+    let run_id = uuid::Uuid::new_v4().to_string();
+    log::info!("Run id {run_id}");
+    let caption_include = if app_config.caption_include_patterns.is_empty() {
+        None
+    } else {
+        Some(
+            RegexSet::new(&app_config.caption_include_patterns)
+                .context("Invalid regex in caption_include_patterns")?,
+        )
+    };
+    let seq = if app_config.uses_seq() {
+        Some(SeqCounter::open(app_config.seq_file()).await?)
+    } else {
+        None
+    };
+    let reactor = if app_config.reactions.is_empty() {
+        None
+    } else {
+        Some(Reactor::new(&tg)?)
+    };
+    let chunked = match app_config.parallel_chunks {
+        Some(chunks) => Some(ChunkedDownloader::new(&tg, chunks)?),
+        None => None,
+    };
+    let breaker = app_config.breaker_failures.map(|threshold| {
+        CircuitBreaker::new(
+            threshold,
+            Duration::from_secs(app_config.breaker_window_secs),
+            Duration::from_secs(app_config.breaker_cooldown_secs),
+        )
+    });
+    let app_state = Arc::new(AppState {
+        config: app_config,
+        media_group_page_numbers: Default::default(),
+        evicted_media_groups: Mutex::new(BoundedMap::new(MAX_EVICTED_MEDIA_GROUPS)),
+        stats: Default::default(),
+        encryption_key,
+        index: Default::default(),
+        captioned_media_groups: Mutex::new(BoundedMap::new(MAX_TRACKED_MEDIA_GROUPS)),
+        caption_include,
+        included_media_groups: Mutex::new(BoundedMap::new(MAX_TRACKED_MEDIA_GROUPS)),
+        album_folder_names: Default::default(),
+        storage,
+        bandwidth,
+        sampler: Mutex::new(match sample_seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_os_rng(),
+        }),
+        breaker,
+        reactor,
+        chunked,
+        inflight,
+        database,
+        download_slots,
+        album_qualities: Default::default(),
+        last_update_at: Default::default(),
+        caption_log_lock: Default::default(),
+        csv_export_lock: Default::default(),
+        album_manifest_lock: Default::default(),
+        deletions: Default::default(),
+        paused: Default::default(),
+        gallery_pending: Default::default(),
+        seq,
+        dir_limit,
+        session_downloads: Default::default(),
+        session_cap_reached: Default::default(),
+        created_dirs: Default::default(),
+        run_id,
+        daily_manifest: Default::default(),
+    });
+
+    let media_directory = PathBuf::from(&app_state.config.media_directory);
+    let indexed = index::rebuild(&app_state.index, media_directory)
+        .await
+        .unwrap_or_else(|e| {
+            log::error!("Failed to index media directory: {e:#}");
+            0
+        });
+    log::info!("Indexed {indexed} previously downloaded files");
+
+    if let Some(secs) = app_state.config.heartbeat_secs.filter(|&secs| secs > 0) {
+        tokio::spawn(heartbeat(app_state.clone(), Duration::from_secs(secs)));
+    }
+    if let Some(secs) = app_state.config.watchdog_secs {
+        tokio::spawn(watchdog(app_state.clone(), Duration::from_secs(secs)));
+    }
+    #[cfg(unix)]
+    tokio::spawn(dump_state_on_signal(app_state.clone()));
+    if app_state.config.daily_manifest {
+        tokio::spawn(daily_manifest::run(app_state.clone()));
+    }
+    if let Some(secs) = app_state.config.access_check_secs.filter(|&secs| secs > 0) {
+        tokio::spawn(recheck_watched_chats(
+            tg.clone(),
+            app_state.clone(),
+            Duration::from_secs(secs),
+        ));
+    }
+
+    // every adaptor stack is its own bot type, so the handlers are instantiated
+    // once per combination
+    match (throttle, cache_me) {
+        (false, false) => dispatch(tg, app_state.clone()).await,
+        (true, false) => dispatch(tg.throttle(Limits::default()), app_state.clone()).await,
+        (false, true) => dispatch(tg.cache_me(), app_state.clone()).await,
+        (true, true) => {
+            dispatch(tg.cache_me().throttle(Limits::default()), app_state.clone()).await
+        }
+    }
+
+    if app_state.config.daily_manifest {
+        let media_directory = Path::new(&app_state.config.media_directory);
+        if let Err(e) = app_state.daily_manifest.flush(media_directory, None).await {
+            log::error!("Failed to write the daily manifest: {e:#}");
+        }
+    }
+
+    let stats = &app_state.stats;
+    log::info!(
+        "Shutting down after {:?}: {} downloads, {} bytes, {} errors",
+        stats.started_at.elapsed(),
+        stats.downloads.load(Ordering::Relaxed),
+        stats.bytes.load(Ordering::Relaxed),
+        stats.errors.load(Ordering::Relaxed),
+    );
+
+    Ok(())
+}
+
+async fn dispatch<B: TelegramBot>(tg: B, app_state: Arc<AppState>) {
+    let tg = Arc::new(tg);
+    let handler = dptree::entry()
+        .inspect(|app_state: Arc<AppState>| {
+            *app_state.last_update_at.lock().unwrap() = Some(Instant::now());
+        })
+        .branch(
+            Update::filter_channel_post()
+                .branch(
+                    // pins, title changes and the like, expected in any channel
+                    dptree::filter(|msg: Message| !matches!(msg.kind, MessageKind::Common(_)))
+                        .endpoint(|msg: Message| async move {
+                            log::trace!("Ignoring service message {} in {}", msg.id, msg.chat.id);
+                            anyhow::Ok(())
+                        }),
+                )
+                .branch(
+                    dptree::filter(|msg: Message, app_state: Arc<AppState>| {
+                        app_state.config.watches(msg.chat.id.0)
+                    })
+                    .endpoint(handle_media_message::<B>),
+                ),
+        )
+        .branch(
+            Update::filter_message()
+                .filter(|msg: Message, app_state: Arc<AppState>| {
+                    commands::is_admin(&msg, &app_state)
+                })
+                .filter_command::<AdminCommand>()
+                .endpoint(commands::handle_admin_command::<B>),
+        );
+
+    if app_state.config.retry_dead_letters_on_start {
+        tokio::spawn(commands::retry_dead_letters(tg.clone(), app_state.clone()));
+    }
+
+    let unhandled_update_log_level = app_state.config.unhandled_update_log_level;
+    let concurrent = !app_state.download_slots.is_empty();
+    let mut polling = Polling::builder(tg.clone())
+        .timeout(Duration::from_secs(app_state.config.poll_timeout_secs));
+    if let Some(limit) = app_state.config.poll_limit {
+        polling = polling.limit(limit);
+    }
+    if app_state.config.drop_pending_updates {
+        log::warn!("Dropping the updates that arrived while the bot was offline");
+        polling = polling.drop_pending_updates();
+    }
+    let listener = polling.delete_webhook().await.build();
+    let listener_error_handler =
+        LoggingErrorHandler::with_custom_text("an error from the update listener");
+    let builder = Dispatcher::builder(tg.clone(), handler)
+        .dependencies(dptree::deps![app_state, tg])
+        .default_handler(move |upd| async move {
+            log_unhandled_update(&upd, unhandled_update_log_level);
+        })
+        .error_handler(LoggingErrorHandler::with_custom_text(
+            "an error has occurred in the dispatcher",
+        ))
+        .enable_ctrlc_handler();
+    if concurrent {
+        // updates of one chat are processed one by one by default, only keep
+        // the parts of an album together and let the download slots do the
+        // limiting
+        builder
+            .distribution_function(distribution_key)
+            .build()
+            .dispatch_with_listener(listener, listener_error_handler)
+            .await;
+    } else {
+        builder
+            .build()
+            .dispatch_with_listener(listener, listener_error_handler)
+            .await;
+    }
+}
+
+/// Updates with the same key are handled one by one when downloads are
+/// concurrent. The parts of an album go in post order, so the first one names
+/// the album and the pages follow the posts; other posts go by chat.
+fn distribution_key(update: &Update) -> Option<(ChatId, Option<String>)> {
+    let chat_id = update.chat()?.id;
+    let media_group_id = match &update.kind {
+        UpdateKind::Message(message) | UpdateKind::ChannelPost(message) => {
+            message.media_group_id().map(str::to_owned)
+        }
+        _ => None,
+    };
+    Some((chat_id, media_group_id))
+}
+
+fn log_unhandled_update(update: &Update, level: LevelFilter) {
+    // text posts and edits are routine in any channel, don't let them drown the log
+    let ignorable = match &update.kind {
+        UpdateKind::EditedChannelPost(_) => true,
+        UpdateKind::ChannelPost(msg) => msg.text().is_some(),
+        _ => false,
+    };
+    let level = if ignorable {
+        Some(log::Level::Trace)
+    } else {
+        level.to_level()
+    };
+    if let Some(level) = level {
+        log::log!(level, "unhandled update: {:?}", update);
+    }
+}
+
+/// What the handlers need from the bot: a bare [`Bot`], or one wrapped in the
+/// adaptors enabled by `throttle` and `cache_me`. The handlers are generic over
+/// it since every adaptor stack is a different type.
+pub trait TelegramBot:
+    Requester<Err = RequestError, GetUpdates: Send>
+    + for<'w> Download<'w, Err = DownloadError>
+    + Clone
+    + Send
+    + Sync
+    + 'static
+{
+}
+
+impl<B> TelegramBot for B where
+    B: Requester<Err = RequestError, GetUpdates: Send>
+        + for<'w> Download<'w, Err = DownloadError>
+        + Clone
+        + Send
+        + Sync
+        + 'static
+{
+}
+
+fn build_bot(app_config: &AppConfig, client: reqwest::Client) -> Result<Bot> {
+    let mut tg = Bot::with_client(app_config.bot_token.expose_secret(), client);
+
+    if let Some(url) = env::var_os(TELEGRAM_BOT_API_URL_ENV) {
+        tg = tg.set_api_url(parse_api_url(url)?);
+    }
+    Ok(tg)
+}
+
+/// teloxide panics on the first request when it can't put the method name
+/// after the url, so anything but an http url is rejected here.
+fn parse_api_url(url: std::ffi::OsString) -> Result<Url> {
+    let url = url
+        .into_string()
+        .map_err(|_| anyhow::anyhow!("{TELEGRAM_BOT_API_URL_ENV} is not valid unicode"))?;
+    let parsed = Url::parse(&url)
+        .with_context(|| format!("{TELEGRAM_BOT_API_URL_ENV} is not a valid url: {url}"))?;
+    if !matches!(parsed.scheme(), "http" | "https") || parsed.cannot_be_a_base() {
+        bail!("{TELEGRAM_BOT_API_URL_ENV} must be an http or https url, got {url}");
+    }
+    Ok(parsed)
+}
+
+fn build_client(app_config: &AppConfig) -> Result<reqwest::Client> {
+    let mut builder = teloxide::net::default_reqwest_settings().timeout(HTTP_TIMEOUT);
+
+    if let Some(ca_cert_path) = &app_config.ca_cert_path {
+        let pem = std::fs::read(ca_cert_path)
+            .with_context(|| format!("Failed to read CA certificate: {ca_cert_path}"))?;
+        let cert = reqwest::Certificate::from_pem(&pem)
+            .with_context(|| format!("Failed to parse CA certificate: {ca_cert_path}"))?;
+        builder = builder.add_root_certificate(cert);
+    }
+    if app_config.danger_accept_invalid_certs {
+        warn!(
+            "danger_accept_invalid_certs is set: TLS certificates are NOT verified, \
+             anyone on the network path can read and tamper with the traffic"
+        );
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    if app_config.debug_http {
+        warn!(
+            "debug_http is set: every getFile call and file download is logged, which is verbose"
+        );
+    }
+    if let Some(max_idle) = app_config.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max_idle);
+    }
+    if let Some(secs) = app_config.pool_idle_timeout_secs {
+        builder = builder.pool_idle_timeout(Duration::from_secs(secs));
+    }
+    if let Some(secs) = app_config.tcp_keepalive_secs {
+        builder = builder.tcp_keepalive(Duration::from_secs(secs));
+    }
+    if let Some(nodelay) = app_config.tcp_nodelay {
+        builder = builder.tcp_nodelay(nodelay);
+    }
+
+    Ok(builder.build()?)
+}
+
+/// Name of the lock file every instance holds in its `media_directory`.
+const LOCK_FILE: &str = ".tg_download_bot.lock";
+
+/// Takes the lock on `media_directory`, so two instances don't race on the
+/// same `.part` files and counters.
+fn lock_media_directory(app_config: &AppConfig) -> Result<Option<std::fs::File>> {
+    use std::io::Write as _;
+
+    if app_config.storage != StorageKind::Local {
+        return Ok(None);
+    }
+    let media_directory = Path::new(&app_config.media_directory);
+    std::fs::create_dir_all(media_directory)
+        .with_context(|| format!("Failed to create {}", media_directory.display()))?;
+    let path = media_directory.join(LOCK_FILE);
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    match file.try_lock() {
+        Err(std::fs::TryLockError::WouldBlock) => {
+            let owner = std::fs::read_to_string(&path).unwrap_or_default();
+            let owner = owner.trim();
+            if !app_config.allow_shared_directory {
+                bail!(
+                    "Another instance (pid {owner}) is already saving to {}. Stop it, or set \
+                     allow_shared_directory = true to run both anyway",
+                    media_directory.display()
+                );
+            }
+            log::warn!(
+                "Another instance (pid {owner}) is already saving to {}, running anyway \
+                 because of allow_shared_directory",
+                media_directory.display()
+            );
+            Ok(None)
+        }
+        Err(std::fs::TryLockError::Error(e)) => {
+            Err(e).with_context(|| format!("Failed to lock {}", path.display()))
+        }
+        _ => {
+            file.set_len(0)?;
+            write!(file, "{}", std::process::id())?;
+            Ok(Some(file))
+        }
+    }
+}
+
+/// Looks up an `@username` `channel_id`, everything else only deals in chat ids.
+async fn resolve_channel_id(bot: &Bot, app_config: &mut AppConfig) -> Result<()> {
+    let ChannelRef::Username(username) = &app_config.channel_id else {
+        return Ok(());
+    };
+    let chat = bot
+        .get_chat(Recipient::ChannelUsername(username.clone()))
+        .await
+        .with_context(|| format!("Failed to look up channel_id {username}"))?;
+    log::info!("Resolved channel_id {username} to {}", chat.id);
+    app_config.channel_id = ChannelRef::Id(chat.id.0);
+    Ok(())
+}
+
+/// Warns about watched chats the bot can't receive posts from. Telegram only
+/// delivers channel posts to bots that are admins of the channel, otherwise
+/// the bot silently gets nothing. Also catches chats that were upgraded to a
+/// supergroup and got a new id, posts of the old id would just never match.
+async fn check_watched_chats(bot: &Bot, app_config: &AppConfig) {
+    let Some(me) = bot
+        .get_me()
+        .await
+        .inspect_err(|e| log::warn!("Can't check access to the watched chats: {e}"))
+        .ok()
+    else {
+        return;
+    };
+    for (chat_id, _) in app_config.channel_limits() {
+        match bot.get_chat(ChatId(chat_id)).await {
+            Err(RequestError::MigrateToChatId(new_id)) => log::error!(
+                "Chat {chat_id} was migrated to {new_id}, nothing will be downloaded from it \
+                 until its id is changed to {new_id} in the config"
+            ),
+            Err(e) => log::warn!(
+                "Can't access chat {chat_id}: {e}. Add the bot to it as an administrator, \
+                 or check that the id is right (channel ids start with -100)"
+            ),
+            chat if chat.as_ref().is_ok_and(Chat::is_channel) => {
+                match bot.get_chat_member(ChatId(chat_id), me.id).await {
+                    Err(e) => log::warn!("Can't check the bot's rights in channel {chat_id}: {e}"),
+                    member
+                        if member
+                            .as_ref()
+                            .is_ok_and(|member| !member.kind.is_privileged()) =>
+                    {
+                        log::error!(
+                            "The bot isn't an administrator of channel {chat_id}, Telegram won't \
+                             send it any posts from there. Add it in the channel settings under \
+                             Administrators, no particular rights are needed"
+                        )
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Repeats `check_watched_chats`, so losing admin rights later is noticed too.
+async fn recheck_watched_chats(bot: Bot, app_state: Arc<AppState>, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    // the check at startup already ran
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        check_watched_chats(&bot, &app_state.config).await;
+    }
+}
+
+async fn heartbeat(app_state: Arc<AppState>, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    // the first tick completes immediately
+    interval.tick().await;
+
+    let mut last_downloads = 0;
+    let mut last_errors = 0;
+    loop {
+        interval.tick().await;
+        let downloads = app_state.stats.downloads.load(Ordering::Relaxed);
+        let errors = app_state.stats.errors.load(Ordering::Relaxed);
+        let budget = app_state
+            .config
+            .max_downloads_per_session
+            .map(|max| {
+                let left = max.saturating_sub(app_state.session_downloads.load(Ordering::Relaxed));
+                format!(", {left} downloads left this session")
+            })
+            .unwrap_or_default();
+        log::info!(
+            "alive{}, {} downloads, {} errors since last heartbeat{budget}",
+            if app_state.paused.load(Ordering::Relaxed) {
+                " (paused)"
+            } else {
+                ""
+            },
+            downloads.saturating_sub(last_downloads),
+            errors.saturating_sub(last_errors)
+        );
+        last_downloads = downloads;
+        last_errors = errors;
+    }
+}
+
+/// Writes the album state and the counters as JSON to `state_dump_path`, or
+/// the log when unset, every time the process gets SIGUSR1.
+#[cfg(unix)]
+async fn dump_state_on_signal(app_state: Arc<AppState>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let signals = signal(SignalKind::user_defined1())
+        .inspect_err(|e| log::error!("Failed to listen for SIGUSR1, state dumps are off: {e}"));
+    let Some(mut signals) = signals.ok() else {
+        return;
+    };
+    while signals.recv().await.is_some() {
+        let dump = state_dump(&app_state);
+        match &app_state.config.state_dump_path {
+            Some(path) => match tokio::fs::write(path, dump).await {
+                Err(e) => log::error!("Failed to write the state dump to {path}: {e}"),
+                _ => log::info!("Dumped the state to {path}"),
+            },
+            None => log::info!("State: {dump}"),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn state_dump(app_state: &AppState) -> String {
+    let media_groups: serde_json::Map<String, serde_json::Value> = app_state
+        .media_group_page_numbers
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(media_group_id, data)| {
+            let data = serde_json::json!({
+                "page_number": data.page_number,
+                "title": data.title,
+                "folder": data.folder,
+                "unique_ids": data.unique_ids,
+                "last_part_secs_ago": data.last_part_at.elapsed().as_secs(),
+            });
+            (media_group_id.clone(), data)
+        })
+        .collect();
+    let stats = &app_state.stats;
+    let dump = serde_json::json!({
+        "run_id": app_state.run_id,
+        "uptime_secs": stats.started_at.elapsed().as_secs(),
+        "paused": app_state.paused.load(Ordering::Relaxed),
+        "downloads": stats.downloads.load(Ordering::Relaxed),
+        "errors": stats.errors.load(Ordering::Relaxed),
+        "bytes": stats.bytes.load(Ordering::Relaxed),
+        "session_downloads": app_state.session_downloads.load(Ordering::Relaxed),
+        "media_groups": media_groups,
+    });
+    serde_json::to_string_pretty(&dump).unwrap_or_default()
+}
+
+/// Long polling can wedge without ever reporting an error. Once updates have
+/// been coming in, going `threshold` without one exits the process so the
+/// supervisor restarts it. Quiet channels that never posted don't trigger it.
+async fn watchdog(app_state: Arc<AppState>, threshold: Duration) {
+    let mut interval = tokio::time::interval(threshold.min(Duration::from_secs(60)));
+    loop {
+        interval.tick().await;
+        let last_update_at = *app_state.last_update_at.lock().unwrap();
+        if let Some(silent) = last_update_at.map(|at| at.elapsed()) {
+            if silent >= threshold {
+                log::error!("No updates received for {silent:?}, exiting so polling restarts");
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+#[tracing::instrument(skip_all, fields(chat_id = message.chat.id.0, message_id = message.id.0))]
+async fn handle_media_message<B: TelegramBot>(
+    bot: Arc<B>,
+    message: Message,
+    app_state: Arc<AppState>,
+) -> Result<()> {
+    if app_state.paused.load(Ordering::Relaxed) {
+        // logged at info so the posts can be fetched with /fetch after /resume
+        log::info!(
+            "Paused, skipping post {} of {}",
+            message.id,
+            message.chat.id
+        );
+        return Ok(());
+    }
+    if app_state.config.skip_forwards && message.forward().is_some() {
+        log::debug!(
+            "Skipping forwarded post {} of {}",
+            message.id,
+            message.chat.id
+        );
+        return Ok(());
+    }
+    if let Some(max) = app_state.config.max_downloads_per_session {
+        if app_state.session_downloads.load(Ordering::Relaxed) >= max {
+            if !app_state.session_cap_reached.swap(true, Ordering::Relaxed) {
+                log::info!("Reached max_downloads_per_session ({max}), not downloading anymore");
+            }
+            log::debug!(
+                "Session cap reached, skipping post {} of {}",
+                message.id,
+                message.chat.id
+            );
+            return Ok(());
+        }
+    }
+    let sample_rate = app_state.config.sample_rate;
+    if sample_rate < 1.0 {
+        let sampled = app_state.sampler.lock().unwrap().random_bool(sample_rate);
+        log::trace!("Message {} sampled: {sampled}", message.id);
+        if !sampled {
+            return Ok(());
+        }
+    }
+
+    let _slot = match app_state.download_slots.get(&message.chat.id.0) {
+        Some(slots) => Some(slots.clone().acquire_owned().await?),
+        None => None,
+    };
+    let delete_after_download = app_state.config.delete_after_download;
+    if delete_after_download {
+        app_state.deletions.begin(&message);
+    }
+    let result = retry::retry(
+        app_state.config.message_retry_policy(),
+        "Handling the post",
+        retry::classify_message_error,
+        || save_message_media(bot.clone(), &message, app_state.clone()),
+    )
+    .await;
+    if delete_after_download {
+        let archived = result.as_ref().is_ok_and(|&archived| archived);
+        app_state
+            .deletions
+            .finish(bot, &message, archived, app_state.clone());
+    }
+    if result.is_err() {
+        app_state.stats.errors.fetch_add(1, Ordering::Relaxed);
+    }
+    result.map(|_| ())
+}
+
+/// Returns whether a media file of the post was saved.
+async fn save_message_media<B: TelegramBot>(
+    bot: Arc<B>,
+    message: &Message,
+    app_state: Arc<AppState>,
+) -> Result<bool> {
+    if !message.has_protected_content() {
+        return save_media(bot, message, app_state).await;
+    }
+    if app_state.config.protected_content == ProtectedContent::Skip {
+        log::info!(
+            "Skipping post {} of {}, the channel protects its content. Set \
+             protected_content = \"download\" to save it anyway",
+            message.id,
+            message.chat.id
+        );
+        return Ok(false);
+    }
+    // bots can usually still download from protected channels, but when
+    // Telegram refuses, a generic error would hide why
+    save_media(bot, message, app_state)
+        .await
+        .context("The channel has protected content enabled")
+}
+
+async fn save_media<B: TelegramBot>(
+    bot: Arc<B>,
+    message: &Message,
+    app_state: Arc<AppState>,
+) -> Result<bool> {
+    let media_kind = if let MessageKind::Common {
+        0: MessageCommon { media_kind, .. },
+        ..
+    } = &message.kind
+    {
+        media_kind
+    } else {
+        return Ok(false);
+    };
+
+    if app_state.config.require_caption && !has_group_caption(message, &app_state) {
+        log::debug!("Skipping message {} without caption", message.id);
+        return Ok(false);
+    }
+    if let Some(patterns) = &app_state.caption_include {
+        if !group_caption_matches(message, patterns, &app_state) {
+            log::debug!(
+                "Skipping message {} whose caption matches no caption_include_patterns",
+                message.id
+            );
+            return Ok(false);
+        }
+    }
+
+    let saved = match media_kind {
+        MediaKind::Photo(photo) => {
+            let max_size = app_state
+                .config
+                .photo_size
+                .select(&photo.photo)
+                .context("Photo without sizes")?;
+
+            let media = MediaFile {
+                media_type: "photo",
+                quality_key: album_quality::photo_key(max_size.width, max_size.height),
+                file_meta: &max_size.file,
+                file_name: photo.caption.as_deref(),
+                caption: photo.caption.as_deref(),
+                sender_dir: None,
+                sender_file_name: None,
+                mime_type: None,
+                default_ext: app_state
+                    .config
+                    .default_extension(message.chat.id.0, "photo", "jpg"),
+                spoiler: photo.has_media_spoiler,
+                performer: None,
+                track_title: None,
+                subdir: None,
+                ocr: None,
+            };
+            let saved = download_and_save_file(bot.clone(), message, media, app_state.clone())
+                .await
+                .context("Failed download photo")?;
+            if let (Some(photo_path), true) = (&saved, app_state.config.save_all_photo_sizes) {
+                save_other_photo_sizes(
+                    bot.as_ref(),
+                    &photo.photo,
+                    &max_size.file,
+                    photo_path,
+                    &app_state,
+                )
+                .await
+                .context("Failed download photo sizes")?;
+            }
+            saved
+        }
+        MediaKind::Video(video) => {
+            if let Some(min) = app_state.config.min_video_duration_secs {
+                if video.video.duration < min {
+                    log::debug!(
+                        "Skipping {}s video of message {}, shorter than {min}s",
+                        video.video.duration,
+                        message.id
+                    );
+                    return Ok(false);
+                }
+            }
+            if let Some(min) = app_state.config.min_video_resolution {
+                let resolution = video.video.width.min(video.video.height);
+                if resolution < min {
+                    log::debug!(
+                        "Skipping {}x{} video of message {}, below {min}p",
+                        video.video.width,
+                        video.video.height,
+                        message.id
+                    );
+                    return Ok(false);
+                }
+            }
+            let (sender_dir, sender_name) = app_state
+                .config
+                .sender_file_name(video.video.file_name.as_deref());
+            let media = MediaFile {
+                media_type: "video",
+                quality_key: album_quality::video_key(
+                    video.video.width,
+                    video.video.height,
+                    video.video.duration,
+                ),
+                file_meta: &video.video.file,
+                file_name: app_state.config.media_name(
+                    "video",
+                    video.caption.as_deref(),
+                    sender_name,
+                ),
+                caption: video.caption.as_deref(),
+                sender_dir,
+                sender_file_name: sender_name,
+                mime_type: video
+                    .video
+                    .mime_type
+                    .as_ref()
+                    .map(|mime| mime.essence_str()),
+                default_ext: app_state
+                    .config
+                    .default_extension(message.chat.id.0, "video", "mp4"),
+                spoiler: video.has_media_spoiler,
+                performer: None,
+                track_title: None,
+                subdir: None,
+                ocr: None,
+            };
+            download_and_save_file(bot, message, media, app_state.clone())
+                .await
+                .context("Failed download video")?
+        }
+        MediaKind::Audio(audio) => {
+            let (sender_dir, sender_name) = app_state
+                .config
+                .sender_file_name(audio.audio.file_name.as_deref());
+            let media = MediaFile {
+                media_type: "audio",
+                quality_key: album_quality::audio_key(audio.audio.duration),
+                file_meta: &audio.audio.file,
+                file_name: app_state.config.media_name(
+                    "audio",
+                    audio.caption.as_deref(),
+                    sender_name,
+                ),
+                caption: audio.caption.as_deref(),
+                sender_dir,
+                sender_file_name: sender_name,
+                mime_type: audio
+                    .audio
+                    .mime_type
+                    .as_ref()
+                    .map(|mime| mime.essence_str()),
+                default_ext: app_state
+                    .config
+                    .default_extension(message.chat.id.0, "audio", "mp3"),
+                // audio can't be sent as a spoiler
+                spoiler: false,
+                performer: audio.audio.performer.as_deref(),
+                track_title: audio.audio.title.as_deref(),
+                subdir: None,
+                ocr: None,
+            };
+            let saved = download_and_save_file(bot.clone(), message, media, app_state.clone())
+                .await
+                .context("Failed download audio")?;
+            if let (Some(audio_path), Some(cover), true) = (
+                &saved,
+                &audio.audio.thumb,
+                app_state.config.save_audio_covers,
+            ) {
+                save_audio_cover(bot.as_ref(), &cover.file, audio_path, &app_state)
+                    .await
+                    .context("Failed download audio cover")?;
+            }
+            saved
+        }
+        MediaKind::Contact(contact) if app_state.config.save_non_media => {
+            save_non_media_post(message, "contact", &contact.contact, &app_state).await?;
+            None
+        }
+        MediaKind::Location(location) if app_state.config.save_non_media => {
+            save_non_media_post(message, "location", &location.location, &app_state).await?;
+            None
+        }
+        MediaKind::Venue(venue) if app_state.config.save_non_media => {
+            save_non_media_post(message, "venue", &venue.venue, &app_state).await?;
+            None
+        }
+        MediaKind::Poll(poll) if app_state.config.save_non_media => {
+            save_non_media_post(message, "poll", &poll.poll, &app_state).await?;
+            None
+        }
+        _ if app_state.config.download_unknown_media => {
+            let Some(unknown) = UnknownMedia::of(media_kind) else {
+                return Ok(false);
+            };
+            let (sender_dir, sender_name) = app_state.config.sender_file_name(unknown.file_name);
+            let media = MediaFile {
+                media_type: "other",
+                // never the same media in another quality
+                quality_key: format!("other:{}", unknown.file.unique_id),
+                file_meta: unknown.file,
+                file_name: app_state
+                    .config
+                    .media_name("other", unknown.caption, sender_name),
+                caption: unknown.caption,
+                sender_dir,
+                sender_file_name: sender_name,
+                mime_type: unknown.mime_type,
+                default_ext: unknown
+                    .mime_type
+                    .and_then(inspect::mime_extension)
+                    .unwrap_or("bin"),
+                spoiler: false,
+                performer: None,
+                track_title: None,
+                subdir: Some(&app_state.config.unknown_media_dir),
+                ocr: None,
+            };
+            log::debug!(
+                "Saving {} of message {} as unknown media",
+                unknown.kind,
+                message.id
+            );
+            download_and_save_file(bot, message, media, app_state.clone())
+                .await
+                .with_context(|| format!("Failed download {}", unknown.kind))?
+        }
+        _ => None,
+    };
+    Ok(saved.is_some())
+}
+
+/// Media without dedicated handling that still carries a file, saved as is
+/// with `download_unknown_media`.
+struct UnknownMedia<'a> {
+    kind: &'static str,
+    file: &'a FileMeta,
+    file_name: Option<&'a str>,
+    mime_type: Option<&'a str>,
+    caption: Option<&'a str>,
+}
+
+impl<'a> UnknownMedia<'a> {
+    fn of(media_kind: &'a MediaKind) -> Option<Self> {
+        let (kind, file, file_name, mime_type, caption) = match media_kind {
+            MediaKind::Animation(animation) => (
+                "animation",
+                &animation.animation.file,
+                animation.animation.file_name.as_deref(),
+                animation.animation.mime_type.as_ref(),
+                animation.caption.as_deref(),
+            ),
+            MediaKind::Document(document) => (
+                "document",
+                &document.document.file,
+                document.document.file_name.as_deref(),
+                document.document.mime_type.as_ref(),
+                document.caption.as_deref(),
+            ),
+            MediaKind::Voice(voice) => (
+                "voice",
+                &voice.voice.file,
+                None,
+                voice.voice.mime_type.as_ref(),
+                voice.caption.as_deref(),
+            ),
+            MediaKind::VideoNote(video_note) => {
+                ("video note", &video_note.video_note.file, None, None, None)
+            }
+            MediaKind::Sticker(sticker) => ("sticker", &sticker.sticker.file, None, None, None),
+            _ => return None,
+        };
+        Some(Self {
+            kind,
+            file,
+            file_name,
+            mime_type: mime_type.map(|mime| mime.essence_str()),
+            caption,
+        })
+    }
+}
+
+/// Appends a line for `file_path` to the `captions.txt` of its directory,
+/// returns the file and the line.
+async fn append_caption_log(
+    app_state: &AppState,
+    file_path: &Path,
+    caption: &str,
+) -> Result<(PathBuf, String)> {
+    let file_name = file_path
+        .file_name()
+        .context("File without a name")?
+        .to_string_lossy();
+    let line = sidecar::caption_log_line(&file_name, caption);
+    let log_path = file_path.with_file_name("captions.txt");
+    // a single write of a line with O_APPEND isn't interleaved on local
+    // filesystems, the lock also covers network mounts that don't guarantee it
+    let _guard = app_state.caption_log_lock.lock().await;
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_path)
+        .await?;
+    file.write_all(line.as_bytes()).await?;
+    Ok((log_path, line))
+}
+
+#[derive(Serialize)]
+struct CsvRow<'a> {
+    timestamp: String,
+    channel: &'a str,
+    unique_id: &'a str,
+    path: &'a str,
+    size: u64,
+    caption: &'a str,
+}
+
+/// Appends `row` to the `csv_export`, starting a new file with the header.
+/// Returns the row as written, without the header.
+async fn append_csv_export(
+    app_state: &AppState,
+    csv_export: &Path,
+    row: &CsvRow<'_>,
+) -> Result<Vec<u8>> {
+    let _guard = app_state.csv_export_lock.lock().await;
+    let is_new = match tokio::fs::metadata(csv_export).await {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => true,
+        Err(e) => return Err(e.into()),
+        metadata => metadata?.len() == 0,
+    };
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(is_new)
+        .from_writer(Vec::new());
+    writer.serialize(row)?;
+    let record = writer.into_inner()?;
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(csv_export)
+        .await?;
+    file.write_all(&record).await?;
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(Vec::new());
+    writer.serialize(row)?;
+    Ok(writer.into_inner()?)
+}
+
+/// Runs `conversion` on a saved file and returns the converted file, which
+/// replaces it. On failure the original is kept and returned.
+async fn convert(conversion: &Conversion, path: PathBuf) -> PathBuf {
+    let converted = path.with_extension(&conversion.extension);
+    if let Err(e) = hooks::convert(&conversion.command, &path, &converted).await {
+        log::warn!("Keeping {} unconverted: {e:#}", path.display());
+        if let Err(e) = tokio::fs::remove_file(&converted).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                log::error!("Failed to remove {}: {e}", converted.display());
+            }
+        }
+        return path;
+    }
+    if let Err(e) = tokio::fs::remove_file(&path).await {
+        log::error!(
+            "Failed to remove {} after converting it: {e}",
+            path.display()
+        );
+    }
+    log::debug!("Converted {} to {}", path.display(), converted.display());
+    converted
+}
+
+/// `create_dir_all` through the storage, skipped for directories created
+/// before when `cache_created_dirs` is set.
+async fn create_dir_all(app_state: &AppState, dir: &Path) -> Result<()> {
+    if !app_state.config.cache_created_dirs {
+        return app_state.storage.create_dir_all(dir).await;
+    }
+    if app_state.created_dirs.lock().unwrap().contains(dir) {
+        return Ok(());
+    }
+    app_state.storage.create_dir_all(dir).await?;
+    app_state
+        .created_dirs
+        .lock()
+        .unwrap()
+        .insert(dir.to_path_buf());
+    Ok(())
+}
+
+/// Moves a file flagged by `scan_command` into `quarantine_dir`.
+async fn quarantine(path: &Path, quarantine_dir: &Path) -> Result<PathBuf> {
+    tokio::fs::create_dir_all(quarantine_dir)
+        .await
+        .context("Failed to create quarantine_dir")?;
+    let target = quarantine_dir.join(path.file_name().context("File without a name")?);
+    if tokio::fs::rename(path, &target).await.is_err() {
+        // quarantine_dir may be on another filesystem
+        tokio::fs::copy(path, &target)
+            .await
+            .with_context(|| format!("Failed to quarantine {}", path.display()))?;
+        tokio::fs::remove_file(path).await?;
+    }
+    Ok(target)
+}
+
+/// Saves the cover art of an audio file as `<name>.cover.jpg` next to it.
+async fn save_audio_cover<B: TelegramBot>(
+    bot: &B,
+    cover: &FileMeta,
+    audio_path: &Path,
+    app_state: &AppState,
+) -> Result<()> {
+    let (stem, _) = split_media_file_name(audio_path, app_state)?;
+    let cover_path = audio_path.with_file_name(media_file_name(
+        &format!("{stem}.cover"),
+        "jpg",
+        app_state.encryption_key.is_some(),
+    ));
+    save_file_as(bot, cover, &cover_path, app_state).await?;
+    log::info!("Saved audio cover: {}", cover_path.display());
+    Ok(())
+}
+
+/// Saves the sizes of a photo other than the one saved at `photo_path` next to
+/// it as `<name>_w<width>.<extension>`, for `save_all_photo_sizes`.
+async fn save_other_photo_sizes<B: TelegramBot>(
+    bot: &B,
+    sizes: &[PhotoSize],
+    saved: &FileMeta,
+    photo_path: &Path,
+    app_state: &AppState,
+) -> Result<()> {
+    let (stem, extension) = split_media_file_name(photo_path, app_state)?;
+    for size in sizes
+        .iter()
+        .filter(|size| size.file.unique_id != saved.unique_id)
+    {
+        let size_path = photo_path.with_file_name(media_file_name(
+            &format!("{stem}_w{}", size.width),
+            extension.unwrap_or("jpg"),
+            app_state.encryption_key.is_some(),
+        ));
+        save_file_as(bot, &size.file, &size_path, app_state)
+            .await
+            .with_context(|| format!("Failed to save the {}px wide size", size.width))?;
+        log::info!("Saved photo size: {}", size_path.display());
+    }
+    Ok(())
+}
+
+/// Stem and extension of a saved media file, without the encryption extension.
+fn split_media_file_name<'a>(
+    path: &'a Path,
+    app_state: &AppState,
+) -> Result<(&'a str, Option<&'a str>)> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .context("Non unicode media path")?;
+    let name = name
+        .strip_suffix(crypto::ENCRYPTED_EXTENSION)
+        .and_then(|name| name.strip_suffix('.'))
+        .filter(|_| app_state.encryption_key.is_some())
+        .unwrap_or(name);
+    let stem = Path::new(name)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(name);
+    let extension = Path::new(name)
+        .extension()
+        .and_then(|extension| extension.to_str());
+    Ok((stem, extension))
+}
+
+/// Downloads `file` to `path` as is, encrypted when encryption is enabled.
+async fn save_file_as<B: TelegramBot>(
+    bot: &B,
+    file: &FileMeta,
+    path: &Path,
+    app_state: &AppState,
+) -> Result<()> {
+    let file = retry::retry(
+        app_state.config.retry_policy(),
+        "get_file",
+        retry::classify_request_error,
+        || bot.get_file(file.id.clone()).send(),
+    )
+    .await?;
+    let (dst, upload) = app_state.storage.create(path).await?;
+    let mut dst: storage::Writer = match &app_state.encryption_key {
+        Some(key) => Box::new(EncryptedWriter::new(dst, key)),
+        None => Box::new(dst),
+    };
+    copy_telegram_file(bot, &file.path, &mut dst).await?;
+    upload.finish(&mut dst).await
+}
+
+/// Records posts without a file (contacts, locations, polls) as
+/// `<kind>_<chat id>_<message id>.json`.
+async fn save_non_media_post(
+    message: &Message,
+    kind: &str,
+    content: &impl serde::Serialize,
+    app_state: &AppState,
+) -> Result<()> {
+    let record = serde_json::json!({
+        "type": kind,
+        "chat_id": message.chat.id.0,
+        "message_id": message.id.0,
+        "date": message.date,
+        kind: content,
+    });
+
+    let dir = post_dir(app_state, message);
+    let path = dir.join(format!(
+        "{kind}_{}_{}.json",
+        message.chat.id.0, message.id.0
+    ));
+
+    create_dir_all(app_state, &dir).await?;
+    app_state
+        .storage
+        .write_file(&path, &serde_json::to_vec_pretty(&record)?)
+        .await
+        .with_context(|| format!("Failed to save {kind}"))?;
+    log::info!("Saved {kind}: {}", path.display());
+    Ok(())
+}
+
+/// Only the first item of an album usually carries the caption, so the rest
+/// of the album counts as captioned once it has been seen.
+fn has_group_caption(message: &Message, app_state: &AppState) -> bool {
+    let mut captioned = app_state.captioned_media_groups.lock().unwrap();
+    match (message.caption(), message.media_group_id()) {
+        (Some(_), Some(media_group_id)) => {
+            captioned.insert(media_group_id.to_owned(), ());
+            true
+        }
+        (Some(_), None) => true,
+        (None, Some(media_group_id)) => captioned.contains(media_group_id),
+        (None, None) => false,
+    }
+}
+
+/// Like `has_group_caption`, album items without a caption go with the
+/// caption of an earlier item.
+fn group_caption_matches(message: &Message, patterns: &RegexSet, app_state: &AppState) -> bool {
+    let mut included = app_state.included_media_groups.lock().unwrap();
+    match (message.caption(), message.media_group_id()) {
+        (Some(caption), media_group_id) => {
+            let matches = patterns.is_match(caption);
+            if let Some(media_group_id) = media_group_id.filter(|_| matches) {
+                included.insert(media_group_id.to_owned(), ());
+            }
+            matches
+        }
+        (None, Some(media_group_id)) => included.contains(media_group_id),
+        (None, None) => false,
+    }
+}
+
+/// A downloadable file of a message and what's known for naming it.
+struct MediaFile<'a> {
+    /// `photo`, `video` or `audio`.
+    media_type: &'static str,
+    /// Groups album items that look like the same media in different qualities.
+    quality_key: String,
+    file_meta: &'a FileMeta,
+    file_name: Option<&'a str>,
+    /// Caption of the post, for `name_sources`.
+    caption: Option<&'a str>,
+    /// Directory part of the sender's file name, recreated below the download
+    /// directory.
+    sender_dir: Option<&'a str>,
+    /// Basename of the file as it was sent, the only source of a real extension.
+    sender_file_name: Option<&'a str>,
+    mime_type: Option<&'a str>,
+    default_ext: &'a str,
+    spoiler: bool,
+    /// Tags of audio files.
+    performer: Option<&'a str>,
+    track_title: Option<&'a str>,
+    /// Directory below the post's directory the file is saved in.
+    subdir: Option<&'a str>,
+    /// Text found by `ocr_command`, only known after the download.
+    ocr: Option<&'a str>,
+}
+
+async fn download_and_save_file<B: TelegramBot>(
+    bot: Arc<B>,
+    message: &Message,
+    media: MediaFile<'_>,
+    app_state: Arc<AppState>,
+) -> Result<Option<PathBuf>> {
+    if let Some(breaker) = &app_state.breaker {
+        breaker.wait_until_closed().await;
+    }
+    let quality_group = message
+        .media_group_id()
+        .filter(|_| app_state.config.dedupe_album_qualities);
+    let mut replaced = None;
+    if let Some(media_group_id) = quality_group {
+        match app_state.album_qualities.decide(
+            media_group_id,
+            &media.quality_key,
+            media.file_meta.size,
+        ) {
+            Decision::Download => {}
+            Decision::Skip => {
+                log::info!(
+                    "Discarding {} of album {media_group_id}, a larger version of it is kept",
+                    media.file_meta.unique_id
+                );
+                return Ok(None);
+            }
+            Decision::Replace(smaller) => replaced = Some(smaller),
+        }
+    }
+
+    let _inflight = match &app_state.inflight {
+        Some(limit) => Some(limit.acquire(u64::from(media.file_meta.size)).await),
+        None => None,
+    };
+    let result = try_download_and_save_file(bot, message, &media, &app_state).await;
+
+    if let (Some(media_group_id), Some(Some(path))) = (quality_group, result.as_ref().ok()) {
+        app_state.album_qualities.saved(
+            media_group_id,
+            &media.quality_key,
+            media.file_meta.size,
+            path.clone(),
+        );
+        if let Some(smaller) = replaced {
+            log::info!(
+                "Discarding {}, a larger version of it was saved as {}",
+                smaller.display(),
+                path.display()
+            );
+            if let Err(e) = app_state.storage.discard(&smaller).await {
+                log::error!("Failed to remove smaller version: {e:#}");
+            }
+        }
+    }
+    if let (Some(media_group_id), Err(_)) = (quality_group, &result) {
+        app_state
+            .album_qualities
+            .failed(media_group_id, &media.quality_key, media.file_meta.size);
+    }
+    if let Some(breaker) = &app_state.breaker {
+        breaker.record(&result);
+    }
+    if let Some(dead_letter_dir) = &app_state.config.dead_letter_dir {
+        let dead_letter_dir = Path::new(dead_letter_dir);
+        if let Err(e) = &result {
+            let dead_letter = DeadLetter::new(message, &media, e);
+            if let Err(e) = dead_letter::write(dead_letter_dir, &dead_letter).await {
+                log::error!("Failed to write dead letter: {e:#}");
+            }
+        } else if result.as_ref().is_ok_and(Option::is_some) {
+            // left by an earlier attempt
+            dead_letter::remove(dead_letter_dir, &media.file_meta.unique_id).await;
+        }
+    }
+    if let (true, Some(Some(path))) = (app_state.config.generate_gallery, result.as_ref().ok()) {
+        if let Some(dir) = path.parent() {
+            gallery::schedule(app_state.clone(), dir.to_owned());
+        }
+    }
+    let outcome = if result.is_err() {
+        Outcome::Failed
+    } else if result.as_ref().is_ok_and(Option::is_some) {
+        Outcome::Saved
+    } else {
+        Outcome::Skipped
+    };
+    react(&app_state, message, media.media_type, outcome);
+    result
+}
+
+/// Sets the configured reaction on the post in the background, for albums
+/// only on the first post handled.
+fn react(app_state: &Arc<AppState>, message: &Message, media_type: &str, outcome: Outcome) {
+    if app_state.reactor.is_none() {
+        return;
+    }
+    let Some(emoji) = reactions::emoji(&app_state.config.reactions, outcome, media_type) else {
+        return;
+    };
+    if let Some(media_group_id) = message.media_group_id() {
+        let mut map = app_state.media_group_page_numbers.lock().unwrap();
+        if let Some(data) = map.get_mut(media_group_id) {
+            if std::mem::replace(&mut data.reacted, true) {
+                return;
+            }
+        }
+    }
+    let (app_state, emoji) = (app_state.clone(), emoji.to_owned());
+    let (chat_id, message_id) = (message.chat.id.0, message.id.0);
+    tokio::spawn(async move {
+        if let Some(reactor) = &app_state.reactor {
+            if let Err(e) = reactor.react(chat_id, message_id, &emoji).await {
+                log::warn!("Failed to react to message {message_id} in {chat_id}: {e:#}");
+            }
+        }
+    });
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(
+        unique_id = %media.file_meta.unique_id,
+        size = media.file_meta.size,
+        bytes = tracing::field::Empty,
+        duration_ms = tracing::field::Empty,
+    )
+)]
+async fn try_download_and_save_file<B: TelegramBot>(
+    bot: Arc<B>,
+    message: &Message,
+    media: &MediaFile<'_>,
+    app_state: &AppState,
+) -> Result<Option<PathBuf>> {
+    let &MediaFile {
+        media_type,
+        file_meta,
+        file_name,
+        sender_dir,
+        mime_type,
+        default_ext: ext,
+        spoiler,
+        ..
+    } = media;
+
+    if file_meta.size > CLOUD_API_MAX_FILE_SIZE
+        && app_state.config.oversized_on_cloud_api == OversizedOnCloudApi::Skip
+        && env::var_os(TELEGRAM_BOT_API_URL_ENV).is_none()
+    {
+        log::warn!(
+            "Skipping {} ({} MB): the cloud Bot API only hands out files up to 20 MB. Run a \
+             local Bot API server (https://github.com/tdlib/telegram-bot-api) and set \
+             {TELEGRAM_BOT_API_URL_ENV} to its url to download bigger files",
+            file_meta.unique_id,
+            file_meta.size / (1024 * 1024)
+        );
+        return Ok(None);
+    }
+
+    let already_downloaded = app_state.config.skip_duplicates
+        && (app_state.index.contains(&file_meta.unique_id)
+            || match &app_state.database {
+                Some(database) => database.contains(&file_meta.unique_id).await?,
+                None => false,
+            });
+    // the page counter and the duplicate checks are decided together under the
+    // lock, so concurrent album items agree on the numbering
+    let mut duplicate_copy = None;
+    let get_file_spacing = app_state
+        .config
+        .album_get_file_spacing_ms
+        .map(Duration::from_millis);
+    let mut get_file_at = None;
+    let media_group = if let Some(media_group_id) = message.media_group_id() {
+        let mut map = app_state.media_group_page_numbers.lock().unwrap();
+        if let Some(grace) = app_state.config.album_grace_secs.map(Duration::from_secs) {
+            let mut evicted = app_state.evicted_media_groups.lock().unwrap();
+            for (media_group_id, data) in
+                map.extract_if(|_, data| data.last_part_at.elapsed() >= grace)
+            {
+                evicted.insert(media_group_id, data);
+            }
+        }
+        let new_album = |title: String| {
+            let folder = app_state.config.album_folders.then(|| {
+                allocate_album_folder(
+                    app_state,
+                    &post_dir(app_state, message),
+                    &title,
+                    media_group_id,
+                )
+            });
+            MediaGroupData {
+                page_number: 0,
+                title,
+                folder,
+                unique_ids: Vec::new(),
+                last_part_at: Instant::now(),
+                files: Vec::new(),
+                parts: Vec::new(),
+                next_get_file_at: Instant::now(),
+                captions: BTreeMap::new(),
+                reacted: false,
+            }
+        };
+        let chained_title = (!app_state.config.name_sources.is_empty())
+            .then(|| chained_title(&app_state.config.name_sources, media));
+        let name = match &chained_title {
+            Some(title) => Some(title.as_ref()).filter(|title| !title.is_empty()),
+            None => file_name,
+        };
+        let page_number = map.entry(media_group_id.to_owned()).or_insert_with(|| {
+            let evicted = app_state
+                .evicted_media_groups
+                .lock()
+                .unwrap()
+                .remove(media_group_id);
+            match evicted {
+                Some(data) => {
+                    log::info!(
+                        "Media group {media_group_id} got a part after album_grace_secs, \
+                         continuing after page {}",
+                        data.page_number
+                    );
+                    data
+                }
+                None => new_album(album_title(message, name, media_group_id)),
+            }
+        });
+        page_number.last_part_at = Instant::now();
+        // Telegram albums have at most 10 items, more under one id come from
+        // forwarded or merged content
+        if let Some(max_items) = app_state.config.max_media_group_items {
+            if page_number.page_number == max_items {
+                match app_state.config.oversized_media_groups {
+                    OversizedMediaGroups::Continue => log::warn!(
+                        "Media group {media_group_id} has more than {max_items} items, \
+                         continuing its numbering"
+                    ),
+                    OversizedMediaGroups::Split => {
+                        log::warn!(
+                            "Media group {media_group_id} has more than {max_items} items, \
+                             starting a new album"
+                        );
+                        *page_number = new_album(page_number.title.clone());
+                    }
+                }
+            }
+        }
+        if let Some(caption) = message.caption() {
+            page_number
+                .captions
+                .insert(message.id.0, caption.to_owned());
+        }
+        // a message handled again after a failure keeps the page it got
+        let retried = page_number
+            .parts
+            .iter()
+            .find(|part| part.message_id == message.id.0)
+            .copied();
+        if let Some(part) = retried {
+            duplicate_copy = part.duplicate_copy;
+            get_file_at = page_number.reserve_get_file(get_file_spacing);
+            let mut data = page_number.clone();
+            data.page_number = part.page_number;
+            Some(data)
+        } else {
+            let copies = page_number
+                .unique_ids
+                .iter()
+                .filter(|&unique_id| unique_id == &file_meta.unique_id)
+                .count();
+            if let Some(max_items) = app_state.config.max_items_per_album {
+                // counted by unique id, items can arrive in any order
+                let distinct: HashSet<&String> = page_number.unique_ids.iter().collect();
+                if copies == 0 && distinct.len() >= max_items {
+                    log::info!(
+                        "Skipping {} of media group {media_group_id}, past max_items_per_album",
+                        file_meta.unique_id
+                    );
+                    return Ok(None);
+                }
+            }
+            let policy = app_state.config.album_duplicates;
+            let skip = match policy {
+                AlbumDuplicates::KeepWithSuffix => already_downloaded && copies == 0,
+                _ => already_downloaded || (app_state.config.skip_duplicates && copies > 0),
+            };
+            if skip {
+                if policy == AlbumDuplicates::SkipAndGap {
+                    page_number.page_number += 1;
+                }
+                log::info!("Skipping duplicate file {}", file_meta.unique_id);
+                return Ok(None);
+            }
+            page_number.page_number += 1;
+            page_number.unique_ids.push(file_meta.unique_id.clone());
+            get_file_at = page_number.reserve_get_file(get_file_spacing);
+            if copies > 0 {
+                duplicate_copy = Some(copies + 1);
+            }
+            page_number.parts.push(AlbumPart {
+                message_id: message.id.0,
+                page_number: page_number.page_number,
+                duplicate_copy,
+            });
+            Some(page_number.clone())
+        }
+    } else {
+        None
+    };
+
+    if media_group.is_none() && already_downloaded {
+        log::info!("Skipping already downloaded file {}", file_meta.unique_id);
+        return Ok(None);
+    }
+
+    if let Some(at) = get_file_at {
+        // spreads the near-simultaneous calls of an album's items
+        tokio::time::sleep_until(at.into()).await;
+    }
+    let started_at = Instant::now();
+    // get_file runs before both the local server and the download branch, so
+    // flood control on it is the most common failure
+    let file = retry::retry(
+        app_state.config.retry_policy(),
+        "get_file",
+        retry::classify_request_error,
+        || async {
+            let started = Instant::now();
+            let result = bot.get_file(file_meta.id.clone()).send().await;
+            if app_state.config.debug_http {
+                log_http(
+                    "POST",
+                    &format!("bot<token>/getFile?file_id={}", file_meta.id),
+                    started,
+                    &result,
+                );
+            }
+            result
+        },
+    )
+    .await?;
+    let message_date = message.date.with_timezone(&app_state.config.timezone.0);
+    let album = media_group
+        .as_ref()
+        .and_then(|data| Some((data.folder.clone()?, data.page_number)));
+    let channel = channel_name(app_state, &message.chat);
+    let encrypted = app_state.encryption_key.is_some();
+    let mut dir = post_dir(app_state, message);
+    let size_tier = app_state.config.size_tier_routing.as_ref();
+    if let Some(subdir) = size_tier.and_then(|routing| routing.subdir(file_meta.size)) {
+        dir.push(subdir);
+    }
+    if let Some(subdir) = mime_type.and_then(|mime| app_state.config.mime_subdir(mime)) {
+        dir.push(subdir);
+    }
+    if let Some(sender_dir) = sender_dir {
+        dir.extend(sender_path_components(sender_dir));
+    }
+    let album_dir = album.as_ref().map(|(folder, _)| {
+        dir.push(folder);
+        dir.clone()
+    });
+    if let Some(subdir) = media.subdir {
+        dir.push(subdir);
+    }
+    if spoiler {
+        if let Some(subdir) = &app_state.config.spoiler_subdir {
+            dir.push(subdir);
+        }
+    }
+    // album folders hold only a handful of files each
+    if let (Some(sharding), None) = (&app_state.config.hash_sharding, &album) {
+        dir.extend(sharding.shard(&file_meta.unique_id));
+    }
+    if let (Some(dir_limit), None) = (&app_state.dir_limit, &album) {
+        dir = dir_limit.pick(&dir).await?;
+    }
+    let seq = match &app_state.seq {
+        Some(seq) => Some(seq.next().await.context("Failed to advance {seq}")?),
+        None => None,
+    };
+    // the title is the only part that can be shortened, cut it until the name
+    // and the whole path fit
+    let mut title_limit = app_state.config.max_title_len;
+    let (filename, extension, file_path) = loop {
+        let (mut filename, extension) = get_filename_and_extension(
+            media,
+            media_group.as_ref(),
+            message_date,
+            &channel,
+            seq,
+            title_limit,
+            app_state,
+        );
+        if let Some((_, page_number)) = album {
+            filename = page_number.to_string();
+        }
+        if let Some(copy) = duplicate_copy {
+            filename = format!("{filename}_dup{copy}");
+        }
+        if spoiler {
+            if let Some(prefix) = &app_state.config.spoiler_prefix {
+                filename = format!("{prefix}{filename}");
+            }
+        }
+        let file_path = dir.join(media_file_name(&filename, &extension, encrypted));
+        let excess = app_state.config.path_excess(&file_path);
+        if excess == 0 {
+            break (filename, extension, file_path);
+        }
+        if title_limit == 0 || album.is_some() {
+            bail!("Path is too long: {}", file_path.display());
+        }
+        title_limit = title_limit
+            .min(filename.chars().count())
+            .saturating_sub(excess);
+        log::debug!("Shortening the title to {title_limit} characters to fit the path length");
+    };
+    let media_directory = Path::new(&app_state.config.media_directory);
+    if let Err(e) = ensure_inside(media_directory, &file_path) {
+        log::error!("Refusing to save {}: {e:#}", file_meta.unique_id);
+        return Err(e);
+    }
+
+    create_dir_all(app_state, &dir).await?;
+    if let Storage::Local(_) = app_state.storage {
+        // a symlink inside media_directory could still lead elsewhere
+        let resolved = tokio::fs::canonicalize(&dir).await?;
+        let root = tokio::fs::canonicalize(media_directory).await?;
+        if !resolved.starts_with(&root) {
+            log::error!(
+                "Refusing to save {}: {} resolves to {} outside of media_directory",
+                file_meta.unique_id,
+                dir.display(),
+                resolved.display()
+            );
+            bail!("{} is outside of media_directory", resolved.display());
+        }
+    }
+    let (dst, upload) = app_state.storage.create(&file_path).await?;
+    let dst: storage::Writer = match &app_state.encryption_key {
+        Some(key) => Box::new(EncryptedWriter::new(dst, key)),
+        None => Box::new(dst),
+    };
+    // only second-guess the extension when it's the hard-coded default
+    let sniff = app_state.config.sniff_extension && extension == ext
+        || app_state.config.correct_extension_after_download;
+    let mut dst = InspectingWriter::new(dst, if sniff { inspect::SNIFF_LEN } else { 0 });
+    let bandwidth = app_state.bandwidth.clone().or_else(|| {
+        app_state
+            .config
+            .max_bytes_per_sec
+            .map(|rate| Arc::new(TokenBucket::new(rate)))
+    });
+    let expected = u64::from(file_meta.size);
+    // a local Bot API server hands out paths that are read directly
+    let chunked = app_state.chunked.as_ref().filter(|downloader| {
+        !Path::new(&file.path).is_absolute() && downloader.chunks_for(expected) > 1
+    });
+    let download_started = Instant::now();
+    let copied = match (chunked, bandwidth) {
+        (Some(downloader), _) => {
+            let chunks_path = match app_state.storage {
+                Storage::Local(_) => {
+                    let mut chunks_path = file_path.clone().into_os_string();
+                    chunks_path.push(".chunks");
+                    PathBuf::from(chunks_path)
+                }
+                _ => env::temp_dir().join(format!("{}.chunks", file_meta.unique_id)),
+            };
+            copy_chunked(
+                bot.as_ref(),
+                downloader,
+                &file.path,
+                expected,
+                &chunks_path,
+                &mut dst,
+            )
+            .await
+        }
+        (None, Some(bucket)) => {
+            let mut limited = RateLimitedWriter::new(&mut dst, bucket);
+            copy_telegram_file(bot.as_ref(), &file.path, &mut limited).await
+        }
+        (None, None) => copy_telegram_file(bot.as_ref(), &file.path, &mut dst).await,
+    };
+    if app_state.config.debug_http {
+        if Path::new(&file.path).is_absolute() {
+            log_http("READ", &file.path, download_started, &copied);
+        } else {
+            let path = format!("file/bot<token>/{}", file.path);
+            log_http("GET", &path, download_started, &copied);
+        }
+    }
+    // a short read from the API server would otherwise look like a success
+    let copied = copied.and_then(|()| {
+        if expected > 0 && dst.bytes_written() < expected {
+            bail!(
+                "Incomplete download: got {} of {expected} bytes",
+                dst.bytes_written()
+            );
+        }
+        Ok(())
+    });
+    if let Err(e) = copied {
+        // cancels a remote upload, the partial local file is removed below
+        drop(upload);
+        drop(dst);
+        if app_state.config.keep_partial_on_failure
+            && matches!(app_state.storage, Storage::Local(_))
+        {
+            let mut partial_path = file_path.clone().into_os_string();
+            partial_path.push(".part");
+            let partial_path = PathBuf::from(partial_path);
+            if let Err(e) = app_state.storage.rename(&file_path, &partial_path).await {
+                log::error!("Failed to keep partial file: {e:#}");
+            } else {
+                log::warn!("Kept partial download at {}", partial_path.display());
+            }
+        } else if let Err(e) = app_state.storage.discard(&file_path).await {
+            log::error!("Failed to remove partial file: {e:#}");
+        }
+        return Err(e);
+    }
+    upload.finish(&mut dst).await?;
+    let mut cleanup = StrictCleanup {
+        file: app_state.config.strict_pipeline.then(|| file_path.clone()),
+        nfo: false,
+        blob: None,
+    };
+    if app_state.config.strict_pipeline {
+        verify_on_disk(&file_path, (!encrypted).then(|| dst.bytes_written())).await?;
+    }
+
+    let file_path = match dst
+        .sniffed_extension()
+        .filter(|&sniffed| !inspect::same_extension(sniffed, &extension))
+    {
+        Some(sniffed) => {
+            let sniffed_path = dir.join(media_file_name(&filename, sniffed, encrypted));
+            app_state.storage.rename(&file_path, &sniffed_path).await?;
+            log::debug!("Detected .{sniffed} content, saved as .{sniffed} instead of .{extension}");
+            cleanup.moved_to(&sniffed_path);
+            sniffed_path
+        }
+        None => file_path,
+    };
+
+    if app_state.config.auto_orient_photos && media_type == "photo" && !encrypted {
+        let oriented = orient::auto_orient(&file_path).await;
+        if let Err(e) = &oriented {
+            log::warn!("Keeping {} as it is: {e:#}", file_path.display());
+        } else if oriented.is_ok_and(|changed| changed) {
+            log::debug!("Applied the EXIF orientation of {}", file_path.display());
+        }
+    }
+    if app_state.config.verify_with_ffprobe && media_type == "video" && !encrypted {
+        if let Err(e) = hooks::ffprobe(&app_state.config.ffprobe_path, &file_path).await {
+            if let Err(e) = app_state.storage.discard(&file_path).await {
+                log::error!("Failed to remove broken file: {e:#}");
+            }
+            return Err(e);
+        }
+    }
+    let clean = if app_state.config.scan_command.is_empty() {
+        true
+    } else {
+        match hooks::scan_file(
+            &app_state.config.scan_command,
+            &file_path,
+            &file_meta.unique_id,
+            message.caption().or(file_name),
+            sidecar::message_link(message).as_deref(),
+        )
+        .await
+        {
+            Result::Ok(clean) => clean,
+            // a file the scanner couldn't check isn't kept either
+            Err(e) => {
+                if let Err(e) = app_state.storage.discard(&file_path).await {
+                    log::error!("Failed to remove unscanned file: {e:#}");
+                }
+                return Err(e);
+            }
+        }
+    };
+    if !clean {
+        let quarantine_dir = app_state
+            .config
+            .quarantine_dir
+            .as_deref()
+            .context("quarantine_dir not set")?;
+        let quarantined = quarantine(&file_path, Path::new(quarantine_dir)).await?;
+        log::warn!(
+            "Quarantined {} as {}",
+            file_path.display(),
+            quarantined.display()
+        );
+        return Ok(None);
+    }
+    let file_path = match file_path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .and_then(|extension| app_state.config.conversions.get(extension))
+    {
+        Some(conversion) if !encrypted => convert(conversion, file_path).await,
+        _ => file_path,
+    };
+    cleanup.moved_to(&file_path);
+
+    let ocr_text =
+        if !app_state.config.ocr_command.is_empty() && media_type == "photo" && !encrypted {
+            hooks::ocr(&app_state.config.ocr_command, &file_path)
+                .await
+                .inspect_err(|e| log::warn!("No OCR text for {}: {e:#}", file_path.display()))
+                .ok()
+                .filter(|text| !text.is_empty())
+        } else {
+            None
+        };
+    // the text is only known now, so the file gets its final name afterwards
+    let file_path = match &ocr_text {
+        Some(ocr) if album.is_none() && app_state.config.uses_ocr_token() => {
+            let media = MediaFile {
+                quality_key: String::new(),
+                ocr: Some(ocr),
+                ..*media
+            };
+            let (mut ocr_filename, _) = get_filename_and_extension(
+                &media,
+                media_group.as_ref(),
+                message_date,
+                &channel,
+                seq,
+                title_limit,
+                app_state,
+            );
+            if spoiler {
+                if let Some(prefix) = &app_state.config.spoiler_prefix {
+                    ocr_filename = format!("{prefix}{ocr_filename}");
+                }
+            }
+            let extension = file_path
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .unwrap_or(&extension);
+            let ocr_path = dir.join(media_file_name(&ocr_filename, extension, false));
+            if ocr_path == file_path || app_state.config.path_excess(&ocr_path) > 0 {
+                file_path
+            } else {
+                app_state.storage.rename(&file_path, &ocr_path).await?;
+                cleanup.moved_to(&ocr_path);
+                ocr_path
+            }
+        }
+        _ => file_path,
+    };
+
+    let phash = if app_state.config.compute_phash && media_type == "photo" && !encrypted {
+        phash::compute(&file_path)
+            .await
+            .inspect_err(|e| log::warn!("No perceptual hash for {}: {e:#}", file_path.display()))
+            .ok()
+    } else {
+        None
+    };
+    let content_addressed = app_state.config.storage_layout == StorageLayout::ContentAddressed;
+    let sha256 = if app_state.config.strict_pipeline || content_addressed {
+        Some(sha256_file(&file_path).await?)
+    } else {
+        None
+    };
+    if let Some(sha256) = sha256.as_deref().filter(|_| content_addressed) {
+        cleanup.blob = content_store::store(media_directory, &file_path, sha256).await?;
+    }
+
+    if app_state.config.write_metadata {
+        let mut metadata = sidecar::Metadata::new(
+            message,
+            &file_meta.unique_id,
+            &file_meta.id,
+            app_state.config.detect_language,
+        );
+        metadata.phash = phash.as_deref();
+        metadata.ocr_text = ocr_text.as_deref();
+        metadata.sha256 = sha256.as_deref();
+        if app_state.config.metadata_run_id {
+            metadata.run_id = Some(&app_state.run_id);
+        }
+        if app_state.config.metadata_message_link {
+            metadata.message_link = Some(sidecar::message_link(message));
+        }
+        app_state
+            .storage
+            .write_file(
+                &sidecar::sidecar_path(&file_path),
+                &serde_json::to_vec_pretty(&metadata)?,
+            )
+            .await
+            .context("Failed to write metadata")?;
+    }
+    if app_state.config.write_context {
+        app_state
+            .storage
+            .write_file(
+                &sidecar::context_path(&file_path),
+                &serde_json::to_vec_pretty(&sidecar::Context::new(message))?,
+            )
+            .await
+            .context("Failed to write context")?;
+    }
+    if app_state.config.write_nfo && media_type == "video" {
+        let nfo = sidecar::nfo(message.caption(), &filename, message_date);
+        cleanup.nfo = true;
+        app_state
+            .storage
+            .write_file(&sidecar::nfo_path(&file_path), nfo.as_bytes())
+            .await
+            .context("Failed to write nfo")?;
+    }
+    // lines added to files shared with other downloads are taken back when a
+    // later step fails, the database entry is the last step that can fail
+    let mut appended = Appended::default();
+    let size = dst.bytes_written();
+    let recorded: Result<()> = async {
+        if let (true, Some(album_dir), Some((_, page)), Some(media_group_id)) = (
+            app_state.config.album_manifest,
+            &album_dir,
+            &album,
+            message.media_group_id(),
+        ) {
+            write_album_manifest(
+                app_state,
+                media_group_id,
+                album_dir,
+                sidecar::AlbumFile {
+                    page: *page,
+                    file: file_path
+                        .strip_prefix(album_dir)
+                        .unwrap_or(&file_path)
+                        .to_string_lossy()
+                        .into_owned(),
+                    unique_id: file_meta.unique_id.clone(),
+                },
+            )
+            .await
+            .context("Failed to write album manifest")?;
+            appended.album_manifest = Some((media_group_id.to_owned(), album_dir.clone()));
+        }
+        if app_state.config.caption_log {
+            appended.caption_log = Some(
+                append_caption_log(app_state, &file_path, message.caption().unwrap_or(""))
+                    .await
+                    .context("Failed to append to captions.txt")?,
+            );
+        }
+        if let Some(csv_export) = &app_state.config.csv_export {
+            let row = append_csv_export(
+                app_state,
+                Path::new(csv_export),
+                &CsvRow {
+                    timestamp: chrono::Utc::now()
+                        .with_timezone(&app_state.config.timezone.0)
+                        .to_rfc3339(),
+                    channel: &channel,
+                    unique_id: &file_meta.unique_id,
+                    path: &file_path.to_string_lossy(),
+                    size,
+                    caption: message.caption().unwrap_or(""),
+                },
+            )
+            .await
+            .with_context(|| format!("Failed to append to {csv_export}"))?;
+            appended.csv_export = Some(row);
+        }
+        if let Some(database) = &app_state.database {
+            database
+                .insert(DownloadRecord {
+                    unique_id: file_meta.unique_id.clone(),
+                    file_id: file_meta.id.clone(),
+                    path: file_path.clone(),
+                    size,
+                    caption: message.caption().map(str::to_owned),
+                    date: message.date,
+                    media_type: media_type.to_owned(),
+                    chat_id: message.chat.id.0,
+                    channel,
+                    phash,
+                    run_id: app_state.run_id.clone(),
+                })
+                .await?;
+        }
+        Ok(())
+    }
+    .await;
+    if let Err(e) = recorded {
+        if app_state.config.strict_pipeline {
+            appended.undo(app_state, &file_meta.unique_id).await;
+        }
+        return Err(e);
+    }
+    if app_state.config.daily_manifest {
+        app_state.daily_manifest.record(
+            app_state.config.timezone.0,
+            daily_manifest::Entry {
+                path: file_path
+                    .strip_prefix(media_directory)
+                    .unwrap_or(&file_path)
+                    .to_owned(),
+                caption: message.caption().map(str::to_owned),
+                unique_id: file_meta.unique_id.clone(),
+                size,
+            },
+        );
+    }
+    // the file is complete with everything around it, so nothing can fail
+    // anymore once it is counted and others are told about it
+    log::info!("Downloaded and saved file: {}", file_path.display());
+    tracing::Span::current()
+        .record("bytes", size)
+        .record("duration_ms", started_at.elapsed().as_millis() as u64);
+    app_state.stats.downloads.fetch_add(1, Ordering::Relaxed);
+    app_state.session_downloads.fetch_add(1, Ordering::Relaxed);
+    app_state.stats.bytes.fetch_add(size, Ordering::Relaxed);
+    cleanup.keep();
+    if !app_state.config.post_download_command.is_empty() {
+        hooks::spawn_post_download_hook(
+            &app_state.config.post_download_command,
+            &file_path,
+            &file_meta.unique_id,
+            message.caption().or(file_name),
+            sidecar::message_link(message).as_deref(),
+            app_state.config.hook_retry_policy(),
+            app_state.config.dead_letter_dir.as_ref().map(PathBuf::from),
+        );
+    }
+    app_state
+        .index
+        .insert(file_meta.unique_id.clone(), file_path.clone());
+    Ok(Some(file_path))
+}
+
+/// With `strict_pipeline`, removes the file of a download that failed halfway
+/// along with its sidecars when dropped, unless `keep` was called.
+struct StrictCleanup {
+    file: Option<PathBuf>,
+    /// Whether this download writes the nfo. Its path only depends on the
+    /// stem, so otherwise it can be the nfo of another file.
+    nfo: bool,
+    /// Blob this download added to the content store.
+    blob: Option<PathBuf>,
+}
+
+impl StrictCleanup {
+    fn moved_to(&mut self, path: &Path) {
+        if let Some(file) = &mut self.file {
+            path.clone_into(file);
+        }
+    }
+
+    fn keep(&mut self) {
+        self.file = None;
+    }
+}
+
+impl Drop for StrictCleanup {
+    fn drop(&mut self) {
+        let Some(file) = self.file.take() else {
+            return;
+        };
+        let sidecars = [
+            Some(sidecar::sidecar_path(&file)),
+            Some(sidecar::context_path(&file)),
+            self.nfo.then(|| sidecar::nfo_path(&file)),
+            self.blob.take(),
+        ];
+        for path in std::iter::once(file).chain(sidecars.into_iter().flatten()) {
+            match std::fs::remove_file(&path) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => log::error!("Failed to remove {}: {e}", path.display()),
+                _ => log::warn!("Removed {} of the failed download", path.display()),
+            }
+        }
+    }
+}
+
+/// What a download added to files shared with other downloads, taken back
+/// with `strict_pipeline` when a later step of it fails.
+#[derive(Default)]
+struct Appended {
+    /// Album and directory of the `album.json` listing the file.
+    album_manifest: Option<(String, PathBuf)>,
+    /// `captions.txt` and the line added to it.
+    caption_log: Option<(PathBuf, String)>,
+    /// Row added to the `csv_export`.
+    csv_export: Option<Vec<u8>>,
+}
+
+impl Appended {
+    async fn undo(self, app_state: &AppState, unique_id: &str) {
+        if let Some((media_group_id, album_dir)) = self.album_manifest {
+            if let Err(e) =
+                remove_from_album_manifest(app_state, &media_group_id, &album_dir, unique_id).await
+            {
+                log::error!("Failed to remove {unique_id} from the album manifest: {e:#}");
+            }
+        }
+        if let Some((path, line)) = self.caption_log {
+            let _guard = app_state.caption_log_lock.lock().await;
+            if let Err(e) = remove_appended(&path, line.as_bytes(), 0).await {
+                log::error!(
+                    "Failed to remove {unique_id} from {}: {e:#}",
+                    path.display()
+                );
+            }
+        }
+        if let (Some(row), Some(csv_export)) = (self.csv_export, &app_state.config.csv_export) {
+            // the header stays as long as others rows are left
+            let _guard = app_state.csv_export_lock.lock().await;
+            if let Err(e) = remove_appended(Path::new(csv_export), &row, 1).await {
+                log::error!("Failed to remove {unique_id} from {csv_export}: {e:#}");
+            }
+        }
+    }
+}
+
+/// Takes the last occurrence of `appended` out of the file at `path` again,
+/// removing the file when no more than `header_lines` are left of it.
+async fn remove_appended(path: &Path, appended: &[u8], header_lines: usize) -> Result<()> {
+    let mut contents = tokio::fs::read(path).await?;
+    let Some(start) = contents
+        .windows(appended.len())
+        .rposition(|window| window == appended)
+    else {
+        return Ok(());
+    };
+    contents.drain(start..start + appended.len());
+    if contents.iter().filter(|&&byte| byte == b'\n').count() <= header_lines {
+        tokio::fs::remove_file(path).await?;
+    } else {
+        tokio::fs::write(path, contents).await?;
+    }
+    Ok(())
+}
+
+/// Flushes a finished download to disk and checks it has `expected` bytes.
+async fn verify_on_disk(path: &Path, expected: Option<u64>) -> Result<()> {
+    let file = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    file.sync_all()
+        .await
+        .with_context(|| format!("Failed to sync {}", path.display()))?;
+    let size = file.metadata().await?.len();
+    if let Some(expected) = expected.filter(|&expected| expected != size) {
+        bail!(
+            "{} has {size} bytes on disk instead of {expected}",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// SHA-256 of the file at `path` as hex.
+async fn sha256_file(path: &Path) -> Result<String> {
+    use tokio::io::AsyncReadExt;
+
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Adds `file` to the `album.json` of the album, rewriting it whole.
+async fn write_album_manifest(
+    app_state: &AppState,
+    media_group_id: &str,
+    album_dir: &Path,
+    file: sidecar::AlbumFile,
+) -> Result<()> {
+    // the snapshot is taken under the same lock as the write, so a slower
+    // write never replaces a newer manifest
+    let _guard = app_state.album_manifest_lock.lock().await;
+    let manifest = {
+        let mut map = app_state.media_group_page_numbers.lock().unwrap();
+        // the album's grace window ended or it was split meanwhile
+        let Some(data) = map.get_mut(media_group_id).filter(|data| {
+            data.folder.as_deref().map(Path::new) == album_dir.file_name().map(Path::new)
+        }) else {
+            return Ok(());
+        };
+        data.files.push(file);
+        data.files.sort_by_key(|file| file.page);
+        data.manifest(media_group_id)
+    };
+    app_state
+        .storage
+        .write_file(
+            &album_dir.join(sidecar::ALBUM_MANIFEST),
+            &serde_json::to_vec_pretty(&manifest)?,
+        )
+        .await
+}
+
+/// Takes the file `unique_id` out of the `album.json` of the album again,
+/// removing the manifest when it was the only file.
+async fn remove_from_album_manifest(
+    app_state: &AppState,
+    media_group_id: &str,
+    album_dir: &Path,
+    unique_id: &str,
+) -> Result<()> {
+    let _guard = app_state.album_manifest_lock.lock().await;
+    let manifest = {
+        let mut map = app_state.media_group_page_numbers.lock().unwrap();
+        let Some(data) = map.get_mut(media_group_id).filter(|data| {
+            data.folder.as_deref().map(Path::new) == album_dir.file_name().map(Path::new)
+        }) else {
+            return Ok(());
+        };
+        data.files.retain(|file| file.unique_id != unique_id);
+        (!data.files.is_empty()).then(|| data.manifest(media_group_id))
+    };
+    let path = album_dir.join(sidecar::ALBUM_MANIFEST);
+    match manifest {
+        Some(manifest) => {
+            app_state
+                .storage
+                .write_file(&path, &serde_json::to_vec_pretty(&manifest)?)
+                .await
+        }
+        None => app_state.storage.discard(&path).await,
+    }
+}
+
+/// Title of an album, decided by the part that arrives first.
+fn album_title(message: &Message, file_name: Option<&str>, media_group_id: &str) -> String {
+    // a forwarded album gets a new media_group_id but keeps the caption of the
+    // original, the file names are whatever the uploader's client made up
+    if message.forward().is_some() {
+        if let Some(title) = message
+            .caption()
+            .or_else(|| message.forward_from_chat().and_then(Chat::title))
+        {
+            return title.to_owned();
+        }
+    }
+    // the parts of an album can be of different types, so the file extension of
+    // the first one isn't part of the title; captions are kept whole, a dot in
+    // them doesn't start an extension
+    let title = match file_name {
+        Some(name) if message.caption() == Some(name) => Some(name),
+        name => name
+            .map(Path::new)
+            .and_then(|p| p.file_stem().and_then(|s| s.to_str())),
+    };
+    title.unwrap_or(media_group_id).to_owned()
+}
+
+/// Title of `chat` usable as a path segment, falling back to the chat id.
+/// Every post carries the current title, so a renamed channel is picked up
+/// with its next post.
+fn channel_name(app_state: &AppState, chat: &Chat) -> String {
+    match chat
+        .title()
+        .map(|title| app_state.config.replace_path_separators(title))
+    {
+        Some(title) if !matches!(title.trim(), "" | "." | "..") => title.trim().to_owned(),
+        _ => chat.id.0.to_string(),
+    }
+}
+
+/// Directory of everything saved from `message` before album folders and
+/// per-file subdirectories: `media_directory[/<channel>][/YYYY/MM/DD]`.
+fn post_dir(app_state: &AppState, message: &Message) -> PathBuf {
+    let mut dir = PathBuf::from(&app_state.config.media_directory);
+    if app_state.config.channel_folders {
+        dir.push(channel_name(app_state, &message.chat));
+    }
+    if app_state.config.date_subdirs {
+        let message_date = message.date.with_timezone(&app_state.config.timezone.0);
+        dir.push(message_date.format("%Y/%m/%d").to_string());
+    }
+    dir
+}
+
+/// Picks a directory name inside `parent` for a new album, adding a numeric
+/// suffix when the title is already taken by another album there, now or
+/// from an earlier run, so unrelated albums never share a folder.
+fn allocate_album_folder(
+    app_state: &AppState,
+    parent: &Path,
+    title: &str,
+    media_group_id: &str,
+) -> String {
+    let title = app_state.config.unicode_normalization.apply(title);
+    let base = match app_state.config.replace_path_separators(&title).trim() {
+        "" | "." | ".." => media_group_id.to_owned(),
+        title => title.to_owned(),
+    };
+
+    let mut taken = app_state.album_folder_names.lock().unwrap();
+    let mut folder = base.clone();
+    let mut suffix = 1;
+    while taken.contains(&parent.join(&folder)) || parent.join(&folder).exists() {
+        suffix += 1;
+        folder = format!("{base} ({suffix})");
+    }
+    taken.insert(parent.join(&folder));
+    folder
+}
+
+fn media_file_name(filename: &str, extension: &str, encrypted: bool) -> String {
+    if encrypted {
+        format!("{filename}.{extension}.{}", crypto::ENCRYPTED_EXTENSION)
+    } else {
+        format!("{filename}.{extension}")
+    }
+}
+
+fn get_filename_and_extension(
+    media: &MediaFile<'_>,
+    media_group_data: Option<&MediaGroupData>,
+    message_date: DateTime<Tz>,
+    channel: &str,
+    seq: Option<u64>,
+    max_title_len: usize,
+    app_state: &AppState,
+) -> (String, String) {
+    let config = &app_state.config;
+    let &MediaFile {
+        file_meta,
+        file_name,
+        sender_file_name,
+        mime_type,
+        default_ext,
+        performer,
+        track_title,
+        ocr,
+        ..
+    } = media;
+    // a caption used as the name says nothing about the file type
+    let ext = if config.trust_file_name_extension {
+        sender_file_name
+            .map(Path::new)
+            .and_then(|p| p.extension().and_then(|e| e.to_str()))
+    } else {
+        mime_type.and_then(inspect::mime_extension)
+    }
+    .unwrap_or(default_ext);
+
+    let unique_id = &file_meta.unique_id;
+    let title = match media_group_data {
+        Some(data) => Cow::Borrowed(data.title.as_str()),
+        None if config.name_sources.is_empty() => Cow::Borrowed(file_name.unwrap_or("")),
+        None => chained_title(&config.name_sources, media),
+    };
+    let title = config.unicode_normalization.apply(&title);
+    let title = truncate_chars(&title, max_title_len);
+    // emoji-only names trip up some filesystems and tools
+    let title = if config.drop_symbol_only_titles && !title.chars().any(char::is_alphanumeric) {
+        ""
+    } else {
+        title
+    };
+    // tagged music is named after its tags, everything else the usual way
+    let filename_template = match (&config.audio_filename_template, performer, track_title) {
+        (Some(template), Some(_), Some(_)) => Some(template),
+        _ => config.filename_template.as_ref(),
+    };
+    let filename = if let Some(filename_template) = filename_template {
+        template::render(filename_template, |token| match token {
+            "title" => Some(title.to_owned()),
+            "unique_id" => Some(unique_id.clone()),
+            "page" => Some(
+                media_group_data
+                    .as_ref()
+                    .map_or_else(String::new, |data| data.page_number.to_string()),
+            ),
+            "date" | "message_date" => Some(message_date.format(&config.date_format).to_string()),
+            "download_date" => Some(
+                chrono::Utc::now()
+                    .with_timezone(&message_date.timezone())
+                    .format(&config.date_format)
+                    .to_string(),
+            ),
+            "channel" => Some(channel.to_owned()),
+            "seq" => seq.map(|seq| seq.to_string()),
+            "performer" => Some(performer.unwrap_or("").to_owned()),
+            "track_title" => Some(track_title.unwrap_or("").to_owned()),
+            "run_id" => Some(app_state.run_id.clone()),
+            "ocr" => Some(ocr.map_or_else(String::new, |ocr| ocr_token(ocr, config))),
+            _ => None,
+        })
+    } else {
+        let title = match title.trim() {
+            "" => config.empty_title_label.as_deref().unwrap_or(""),
+            _ => title,
+        };
+        let prefix = match (title, &media_group_data) {
+            // no caption and no file name, `[]_` would only look broken
+            ("", _) => String::new(),
+            (_, Some(_)) => format!("title:[{title}]_"),
+            (_, None) => format!("[{title}]_"),
+        };
+        let page_part = media_group_data
+            .map_or_else(String::new, |data| format!("{{page:{}}}", data.page_number));
+
+        format!("{prefix}{unique_id}{page_part}")
+    };
+
+    let filename = config.replace_path_separators(&filename);
+
+    (filename, ext.to_owned())
+}
+
+/// The first of `sources` that `media` has a value for. The unique id is part
+/// of every name anyway, so `unique_id` ends the search without a title.
+fn chained_title<'a>(sources: &[TitleSource], media: &MediaFile<'a>) -> Cow<'a, str> {
+    for source in sources {
+        let title = match source {
+            TitleSource::PerformerTitle => match (media.performer, media.track_title) {
+                (Some(performer), Some(track_title)) => {
+                    Some(Cow::Owned(format!("{performer} - {track_title}")))
+                }
+                _ => None,
+            },
+            TitleSource::Caption => media.caption.map(Cow::Borrowed),
+            TitleSource::FileName => media.sender_file_name.map(Cow::Borrowed),
+            TitleSource::UniqueId => break,
+        };
+        if let Some(title) = title.filter(|title| !title.trim().is_empty()) {
+            return title;
+        }
+    }
+    Cow::Borrowed("")
+}
+
+/// The OCR text on one line, cut to `ocr_token_len` characters.
+fn ocr_token(ocr: &str, config: &AppConfig) -> String {
+    let line = ocr.split_whitespace().collect::<Vec<_>>().join(" ");
+    config
+        .unicode_normalization
+        .apply(truncate_chars(&line, config.ocr_token_len))
+        .into_owned()
+}
+
+/// Logs a Bot API call for `debug_http`. `path` is relative to the API server
+/// and has `<token>` in place of the bot token.
+fn log_http<T, E: std::fmt::Display>(
+    method: &str,
+    path: &str,
+    started: Instant,
+    result: &Result<T, E>,
+) {
+    let url = if Path::new(path).is_absolute() {
+        path.to_owned()
+    } else {
+        let api_url = env::var(TELEGRAM_BOT_API_URL_ENV)
+            .unwrap_or_else(|_| "https://api.telegram.org".to_owned());
+        format!("{}/{path}", api_url.trim_end_matches('/'))
+    };
+    let outcome = match result.as_ref().err() {
+        Some(e) => format!("failed: {e}"),
+        None => "ok".to_owned(),
+    };
+    log::info!(
+        "HTTP {method} {url}: {outcome} after {:?}",
+        started.elapsed()
+    );
+}
+
+/// Streams a file returned by `get_file` into `dst`. A local Bot API server
+/// hands out absolute paths that are read directly.
+async fn copy_telegram_file(
+    bot: &impl for<'w> Download<'w, Err = DownloadError>,
+    path: &str,
+    dst: &mut (impl tokio::io::AsyncWrite + Unpin + Send),
+) -> Result<()> {
+    validate_file_path(path)?;
+    if Path::new(path).is_absolute() {
+        let mut absolute_file = tokio::fs::File::open(path).await?;
+        tokio::io::copy(&mut absolute_file, dst).await?;
+    } else {
+        bot.download_file(path, dst)
+            .await
+            .context("Failed to download file")?;
+    }
+    Ok(())
+}
+
+/// With `parallel_chunks`, downloads the file at `path` on the server into
+/// `chunks_path` first and then streams it into `dst` like any other download.
+/// Falls back to a single stream when the chunks can't be downloaded.
+async fn copy_chunked(
+    bot: &impl TelegramBot,
+    downloader: &ChunkedDownloader,
+    path: &str,
+    size: u64,
+    chunks_path: &Path,
+    dst: &mut (impl tokio::io::AsyncWrite + Unpin + Send),
+) -> Result<()> {
+    let result = match downloader.download(path, size, chunks_path).await {
+        Err(e) => {
+            log::warn!("Chunked download failed, downloading as a single stream: {e:#}");
+            copy_telegram_file(bot, path, dst).await
+        }
+        _ => {
+            async {
+                let mut chunks = tokio::fs::File::open(chunks_path).await?;
+                tokio::io::copy(&mut chunks, dst).await?;
+                Ok(())
+            }
+            .await
+        }
+    };
+    if let Err(e) = tokio::fs::remove_file(chunks_path).await {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            log::error!("Failed to remove {}: {e}", chunks_path.display());
+        }
+    }
+    result
+}
+
+/// Checks that `path` is below `root` without relying on the filesystem: only
+/// plain components, no `..`, no absolute parts and no null bytes, whatever the
+/// captions and file names it was built from contained.
+fn ensure_inside(root: &Path, path: &Path) -> Result<()> {
+    let relative = path
+        .strip_prefix(root)
+        .with_context(|| format!("{} is outside of media_directory", path.display()))?;
+    for component in relative.components() {
+        match component {
+            Component::Normal(segment) if !segment.as_encoded_bytes().contains(&0) => {}
+            _ => bail!("{} has an unsafe component {component:?}", path.display()),
+        }
+    }
+    Ok(())
+}
+
+/// Directories of a path sent by a client that are safe to recreate. Roots,
+/// drive letters and `..` are dropped, so `../../etc` ends up as `etc` inside
+/// the download directory.
+fn sender_path_components(dir: &str) -> impl Iterator<Item = &str> {
+    dir.split(['/', '\\'])
+        .map(str::trim)
+        .filter(|component| !matches!(*component, "" | "." | ".."))
+        .filter(|component| !component.contains([':', '\0']))
+}
+
+/// Rejects paths from `get_file` that can't be downloaded, so the failure
+/// names the API response instead of surfacing as a generic download error.
+fn validate_file_path(path: &str) -> Result<()> {
+    if path.trim().is_empty() {
+        bail!("get_file returned an empty file path");
+    }
+    if path.contains('\0')
+        || Path::new(path)
+            .components()
+            .any(|component| component == std::path::Component::ParentDir)
+    {
+        bail!("get_file returned a malformed file path: {path:?}");
+    }
+    Ok(())
+}
+
+/// Cuts `s` to at most `max_chars` characters, never splitting a codepoint.
+fn truncate_chars(s: &str, max_chars: usize) -> &str {
+    s.char_indices()
+        .nth(max_chars)
+        .map_or(s, |(byte_index, _)| &s[..byte_index])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use chrono::TimeZone;
+    use futures::stream::{self, BoxStream};
+
+    use super::*;
+
+    /// Config with the required options and `extra`, saving into a fresh
+    /// directory below the temporary directory.
+    pub(crate) fn test_config(extra: &str) -> AppConfig {
+        let media_directory =
+            env::temp_dir().join(format!("tg_download_bot_test_{}", uuid::Uuid::new_v4()));
+        toml::from_str(&format!(
+            "bot_token = \"1:token\"\nchannel_id = -1001\nmedia_directory = {:?}\n{extra}",
+            media_directory.display().to_string()
+        ))
+        .unwrap()
+    }
+
+    pub(crate) fn test_state(config: AppConfig) -> AppState {
+        let storage = Storage::new(
+            config.storage,
+            config.webdav.as_ref(),
+            &config.media_directory,
+            reqwest::Client::new(),
+            LocalStorage {
+                retry: config.fs_retry_policy(),
+                file_mode: config.file_mode,
+                dir_mode: config.dir_mode,
+            },
+        )
+        .unwrap();
+        AppState {
+            config,
+            media_group_page_numbers: Default::default(),
+            evicted_media_groups: Mutex::new(BoundedMap::new(MAX_EVICTED_MEDIA_GROUPS)),
+            stats: Default::default(),
+            encryption_key: None,
+            index: Default::default(),
+            captioned_media_groups: Mutex::new(BoundedMap::new(MAX_TRACKED_MEDIA_GROUPS)),
+            caption_include: None,
+            included_media_groups: Mutex::new(BoundedMap::new(MAX_TRACKED_MEDIA_GROUPS)),
+            album_folder_names: Default::default(),
+            storage,
+            bandwidth: None,
+            sampler: Mutex::new(StdRng::seed_from_u64(0)),
+            breaker: None,
+            reactor: None,
+            chunked: None,
+            inflight: None,
+            database: None,
+            download_slots: Default::default(),
+            album_qualities: Default::default(),
+            last_update_at: Default::default(),
+            caption_log_lock: Default::default(),
+            csv_export_lock: Default::default(),
+            album_manifest_lock: Default::default(),
+            deletions: Default::default(),
+            paused: Default::default(),
+            gallery_pending: Default::default(),
+            seq: None,
+            dir_limit: None,
+            session_downloads: Default::default(),
+            session_cap_reached: Default::default(),
+            created_dirs: Default::default(),
+            run_id: "run".to_owned(),
+            daily_manifest: Default::default(),
+        }
+    }
+
+    fn file_meta(unique_id: &str) -> FileMeta {
+        serde_json::from_value(serde_json::json!({
+            "file_id": format!("id_{unique_id}"),
+            "file_unique_id": unique_id,
+            "file_size": format!("contents of {unique_id}").len(),
+        }))
+        .unwrap()
+    }
+
+    fn media_file<'a>(media_type: &'static str, file_meta: &'a FileMeta) -> MediaFile<'a> {
+        MediaFile {
+            media_type,
+            quality_key: String::new(),
+            file_meta,
+            file_name: None,
+            caption: None,
+            sender_dir: None,
+            sender_file_name: None,
+            mime_type: None,
+            default_ext: "bin",
+            spoiler: false,
+            performer: None,
+            track_title: None,
+            subdir: None,
+            ocr: None,
+        }
+    }
+
+    /// Post in the watched channel with the fields of `fields` added.
+    fn channel_post(fields: serde_json::Value) -> Message {
+        let mut post = serde_json::json!({
+            "message_id": 1,
+            "date": 1704164645,
+            "chat": { "id": -1001, "type": "channel", "title": "Channel" },
+        });
+        post.as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        serde_json::from_value(post).unwrap()
+    }
+
+    /// The `photo` field of a post with one size of the photo `unique_id`,
+    /// served by [`fake_bot_api`].
+    fn photo(unique_id: &str) -> serde_json::Value {
+        serde_json::json!([{
+            "file_id": format!("id_{unique_id}"),
+            "file_unique_id": unique_id,
+            "file_size": format!("contents of {unique_id}").len(),
+            "width": 800,
+            "height": 600,
+        }])
+    }
+
+    /// `(filename, extension)` of a single file posted at 2024-01-02 03:04:05 UTC.
+    fn filename_and_extension(media: &MediaFile<'_>, app_state: &AppState) -> (String, String) {
+        let message_date = chrono::Utc
+            .with_ymd_and_hms(2024, 1, 2, 3, 4, 5)
+            .unwrap()
+            .with_timezone(&app_state.config.timezone.0);
+        get_filename_and_extension(
+            media,
+            None,
+            message_date,
+            "channel",
+            None,
+            app_state.config.max_title_len,
+            app_state,
+        )
+    }
+
+    /// Answers `getFile` and file downloads like the Bot API, every file
+    /// contains `contents of <unique id>`. Returns the API url.
+    async fn fake_bot_api() -> reqwest::Url {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(serve_bot_api(stream));
+            }
+        });
+        url.parse().unwrap()
+    }
+
+    async fn serve_bot_api(mut stream: tokio::net::TcpStream) -> std::io::Result<()> {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+        let (read, mut write) = stream.split();
+        let mut read = tokio::io::BufReader::new(read);
+        let mut request_line = String::new();
+        read.read_line(&mut request_line).await?;
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            read.read_line(&mut header).await?;
+            if header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+        }
+        let mut body = vec![0; content_length];
+        read.read_exact(&mut body).await?;
+
+        let path = request_line.split(' ').nth(1).unwrap_or_default();
+        let response = if path.starts_with("/file/") {
+            format!("contents of {}", path.rsplit('/').next().unwrap())
+        } else if path.to_ascii_lowercase().ends_with("/getfile") {
+            let request: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let file_id = request["file_id"].as_str().unwrap();
+            let unique_id = file_id.strip_prefix("id_").unwrap();
+            serde_json::json!({
+                "ok": true,
+                "result": {
+                    "file_id": file_id,
+                    "file_unique_id": unique_id,
+                    "file_size": format!("contents of {unique_id}").len(),
+                    "file_path": format!("files/{unique_id}"),
+                },
+            })
+            .to_string()
+        } else {
+            serde_json::json!({ "ok": true, "result": true }).to_string()
+        };
+        write
+            .write_all(
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{response}",
+                    response.len()
+                )
+                .as_bytes(),
+            )
+            .await
+    }
+
+    /// Handles `posts` in order like the dispatcher would, downloading from
+    /// a fake Bot API.
+    async fn handle_posts(app_state: &Arc<AppState>, posts: impl IntoIterator<Item = Message>) {
+        let bot = Arc::new(Bot::new("1:token").set_api_url(fake_bot_api().await));
+        for post in posts {
+            handle_media_message(bot.clone(), post, app_state.clone())
+                .await
+                .unwrap();
+        }
+    }
+
+    /// Photos `AgAD1..=AgAD<count>` posted as media group `media_group_id`, the
+    /// first one captioned `caption`.
+    fn album_posts(media_group_id: &str, count: i32, caption: &str) -> Vec<Message> {
+        (1..=count)
+            .map(|i| {
+                let mut post = serde_json::json!({
+                    "message_id": i,
+                    "media_group_id": media_group_id,
+                    "photo": photo(&format!("AgAD{i}")),
+                });
+                if i == 1 {
+                    post["caption"] = caption.into();
+                }
+                channel_post(post)
+            })
+            .collect()
+    }
+
+    /// The `video` field of a post with the video `unique_id` sent as `file_name`.
+    fn video(unique_id: &str, file_name: &str) -> serde_json::Value {
+        serde_json::json!({
+            "file_id": format!("id_{unique_id}"),
+            "file_unique_id": unique_id,
+            "file_size": format!("contents of {unique_id}").len(),
+            "file_name": file_name,
+            "mime_type": "video/mp4",
+            "width": 1280,
+            "height": 720,
+            "duration": 10,
+        })
+    }
+
+    /// A photo of media group `media_group_id`.
+    fn album_item(media_group_id: &str, caption: Option<&str>) -> Message {
+        let mut post = serde_json::json!({
+            "media_group_id": media_group_id,
+            "photo": photo("AgAD"),
+        });
+        if let Some(caption) = caption {
+            post["caption"] = caption.into();
+        }
+        channel_post(post)
+    }
+
+    /// Names of the files below `root`, relative to it, sorted.
+    fn saved_files(root: &str) -> Vec<String> {
+        let mut files = Vec::new();
+        let mut pending = vec![PathBuf::from(root)];
+        while let Some(dir) = pending.pop() {
+            let Result::Ok(entries) = std::fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in entries {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    pending.push(path);
+                } else {
+                    let relative = path.strip_prefix(root).unwrap();
+                    files.push(relative.display().to_string());
+                }
+            }
+        }
+        files.sort();
+        files
+    }
+
+    /// Serves every file with the same contents and counts the requests.
+    #[derive(Default)]
+    struct FakeDownloader {
+        requests: AtomicUsize,
+    }
+
+    impl<'w> Download<'w> for FakeDownloader {
+        type Err = DownloadError;
+        type Fut = futures::future::BoxFuture<'w, Result<(), DownloadError>>;
+
+        fn download_file(
+            &self,
+            _path: &str,
+            destination: &'w mut (dyn tokio::io::AsyncWrite + Unpin + Send),
+        ) -> Self::Fut {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move {
+                use tokio::io::AsyncWriteExt;
+                destination.write_all(b"contents").await?;
+                Result::Ok(())
+            })
+        }
+
+        type StreamErr = reqwest::Error;
+        type Stream = BoxStream<'static, Result<tokio_util::bytes::Bytes, reqwest::Error>>;
+
+        fn download_file_stream(&self, _path: &str) -> Self::Stream {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            Box::pin(stream::once(async {
+                Result::Ok(tokio_util::bytes::Bytes::from_static(b"contents"))
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_file_path_is_not_downloaded() {
+        let downloader = FakeDownloader::default();
+        for path in ["", "  "] {
+            let mut dst = Vec::new();
+            let result = copy_telegram_file(&downloader, path, &mut dst).await;
+            assert!(result.is_err(), "{path:?} was accepted");
+            assert!(dst.is_empty());
+        }
+        assert_eq!(downloader.requests.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn malformed_file_path_is_not_downloaded() {
+        let downloader = FakeDownloader::default();
+        for path in ["documents/../../etc/passwd", "documents/file\0.jpg"] {
+            let mut dst = Vec::new();
+            assert!(copy_telegram_file(&downloader, path, &mut dst)
+                .await
+                .is_err());
+            assert!(dst.is_empty());
+        }
+        assert_eq!(downloader.requests.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn valid_file_path_is_downloaded() {
+        let downloader = FakeDownloader::default();
+        let mut dst = Vec::new();
+        copy_telegram_file(&downloader, "documents/file_1.jpg", &mut dst)
+            .await
+            .unwrap();
+        assert_eq!(dst, b"contents");
+    }
+
+    #[test]
+    fn ensure_inside_accepts_nested_paths() {
+        let root = Path::new("media");
+        ensure_inside(root, Path::new("media/channel/album/1.jpg")).unwrap();
+    }
+
+    #[test]
+    fn ensure_inside_rejects_escapes() {
+        let root = Path::new("media");
+        for path in [
+            "media/../etc/passwd",
+            "media/channel/../../etc",
+            "/etc/passwd",
+            "other/file.jpg",
+            "media/file\0.jpg",
+        ] {
+            assert!(ensure_inside(root, Path::new(path)).is_err(), "{path:?}");
+        }
+    }
+
+    #[test]
+    fn sender_path_components_drops_unsafe_parts() {
+        let components = |dir| sender_path_components(dir).collect::<Vec<_>>();
+        assert_eq!(components("photos/2024/trip"), ["photos", "2024", "trip"]);
+        assert_eq!(components("../../etc"), ["etc"]);
+        assert_eq!(components("/etc/passwd"), ["etc", "passwd"]);
+        assert_eq!(components("C:\\Users\\me"), ["Users", "me"]);
+        assert_eq!(components("a/./b\\..\\c"), ["a", "b", "c"]);
+        assert_eq!(components("bad\0name/ok"), ["ok"]);
+    }
+
+    #[test]
+    fn sender_path_components_never_contain_separators() {
+        for component in sender_path_components("a/b\\c/ d /../e:f") {
+            assert!(!component.contains(['/', '\\', ':', '\0']), "{component:?}");
+            assert!(!matches!(component, "" | "." | ".."));
+        }
+    }
+
+    #[test]
+    fn truncate_chars_counts_chars_not_bytes() {
+        assert_eq!(truncate_chars("hello", 3), "hel");
+        assert_eq!(truncate_chars("hello", 5), "hello");
+        assert_eq!(truncate_chars("hello", 10), "hello");
+        assert_eq!(truncate_chars("", 3), "");
+        assert_eq!(truncate_chars("привет", 3), "при");
+        assert_eq!(truncate_chars("日本語のキャプション", 4), "日本語の");
+    }
+
+    #[test]
+    fn truncate_chars_never_splits_emoji() {
+        assert_eq!(truncate_chars("🔥🎉🐳", 2), "🔥🎉");
+        assert_eq!(truncate_chars("a🔥b", 2), "a🔥");
+        for max_chars in 0..8 {
+            let truncated = truncate_chars("añ🔥日é🐳", max_chars);
+            assert_eq!(truncated.chars().count(), max_chars.min(6));
+        }
+    }
+
+    #[test]
+    fn message_and_download_dates_use_date_format() {
+        let app_state = test_state(test_config(
+            "filename_template = \"{message_date}_{download_date}_{unique_id}\"\n\
+             date_format = \"%Y%m%d\"",
+        ));
+        let meta = file_meta("AgAD");
+        let (filename, _) = filename_and_extension(&media_file("photo", &meta), &app_state);
+        let today = chrono::Utc::now().format("%Y%m%d").to_string();
+        assert_eq!(filename, format!("20240102_{today}_AgAD"));
+    }
+
+    #[test]
+    fn caption_is_preferred_by_default() {
+        let config = test_config("");
+        for media_type in ["video", "audio"] {
+            assert_eq!(
+                config.media_name(media_type, Some("caption"), Some("name.mp4")),
+                Some("caption")
+            );
+            assert_eq!(
+                config.media_name(media_type, None, Some("name.mp4")),
+                Some("name.mp4")
+            );
+        }
+    }
+
+    #[test]
+    fn name_source_prefers_the_file_name_per_media_type() {
+        let config = test_config("[name_source]\nvideo = \"file_name_first\"");
+        assert_eq!(
+            config.media_name("video", Some("caption"), Some("name.mp4")),
+            Some("name.mp4")
+        );
+        assert_eq!(
+            config.media_name("video", Some("caption"), None),
+            Some("caption")
+        );
+        assert_eq!(
+            config.media_name("audio", Some("caption"), Some("name.mp3")),
+            Some("caption")
+        );
+        assert_eq!(config.media_name("audio", None, None), None);
+    }
+
+    #[test]
+    fn path_separators_use_the_configured_replacement() {
+        let config = test_config("");
+        assert_eq!(config.replace_path_separators("a/b\\c"), "a-b-c");
+        let config = test_config("path_separator_replacement = \"_\"");
+        assert_eq!(config.replace_path_separators("AC/DC\\live"), "AC_DC_live");
+    }
+
+    #[test]
+    fn titles_with_separators_become_one_segment() {
+        let app_state = test_state(test_config("path_separator_replacement = \"+\""));
+        let meta = file_meta("AgAD");
+        let media = MediaFile {
+            file_name: Some("../etc/passwd"),
+            ..media_file("video", &meta)
+        };
+        let (filename, _) = filename_and_extension(&media, &app_state);
+        assert_eq!(filename, "[..+etc+passwd]_AgAD");
+    }
+
+    #[test]
+    fn forwarded_albums_are_named_after_the_original_caption() {
+        let forwarded = |fields: serde_json::Value| {
+            let mut post = serde_json::json!({
+                "media_group_id": "2",
+                "photo": photo("AgAD"),
+                "forward_from_chat": { "id": -1002, "type": "channel", "title": "Origin" },
+                "forward_from_message_id": 7,
+                "forward_date": 1704000000,
+            });
+            post.as_object_mut()
+                .unwrap()
+                .extend(fields.as_object().unwrap().clone());
+            channel_post(post)
+        };
+        let message = forwarded(serde_json::json!({ "caption": "Original album" }));
+        assert_eq!(
+            album_title(&message, Some("IMG_0001.jpg"), "2"),
+            "Original album"
+        );
+        // without a caption the origin says more than a made up file name
+        let message = forwarded(serde_json::json!({}));
+        assert_eq!(album_title(&message, Some("IMG_0001.jpg"), "2"), "Origin");
+    }
+
+    #[test]
+    fn albums_posted_directly_keep_the_usual_title() {
+        let message = channel_post(serde_json::json!({
+            "media_group_id": "2",
+            "photo": photo("AgAD"),
+        }));
+        assert_eq!(album_title(&message, Some("IMG_0001.jpg"), "2"), "IMG_0001");
+        assert_eq!(album_title(&message, None, "2"), "2");
+    }
+
+    #[test]
+    fn path_excess_checks_the_file_name_length() {
+        let config = test_config("max_file_name_bytes = 20");
+        let dir = Path::new(&config.media_directory);
+        assert_eq!(config.path_excess(&dir.join("short_AgAD.jpg")), 0);
+        assert_eq!(config.path_excess(&dir.join("a_bit_longer_AgAD.jpg")), 1);
+        // bytes, not characters
+        assert_eq!(config.path_excess(&dir.join("ééééé_AgAD.jpg")), 0);
+        assert_eq!(config.path_excess(&dir.join("éééééé_AgAD.jpg")), 1);
+    }
+
+    #[test]
+    fn path_excess_checks_the_whole_path_length() {
+        let config = test_config("max_path_len = 30");
+        let path = Path::new("/media/channel/album/title_AgAD.jpg");
+        assert_eq!(config.path_excess(path), path.as_os_str().len() - 30);
+        assert_eq!(config.path_excess(Path::new("/media/AgAD.jpg")), 0);
+    }
+
+    #[test]
+    fn shortened_titles_keep_the_unique_id_and_extension() {
+        let app_state = test_state(test_config(""));
+        let meta = file_meta("AgAD");
+        let media = MediaFile {
+            file_name: Some("a very long caption"),
+            sender_file_name: Some("clip.mp4"),
+            ..media_file("video", &meta)
+        };
+        let message_date = chrono::Utc::now().with_timezone(&app_state.config.timezone.0);
+        let (filename, extension) =
+            get_filename_and_extension(&media, None, message_date, "channel", None, 6, &app_state);
+        assert_eq!(filename, "[a very]_AgAD");
+        assert_eq!(extension, "mp4");
+    }
+
+    #[test]
+    fn default_extensions_can_differ_per_channel() {
+        let config = test_config(
+            "[default_extensions]\n\
+             audio = \"ogg\"\n\
+             [[channels]]\n\
+             id = -1002\n\
+             default_extensions = { audio = \"flac\" }\n\
+             [[channels]]\n\
+             id = -1003\n\
+             default_extensions = { audio = \"mp3\" }",
+        );
+        assert_eq!(config.default_extension(-1002, "audio", "m4a"), "flac");
+        assert_eq!(config.default_extension(-1003, "audio", "m4a"), "mp3");
+        assert_eq!(config.default_extension(-1001, "audio", "m4a"), "ogg");
+        assert_eq!(config.default_extension(-1002, "video", "mp4"), "mp4");
+    }
+
+    fn album(title: &str, page_number: u32) -> MediaGroupData {
+        MediaGroupData {
+            page_number,
+            title: title.to_owned(),
+            folder: None,
+            unique_ids: Vec::new(),
+            last_part_at: Instant::now(),
+            files: Vec::new(),
+            parts: Vec::new(),
+            next_get_file_at: Instant::now(),
+            captions: Default::default(),
+            reacted: false,
+        }
+    }
+
+    #[test]
+    fn mixed_albums_share_the_title_but_not_the_extension() {
+        let app_state = test_state(test_config(""));
+        let first = channel_post(serde_json::json!({
+            "media_group_id": "2",
+            "photo": photo("AgAD1"),
+        }));
+        // the title comes from the first part, without its extension
+        let title = album_title(&first, Some("holiday.jpg"), "2");
+        assert_eq!(title, "holiday");
+
+        let message_date = chrono::Utc::now().with_timezone(&app_state.config.timezone.0);
+        let photo_meta = file_meta("AgAD1");
+        let photo = MediaFile {
+            default_ext: "jpg",
+            ..media_file("photo", &photo_meta)
+        };
+        let video_meta = file_meta("AgAD2");
+        let video = MediaFile {
+            sender_file_name: Some("holiday.mp4"),
+            ..media_file("video", &video_meta)
+        };
+        let name = |media: &MediaFile<'_>, page_number| {
+            let data = album(&title, page_number);
+            get_filename_and_extension(
+                media,
+                Some(&data),
+                message_date,
+                "channel",
+                None,
+                app_state.config.max_title_len,
+                &app_state,
+            )
+        };
+        assert_eq!(
+            name(&photo, 1),
+            ("title:[holiday]_AgAD1{page:1}".to_owned(), "jpg".to_owned())
+        );
+        assert_eq!(
+            name(&video, 2),
+            ("title:[holiday]_AgAD2{page:2}".to_owned(), "mp4".to_owned())
+        );
+    }
+
+    #[test]
+    fn albums_with_the_same_title_get_their_own_folder() {
+        let app_state = test_state(test_config(""));
+        let parent = PathBuf::from(&app_state.config.media_directory);
+        assert_eq!(
+            allocate_album_folder(&app_state, &parent, "Holiday", "1"),
+            "Holiday"
+        );
+        assert_eq!(
+            allocate_album_folder(&app_state, &parent, "Holiday", "2"),
+            "Holiday (2)"
+        );
+        assert_eq!(
+            allocate_album_folder(&app_state, &parent, "Holiday", "3"),
+            "Holiday (3)"
+        );
+        assert_eq!(allocate_album_folder(&app_state, &parent, "..", "4"), "4");
+    }
+
+    #[test]
+    fn mismatched_file_name_extensions_can_be_ignored() {
+        let meta = file_meta("AgAD");
+        let media = MediaFile {
+            sender_file_name: Some("clip.txt"),
+            mime_type: Some("video/mp4"),
+            default_ext: "mp4",
+            ..media_file("video", &meta)
+        };
+        let trusting = test_state(test_config(""));
+        assert_eq!(filename_and_extension(&media, &trusting).1, "txt");
+        let distrusting = test_state(test_config("trust_file_name_extension = false"));
+        assert_eq!(filename_and_extension(&media, &distrusting).1, "mp4");
+        let unknown_mime = MediaFile {
+            mime_type: Some("application/x-unknown"),
+            ..media
+        };
+        assert_eq!(filename_and_extension(&unknown_mime, &distrusting).1, "mp4");
+    }
+
+    #[test]
+    fn tagged_audio_is_named_after_its_tags() {
+        let app_state = test_state(test_config(
+            "filename_template = \"{title}_{unique_id}\"\n\
+             audio_filename_template = \"{performer} - {track_title}_{unique_id}\"",
+        ));
+        let meta = file_meta("AgAD");
+        let tagged = MediaFile {
+            file_name: Some("track01.mp3"),
+            performer: Some("Artist"),
+            track_title: Some("Song"),
+            ..media_file("audio", &meta)
+        };
+        assert_eq!(
+            filename_and_extension(&tagged, &app_state).0,
+            "Artist - Song_AgAD"
+        );
+        // both tags are needed, otherwise the usual template is used
+        let untagged = MediaFile {
+            track_title: None,
+            ..tagged
+        };
+        assert_eq!(
+            filename_and_extension(&untagged, &app_state).0,
+            "track01.mp3_AgAD"
+        );
+    }
+
+    #[test]
+    fn unicode_normalization_forms() {
+        let (composed, decomposed) = ("Caf\u{e9}", "Cafe\u{301}");
+        assert_eq!(UnicodeNormalization::Nfc.apply(decomposed), composed);
+        assert_eq!(UnicodeNormalization::Nfc.apply(composed), composed);
+        assert_eq!(UnicodeNormalization::Nfd.apply(composed), decomposed);
+        assert_eq!(UnicodeNormalization::None.apply(decomposed), decomposed);
+    }
+
+    #[test]
+    fn composed_and_decomposed_captions_give_the_same_name() {
+        let app_state = test_state(test_config(""));
+        let meta = file_meta("AgAD");
+        let name = |caption| {
+            let media = MediaFile {
+                file_name: Some(caption),
+                ..media_file("video", &meta)
+            };
+            filename_and_extension(&media, &app_state).0
+        };
+        assert_eq!(name("Caf\u{e9}"), name("Cafe\u{301}"));
+        assert_eq!(name("Caf\u{e9}"), "[Caf\u{e9}]_AgAD");
+    }
+
+    #[tokio::test]
+    async fn single_photo_is_saved() {
+        let app_state = Arc::new(test_state(test_config("")));
+        handle_posts(
+            &app_state,
+            [channel_post(serde_json::json!({ "photo": photo("AgAD") }))],
+        )
+        .await;
+        let dir = &app_state.config.media_directory;
+        assert_eq!(saved_files(dir), ["AgAD.jpg"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn oversized_media_groups_continue_their_numbering() {
+        let app_state = Arc::new(test_state(test_config(
+            "album_folders = true\nmax_media_group_items = 10",
+        )));
+        handle_posts(&app_state, album_posts("2", 12, "Trip")).await;
+        let dir = &app_state.config.media_directory;
+        let mut expected: Vec<String> = (1..=12).map(|page| format!("Trip/{page}.jpg")).collect();
+        expected.sort();
+        assert_eq!(saved_files(dir), expected);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn oversized_media_groups_can_be_split() {
+        let app_state = Arc::new(test_state(test_config(
+            "album_folders = true\n\
+             max_media_group_items = 10\n\
+             oversized_media_groups = \"split\"",
+        )));
+        handle_posts(&app_state, album_posts("2", 12, "Trip")).await;
+        let dir = &app_state.config.media_directory;
+        let mut expected: Vec<String> = (1..=10)
+            .map(|page| format!("Trip/{page}.jpg"))
+            .chain((1..=2).map(|page| format!("Trip (2)/{page}.jpg")))
+            .collect();
+        expected.sort();
+        assert_eq!(saved_files(dir), expected);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn hash_shards_are_prefixes_of_the_hash() {
+        let sharding = HashSharding { depth: 3, width: 2 };
+        let shards = sharding.shard("AgAD");
+        let hash = hex::encode(Sha256::digest(b"AgAD"));
+        assert_eq!(shards, [&hash[0..2], &hash[2..4], &hash[4..6]]);
+        // the same id always ends up in the same directory
+        assert_eq!(sharding.shard("AgAD"), shards);
+        assert_eq!(
+            HashSharding {
+                depth: 1,
+                width: 64
+            }
+            .shard("AgAD"),
+            [hash]
+        );
+    }
+
+    #[test]
+    fn hash_sharding_longer_than_the_hash_is_rejected() {
+        let sharding = |depth, width| {
+            validate_config(&test_config(&format!(
+                "[hash_sharding]\ndepth = {depth}\nwidth = {width}"
+            )))
+        };
+        sharding(2, 2).unwrap();
+        sharding(8, 8).unwrap();
+        assert!(sharding(9, 8).is_err());
+        assert!(sharding(0, 2).is_err());
+        assert!(sharding(2, 0).is_err());
+        assert!(sharding(1_u64 << 62, 4).is_err());
+    }
+
+    #[tokio::test]
+    async fn all_photo_sizes_can_be_saved() {
+        let size = |unique_id: &str, width| {
+            serde_json::json!({
+                "file_id": format!("id_{unique_id}"),
+                "file_unique_id": unique_id,
+                "file_size": format!("contents of {unique_id}").len(),
+                "width": width,
+                "height": width,
+            })
+        };
+        let post = || {
+            channel_post(serde_json::json!({
+                "photo": [size("small", 320), size("medium", 800), size("largest", 1280)],
+            }))
+        };
+
+        let app_state = Arc::new(test_state(test_config("")));
+        handle_posts(&app_state, [post()]).await;
+        let dir = &app_state.config.media_directory;
+        assert_eq!(saved_files(dir), ["largest.jpg"]);
+        std::fs::remove_dir_all(dir).unwrap();
+
+        let app_state = Arc::new(test_state(test_config("save_all_photo_sizes = true")));
+        handle_posts(&app_state, [post()]).await;
+        let dir = &app_state.config.media_directory;
+        assert_eq!(
+            saved_files(dir),
+            ["largest.jpg", "largest_w320.jpg", "largest_w800.jpg"]
+        );
+        let contents = std::fs::read_to_string(Path::new(dir).join("largest_w320.jpg")).unwrap();
+        assert_eq!(contents, "contents of small");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn emoji_only_captions_can_fall_back_to_a_label() {
+        let meta = file_meta("AgAD");
+        let media = MediaFile {
+            file_name: Some("🔥🎉 !"),
+            ..media_file("video", &meta)
+        };
+        let kept = test_state(test_config(""));
+        assert_eq!(filename_and_extension(&media, &kept).0, "[🔥🎉 !]_AgAD");
+        let dropped = test_state(test_config("drop_symbol_only_titles = true"));
+        assert_eq!(filename_and_extension(&media, &dropped).0, "AgAD");
+        let labelled = test_state(test_config(
+            "drop_symbol_only_titles = true\nempty_title_label = \"untitled\"",
+        ));
+        assert_eq!(
+            filename_and_extension(&media, &labelled).0,
+            "[untitled]_AgAD"
+        );
+        let worded = MediaFile {
+            file_name: Some("🔥 party"),
+            ..media
+        };
+        assert_eq!(
+            filename_and_extension(&worded, &dropped).0,
+            "[🔥 party]_AgAD"
+        );
+    }
+
+    #[tokio::test]
+    async fn forwarded_posts_can_be_skipped() {
+        let forwarded = || {
+            channel_post(serde_json::json!({
+                "photo": photo("AgAD"),
+                "forward_from_chat": { "id": -1002, "type": "channel", "title": "Origin" },
+                "forward_from_message_id": 7,
+                "forward_date": 1704000000,
+            }))
+        };
+
+        let app_state = Arc::new(test_state(test_config("skip_forwards = true")));
+        handle_posts(&app_state, [forwarded()]).await;
+        assert!(saved_files(&app_state.config.media_directory).is_empty());
+
+        let app_state = Arc::new(test_state(test_config("")));
+        handle_posts(&app_state, [forwarded()]).await;
+        let dir = &app_state.config.media_directory;
+        assert_eq!(saved_files(dir), ["AgAD.jpg"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn size_tiers_start_at_their_min_bytes() {
+        let routing = SizeTierRouting {
+            tiers: vec![
+                SizeTier {
+                    min_bytes: 10_000_000,
+                    subdir: "large".to_owned(),
+                },
+                SizeTier {
+                    min_bytes: 1,
+                    subdir: "small".to_owned(),
+                },
+                SizeTier {
+                    min_bytes: 1_000_000,
+                    subdir: "medium".to_owned(),
+                },
+            ],
+            unknown: Some("unknown".to_owned()),
+        };
+        assert_eq!(routing.subdir(0), Some("unknown"));
+        assert_eq!(routing.subdir(1), Some("small"));
+        assert_eq!(routing.subdir(999_999), Some("small"));
+        assert_eq!(routing.subdir(1_000_000), Some("medium"));
+        assert_eq!(routing.subdir(9_999_999), Some("medium"));
+        assert_eq!(routing.subdir(10_000_000), Some("large"));
+        assert_eq!(routing.subdir(u32::MAX), Some("large"));
+    }
+
+    #[test]
+    fn sizes_below_every_tier_have_no_subdir() {
+        let routing = SizeTierRouting {
+            tiers: vec![SizeTier {
+                min_bytes: 1_000_000,
+                subdir: "large".to_owned(),
+            }],
+            unknown: None,
+        };
+        assert_eq!(routing.subdir(999_999), None);
+        assert_eq!(routing.subdir(0), None);
+    }
+
+    #[tokio::test]
+    async fn albums_stop_after_max_items_per_album() {
+        let app_state = Arc::new(test_state(test_config(
+            "album_folders = true\nmax_items_per_album = 2",
+        )));
+        let mut posts = album_posts("2", 4, "Trip");
+        // out of order, and the first item handled twice
+        posts.swap(0, 2);
+        posts.insert(1, posts[0].clone());
+        handle_posts(&app_state, posts).await;
+        // named after the media group, the captioned item was past the limit
+        let dir = &app_state.config.media_directory;
+        assert_eq!(saved_files(dir), ["2/1.jpg", "2/2.jpg"]);
+        let first = std::fs::read_to_string(Path::new(dir).join("2/1.jpg")).unwrap();
+        assert_eq!(first, "contents of AgAD3");
+        let second = std::fs::read_to_string(Path::new(dir).join("2/2.jpg")).unwrap();
+        assert_eq!(second, "contents of AgAD2");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn malformed_api_urls_are_errors() {
+        for url in [
+            "",
+            "localhost:8081",
+            "not a url",
+            "mailto:bot@example.com",
+            "ftp://example.com/",
+        ] {
+            assert!(parse_api_url(url.into()).is_err(), "{url:?}");
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStringExt;
+            let url = std::ffi::OsString::from_vec(b"http://\xff".to_vec());
+            assert!(parse_api_url(url).is_err());
+        }
+    }
+
+    #[test]
+    fn api_urls_are_parsed() {
+        let url = parse_api_url("http://localhost:8081".into()).unwrap();
+        assert_eq!(url.as_str(), "http://localhost:8081/");
+        let bot = Bot::new("1:token").set_api_url(url);
+        assert_eq!(bot.api_url().as_str(), "http://localhost:8081/");
+    }
+
+    #[test]
+    fn chained_title_takes_the_first_source_present() {
+        use TitleSource::*;
+        let meta = file_meta("AgAD");
+        let media = MediaFile {
+            caption: Some("Caption"),
+            sender_file_name: Some("file.mp3"),
+            performer: Some("Artist"),
+            track_title: Some("Song"),
+            ..media_file("audio", &meta)
+        };
+        assert_eq!(
+            chained_title(&[PerformerTitle, Caption, FileName], &media),
+            "Artist - Song"
+        );
+        assert_eq!(
+            chained_title(&[Caption, PerformerTitle, FileName], &media),
+            "Caption"
+        );
+        assert_eq!(
+            chained_title(&[FileName, Caption, PerformerTitle], &media),
+            "file.mp3"
+        );
+        assert_eq!(chained_title(&[UniqueId, Caption], &media), "");
+    }
+
+    #[test]
+    fn chained_title_skips_empty_sources() {
+        use TitleSource::*;
+        let meta = file_meta("AgAD");
+        let media = MediaFile {
+            caption: Some("  "),
+            sender_file_name: Some(""),
+            performer: Some("Artist"),
+            ..media_file("audio", &meta)
+        };
+        // a performer without a title isn't enough either
+        assert_eq!(
+            chained_title(&[Caption, FileName, PerformerTitle], &media),
+            ""
+        );
+        let media = MediaFile {
+            sender_file_name: Some("file.mp3"),
+            ..media
+        };
+        assert_eq!(
+            chained_title(&[Caption, PerformerTitle, FileName], &media),
+            "file.mp3"
+        );
+    }
+
+    #[test]
+    fn chained_title_falls_back_to_the_unique_id() {
+        use TitleSource::*;
+        let meta = file_meta("AgAD");
+        let media = media_file("photo", &meta);
+        assert_eq!(
+            chained_title(&[PerformerTitle, Caption, FileName], &media),
+            ""
+        );
+        let app_state = test_state(test_config(
+            "name_sources = [\"caption\", \"file_name\", \"unique_id\"]",
+        ));
+        assert_eq!(filename_and_extension(&media, &app_state).0, "AgAD");
+        let captioned = MediaFile {
+            caption: Some("Caption"),
+            ..media
+        };
+        assert_eq!(
+            filename_and_extension(&captioned, &app_state).0,
+            "[Caption]_AgAD"
+        );
+    }
+
+    #[test]
+    fn parallel_chunks_are_not_rate_limited() {
+        validate_config(&test_config("parallel_chunks = 4")).unwrap();
+        validate_config(&test_config("max_bytes_per_sec = 1048576")).unwrap();
+        assert!(validate_config(&test_config(
+            "parallel_chunks = 4\nmax_bytes_per_sec = 1048576"
+        ))
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn albums_continue_after_album_grace_secs() {
+        let app_state = Arc::new(test_state(test_config(
+            "album_folders = true\nalbum_grace_secs = 0",
+        )));
+        // every part arrives after the album was evicted
+        handle_posts(&app_state, album_posts("2", 3, "Trip")).await;
+        let dir = &app_state.config.media_directory;
+        assert_eq!(saved_files(dir), ["Trip/1.jpg", "Trip/2.jpg", "Trip/3.jpg"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn album_items_go_with_an_earlier_caption() {
+        let app_state = test_state(test_config("require_caption = true"));
+        assert!(!has_group_caption(&album_item("1", None), &app_state));
+        assert!(has_group_caption(
+            &album_item("2", Some("Trip")),
+            &app_state
+        ));
+        assert!(has_group_caption(&album_item("2", None), &app_state));
+
+        // only the most recent albums are remembered
+        for media_group_id in 0..MAX_TRACKED_MEDIA_GROUPS {
+            let media_group_id = format!("other{media_group_id}");
+            app_state
+                .captioned_media_groups
+                .lock()
+                .unwrap()
+                .insert(media_group_id, ());
+        }
+        assert!(!has_group_caption(&album_item("2", None), &app_state));
+    }
+
+    #[test]
+    fn album_items_go_with_an_earlier_included_caption() {
+        let app_state = test_state(test_config(""));
+        let patterns = RegexSet::new(["(?i)holiday"]).unwrap();
+        let matches = |message| group_caption_matches(&message, &patterns, &app_state);
+        assert!(!matches(album_item("1", Some("Work"))));
+        assert!(!matches(album_item("1", None)));
+        assert!(matches(album_item("2", Some("Holiday"))));
+        assert!(matches(album_item("2", None)));
+
+        // only the most recent albums are remembered
+        for media_group_id in 0..MAX_TRACKED_MEDIA_GROUPS {
+            let media_group_id = format!("other{media_group_id}");
+            app_state
+                .included_media_groups
+                .lock()
+                .unwrap()
+                .insert(media_group_id, ());
+        }
+        assert!(!matches(album_item("2", None)));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn post_download_hook_gets_the_caption() {
+        let app_state = Arc::new(test_state(test_config(
+            "post_download_command = [\"sh\", \"-c\", \"printf %s \\\"$1\\\" > \\\"$2\\\"\", \
+             \"hook\", \"{caption}\", \"{path}.caption\"]\n\
+             [name_source]\n\
+             video = \"file_name_first\"",
+        )));
+        handle_posts(
+            &app_state,
+            [channel_post(serde_json::json!({
+                "video": video("AgAD", "clip.mp4"),
+                "caption": "Real caption",
+            }))],
+        )
+        .await;
+        // named after the file name, the hook still gets the caption
+        let dir = Path::new(&app_state.config.media_directory);
+        let caption_path = dir.join("[clip.mp4]_AgAD.mp4.caption");
+        // the hook runs in the background
+        for _ in 0..100 {
+            if caption_path.exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(
+            std::fs::read_to_string(&caption_path).unwrap(),
+            "Real caption"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn scan_command_gets_the_caption() {
+        let extra = |quarantine_dir: &Path| {
+            format!(
+                "scan_command = [\"sh\", \"-c\", \"[ \\\"$1\\\" = 'Real caption' ]\", \
+                 \"scan\", \"{{caption}}\"]\n\
+                 quarantine_dir = {:?}\n\
+                 [name_source]\n\
+                 video = \"file_name_first\"",
+                quarantine_dir.display().to_string()
+            )
+        };
+        let quarantine_dir = env::temp_dir().join(format!(
+            "tg_download_bot_test_quarantine_{}",
+            uuid::Uuid::new_v4()
+        ));
+        let app_state = Arc::new(test_state(test_config(&extra(&quarantine_dir))));
+        handle_posts(
+            &app_state,
+            [channel_post(serde_json::json!({
+                "video": video("AgAD", "clip.mp4"),
+                "caption": "Real caption",
+            }))],
+        )
+        .await;
+        // the scanner only passes the file when it got the caption
+        let dir = &app_state.config.media_directory;
+        assert_eq!(saved_files(dir), ["[clip.mp4]_AgAD.mp4"]);
+        assert!(!quarantine_dir.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn config_paths_are_split_on_commas_only() {
+        assert_eq!(
+            config_paths(r"C:\bot\base.toml,,D:\prod.toml").collect::<Vec<_>>(),
+            [r"C:\bot\base.toml", r"D:\prod.toml"]
+        );
+        assert_eq!(
+            config_paths("/etc/bot/base.toml").collect::<Vec<_>>(),
+            ["/etc/bot/base.toml"]
+        );
+    }
+
+    #[test]
+    fn strict_cleanup_keeps_the_nfo_it_did_not_write() {
+        let dir = env::temp_dir().join(format!("tg_download_bot_test_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (photo, video) = (dir.join("Trip.jpg"), dir.join("Trip.mp4"));
+        for path in [&photo, &video, &sidecar::nfo_path(&video)] {
+            std::fs::write(path, "").unwrap();
+        }
+        drop(StrictCleanup {
+            file: Some(photo.clone()),
+            nfo: false,
+            blob: None,
+        });
+        assert!(!photo.exists());
+        assert!(sidecar::nfo_path(&video).exists());
+
+        drop(StrictCleanup {
+            file: Some(video.clone()),
+            nfo: true,
+            blob: None,
+        });
+        assert!(!video.exists());
+        assert!(!sidecar::nfo_path(&video).exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn strict_pipeline_counts_only_complete_downloads() {
+        // a directory can't be appended to, so the csv_export step fails
+        let app_state = Arc::new(test_state(test_config(&format!(
+            "strict_pipeline = true\nwrite_metadata = true\ncaption_log = true\n\
+             csv_export = {:?}",
+            env::temp_dir().display().to_string()
+        ))));
+        let bot = Arc::new(Bot::new("1:token").set_api_url(fake_bot_api().await));
+        let post = channel_post(serde_json::json!({ "photo": photo("AgAD") }));
+        assert!(handle_media_message(bot, post, app_state.clone())
+            .await
+            .is_err());
+        assert_eq!(app_state.stats.downloads.load(Ordering::Relaxed), 0);
+        assert_eq!(app_state.stats.bytes.load(Ordering::Relaxed), 0);
+        assert_eq!(app_state.session_downloads.load(Ordering::Relaxed), 0);
+        let dir = &app_state.config.media_directory;
+        assert_eq!(saved_files(dir), Vec::<String>::new());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn remove_appended_keeps_the_lines_of_others() {
+        let path =
+            env::temp_dir().join(format!("tg_download_bot_test_{}.csv", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "header\nmine\nother\n").unwrap();
+        remove_appended(&path, b"mine\n", 1).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "header\nother\n");
+        // only the header left
+        remove_appended(&path, b"other\n", 1).await.unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn albums_are_handled_in_post_order_while_running_concurrently() {
+        let app_state = Arc::new(test_state(test_config(
+            "album_folders = true\nmax_concurrent_downloads = 4",
+        )));
+        let post = |message_id: i32, media_group_id: &str, caption: Option<&str>| Update {
+            id: message_id,
+            kind: UpdateKind::ChannelPost(channel_post(serde_json::json!({
+                "message_id": message_id,
+                "media_group_id": media_group_id,
+                "photo": photo(&format!("AgAD{message_id}")),
+                "caption": caption,
+            }))),
+        };
+        let updates = [
+            post(1, "1", Some("Trip")),
+            post(2, "2", Some("Party")),
+            post(3, "1", None),
+            post(4, "2", None),
+            post(5, "1", None),
+        ];
+        let mut workers: Vec<(_, Vec<Update>)> = Vec::new();
+        for update in updates {
+            let key = distribution_key(&update).unwrap();
+            match workers.iter_mut().find(|(other, _)| *other == key) {
+                Some((_, updates)) => updates.push(update),
+                None => workers.push((key, vec![update])),
+            }
+        }
+        // one worker per album, each seeing its parts in post order
+        assert_eq!(workers.len(), 2);
+
+        let bot = Arc::new(Bot::new("1:token").set_api_url(fake_bot_api().await));
+        futures::future::join_all(workers.into_iter().map(|(_, updates)| {
+            let (bot, app_state) = (bot.clone(), app_state.clone());
+            async move {
+                for update in updates {
+                    let UpdateKind::ChannelPost(post) = update.kind else {
+                        unreachable!();
+                    };
+                    handle_media_message(bot.clone(), post, app_state.clone())
+                        .await
+                        .unwrap();
+                }
+            }
+        }))
+        .await;
+        let dir = &app_state.config.media_directory;
+        assert_eq!(
+            saved_files(dir),
+            [
+                "Party/1.jpg",
+                "Party/2.jpg",
+                "Trip/1.jpg",
+                "Trip/2.jpg",
+                "Trip/3.jpg"
+            ]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn posts_outside_albums_are_distributed_by_chat() {
+        let update = |post| Update {
+            id: 0,
+            kind: UpdateKind::ChannelPost(channel_post(post)),
+        };
+        let single = update(serde_json::json!({ "photo": photo("AgAD1") }));
+        let part = update(serde_json::json!({ "photo": photo("AgAD2"), "media_group_id": "1" }));
+        assert_eq!(distribution_key(&single), Some((ChatId(-1001), None)));
+        assert_eq!(
+            distribution_key(&part),
+            Some((ChatId(-1001), Some("1".to_owned())))
+        );
+    }
+
+    #[tokio::test]
+    async fn files_the_scanner_could_not_check_are_removed() {
+        let app_state = Arc::new(test_state(test_config(
+            "scan_command = [\"/nonexistent/scanner\", \"{path}\"]",
+        )));
+        let bot = Arc::new(Bot::new("1:token").set_api_url(fake_bot_api().await));
+        let post = channel_post(serde_json::json!({ "photo": photo("AgAD") }));
+        assert!(handle_media_message(bot, post, app_state.clone())
+            .await
+            .is_err());
+        let dir = &app_state.config.media_directory;
+        assert_eq!(saved_files(dir), Vec::<String>::new());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn name_source_applies_to_every_media_type() {
+        let file = |unique_id: &str, file_name: &str, mime_type: &str| {
+            serde_json::json!({
+                "file_id": format!("id_{unique_id}"),
+                "file_unique_id": unique_id,
+                "file_size": format!("contents of {unique_id}").len(),
+                "file_name": file_name,
+                "mime_type": mime_type,
+                "width": 1280,
+                "height": 720,
+                "duration": 10,
+            })
+        };
+        // media field, name_source key, the file and what each order names it,
+        // documents and animations are saved as unknown media
+        let cases = [
+            (
+                "video",
+                "video",
+                file("AgADv", "clip.mp4", "video/mp4"),
+                "[caption]_AgADv.mp4",
+                "[clip.mp4]_AgADv.mp4",
+            ),
+            (
+                "audio",
+                "audio",
+                file("AgADa", "song.mp3", "audio/mpeg"),
+                "[caption]_AgADa.mp3",
+                "[song.mp3]_AgADa.mp3",
+            ),
+            (
+                "document",
+                "other",
+                file("AgADd", "paper.pdf", "application/pdf"),
+                "unknown/[caption]_AgADd.pdf",
+                "unknown/[paper.pdf]_AgADd.pdf",
+            ),
+            (
+                "animation",
+                "other",
+                file("AgADn", "loop.mp4", "video/mp4"),
+                "unknown/[caption]_AgADn.mp4",
+                "unknown/[loop.mp4]_AgADn.mp4",
+            ),
+        ];
+        for (field, key, file, caption_first, file_name_first) in cases {
+            for (order, expected) in [
+                ("caption_first", caption_first),
+                ("file_name_first", file_name_first),
+            ] {
+                let app_state = Arc::new(test_state(test_config(&format!(
+                    "download_unknown_media = true\n[name_source]\n{key} = \"{order}\""
+                ))));
+                let mut post = serde_json::json!({ "caption": "caption" });
+                post[field] = file.clone();
+                if field == "animation" {
+                    // the Bot API sends animations as documents too
+                    post["document"] = file.clone();
+                }
+                handle_posts(&app_state, [channel_post(post)]).await;
+                let dir = &app_state.config.media_directory;
+                assert_eq!(saved_files(dir), [expected], "{field} with {order}");
+                std::fs::remove_dir_all(dir).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn any_option_can_come_from_the_environment() {
+        let vars = [
+            (prefixed_env_var("bot_token"), "1:token"),
+            (prefixed_env_var("channel_id"), "-1001"),
+            (prefixed_env_var("media_directory"), "/srv/media"),
+            (prefixed_env_var("max_title_len"), "12"),
+            (
+                format!("{ENV_PREFIX}_NAME_SOURCE__VIDEO"),
+                "file_name_first",
+            ),
+        ];
+        let config: AppConfig = Config::builder()
+            .add_source(
+                config_environment().source(Some(
+                    vars.into_iter()
+                        .map(|(var, value)| (var, value.to_owned()))
+                        .collect(),
+                )),
+            )
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        assert_eq!(config.media_directory, "/srv/media");
+        assert_eq!(config.max_title_len, 12);
+        assert_eq!(
+            config.media_name("video", Some("caption"), Some("name.mp4")),
+            Some("name.mp4")
+        );
+    }
+
+    #[test]
+    fn channel_names_follow_renames() {
+        let app_state = test_state(test_config(""));
+        let post = channel_post(serde_json::json!({ "photo": photo("AgAD") }));
+        let renamed = channel_post(serde_json::json!({
+            "photo": photo("AgAD"),
+            "chat": { "id": -1001, "type": "channel", "title": "News/Daily" },
+        }));
+        assert_eq!(channel_name(&app_state, &post.chat), "Channel");
+        assert_eq!(channel_name(&app_state, &renamed.chat), "News-Daily");
+    }
 }
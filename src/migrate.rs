@@ -0,0 +1,205 @@
+//! `migrate` subcommand: renames downloaded files to the current
+//! `filename_template` using what the database recorded about them, so the
+//! template can change without downloading everything again.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    crypto,
+    database::{Database, DownloadRecord},
+    sidecar, template, truncate_chars, AppConfig, NameSource, TitleSource,
+};
+
+/// Tokens whose value the database doesn't keep, a template using them can't
+/// be applied to past downloads.
+const UNRECORDED_TOKENS: &[&str] = &[
+    "page",
+    "seq",
+    "download_date",
+    "performer",
+    "track_title",
+    "ocr",
+];
+
+/// Prints the planned renames, and carries them out when `apply` is set.
+pub async fn run(config: &AppConfig, apply: bool) -> Result<()> {
+    let Some(database_path) = &config.database_path else {
+        bail!("migrate needs database_path to know what the files were downloaded from");
+    };
+    let Some(filename_template) = &config.filename_template else {
+        bail!("migrate needs a filename_template to rename the files to");
+    };
+    if let Some(token) =
+        template::tokens(filename_template).find(|token| UNRECORDED_TOKENS.contains(token))
+    {
+        bail!("filename_template uses {{{token}}}, which the database doesn't record");
+    }
+    let database = Arc::new(Database::open(Path::new(database_path))?);
+
+    let (mut renamed, mut skipped) = (0, 0);
+    for record in database.downloads().await? {
+        let Some(new_path) = migrated_path(config, filename_template, &record) else {
+            skipped += 1;
+            continue;
+        };
+        if new_path == record.path {
+            continue;
+        }
+        if !record.path.exists() {
+            log::warn!("Skipping {}: file is missing", record.path.display());
+            skipped += 1;
+            continue;
+        }
+        if new_path.exists() {
+            log::warn!(
+                "Skipping {}: {} already exists",
+                record.path.display(),
+                new_path.display()
+            );
+            skipped += 1;
+            continue;
+        }
+        println!("{} -> {}", record.path.display(), new_path.display());
+        if apply {
+            rename(&record.path, &new_path).await?;
+            database
+                .update_path(&record.unique_id, &new_path)
+                .await
+                .with_context(|| {
+                    format!("Failed to record the new path of {}", record.path.display())
+                })?;
+        }
+        renamed += 1;
+    }
+
+    if apply {
+        println!("Renamed {renamed} files, skipped {skipped}");
+    } else {
+        println!("Would rename {renamed} files, skipped {skipped}. Run with --apply to rename");
+    }
+    Ok(())
+}
+
+/// Where `record` belongs under `filename_template`, in the directory it is
+/// in now. `None` when the name can't be worked out from the database.
+fn migrated_path(
+    config: &AppConfig,
+    filename_template: &str,
+    record: &DownloadRecord,
+) -> Option<PathBuf> {
+    let name = record.path.file_name()?.to_str()?;
+    let (name, encrypted) = match name
+        .strip_suffix(crypto::ENCRYPTED_EXTENSION)
+        .and_then(|name| name.strip_suffix('.'))
+    {
+        Some(name) => (name, true),
+        None => (name, false),
+    };
+    let stem = Path::new(name).file_stem()?.to_str()?;
+    let extension = Path::new(name).extension()?.to_str()?;
+    // files in album folders are named after their page
+    if stem.parse::<u32>().is_ok() {
+        return None;
+    }
+
+    let uses_title = template::tokens(filename_template).any(|token| token == "title");
+    let title = match recorded_title(config, record) {
+        Some(title) => title,
+        None if uses_title => {
+            log::warn!(
+                "Skipping {}: its title isn't recorded",
+                record.path.display()
+            );
+            return None;
+        }
+        None => "",
+    };
+    let title = config.unicode_normalization.apply(title);
+    let title = truncate_chars(&title, config.max_title_len);
+    let title = if config.drop_symbol_only_titles && !title.chars().any(char::is_alphanumeric) {
+        ""
+    } else {
+        title
+    };
+    let message_date = record.date.with_timezone(&config.timezone.0);
+
+    let filename = template::render(filename_template, |token| match token {
+        "title" => Some(title.to_owned()),
+        "unique_id" => Some(record.unique_id.clone()),
+        "date" | "message_date" => Some(message_date.format(&config.date_format).to_string()),
+        "channel" => Some(record.channel.clone()),
+        "run_id" => Some(record.run_id.clone()),
+        _ => None,
+    });
+    let filename = config.replace_path_separators(&filename);
+    let new_path = record
+        .path
+        .with_file_name(crate::media_file_name(&filename, extension, encrypted));
+    if config.path_excess(&new_path) > 0 {
+        log::warn!(
+            "Skipping {}: {} is too long",
+            record.path.display(),
+            new_path.display()
+        );
+        return None;
+    }
+    Some(new_path)
+}
+
+/// The title the file was named with, `None` when it may have come from
+/// what the database doesn't record: the file name the sender gave or the
+/// tags of audio. Photos have neither.
+fn recorded_title<'a>(config: &AppConfig, record: &'a DownloadRecord) -> Option<&'a str> {
+    let caption = record
+        .caption
+        .as_deref()
+        .filter(|caption| !caption.trim().is_empty());
+    let is_photo = record.media_type == "photo";
+    if config.name_sources.is_empty() {
+        let name_source = config
+            .name_source
+            .get(&record.media_type)
+            .copied()
+            .unwrap_or_default();
+        return match caption {
+            _ if is_photo => Some(caption.unwrap_or("")),
+            Some(caption) if name_source == NameSource::CaptionFirst => Some(caption),
+            _ => None,
+        };
+    }
+    for source in &config.name_sources {
+        match source {
+            TitleSource::Caption if caption.is_some() => return caption,
+            TitleSource::Caption => {}
+            TitleSource::UniqueId => return Some(""),
+            _ if is_photo => {}
+            TitleSource::PerformerTitle | TitleSource::FileName => return None,
+        }
+    }
+    Some("")
+}
+
+/// Renames the file together with its sidecars.
+async fn rename(from: &Path, to: &Path) -> Result<()> {
+    tokio::fs::rename(from, to)
+        .await
+        .with_context(|| format!("Failed to rename {}", from.display()))?;
+    for sidecar in [
+        sidecar::sidecar_path,
+        sidecar::context_path,
+        sidecar::nfo_path,
+    ] {
+        let (from, to) = (sidecar(from), sidecar(to));
+        if from.exists() {
+            tokio::fs::rename(&from, &to)
+                .await
+                .with_context(|| format!("Failed to rename {}", from.display()))?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,62 @@
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use image::{
+    codecs::jpeg::JpegEncoder, metadata::Orientation, DynamicImage, ImageDecoder, ImageFormat,
+    ImageReader,
+};
+
+/// Quality of re-encoded JPEGs, high enough that one more generation of
+/// compression isn't visible.
+const JPEG_QUALITY: u8 = 95;
+
+/// Rotates a JPEG or PNG at `path` as its EXIF orientation says and saves it
+/// again without the tag, so viewers that ignore EXIF show it upright too.
+/// Returns whether the file was changed; other formats and images without an
+/// orientation are left alone.
+pub async fn auto_orient(path: &Path) -> Result<bool> {
+    let path = path.to_owned();
+    tokio::task::spawn_blocking(move || orient(&path)).await?
+}
+
+fn orient(path: &Path) -> Result<bool> {
+    let reader = ImageReader::open(path)?.with_guessed_format()?;
+    let format = match reader.format() {
+        Some(format @ (ImageFormat::Jpeg | ImageFormat::Png)) => format,
+        _ => return Ok(false),
+    };
+    let mut decoder = reader.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    if orientation == Orientation::NoTransforms {
+        return Ok(false);
+    }
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+    let mut writer = BufWriter::new(File::create(&temp_path)?);
+    let written = match format {
+        ImageFormat::Jpeg => {
+            image.write_with_encoder(JpegEncoder::new_with_quality(&mut writer, JPEG_QUALITY))
+        }
+        _ => image.write_to(&mut writer, format),
+    };
+    let written = written.map_err(anyhow::Error::from).and_then(|()| {
+        Ok(writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?)
+    });
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(e).context("Failed to write the rotated image");
+    }
+    std::fs::rename(&temp_path, path).context("Failed to replace the image")?;
+    Ok(true)
+}
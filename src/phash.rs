@@ -0,0 +1,18 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use image_hasher::HasherConfig;
+
+/// Perceptual hash of the image at `path` as hex. Similar images get hashes
+/// that differ in few bits, unlike a checksum, so near-duplicates can be found
+/// by their Hamming distance.
+pub async fn compute(path: &Path) -> Result<String> {
+    let path = path.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let image =
+            image::open(&path).with_context(|| format!("Failed to decode {}", path.display()))?;
+        let hash = HasherConfig::new().to_hasher().hash_image(&image);
+        Ok(hex::encode(hash.as_bytes()))
+    })
+    .await?
+}
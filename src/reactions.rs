@@ -0,0 +1,170 @@
+//! Reacting to handled posts with an emoji, configured by `reactions`.
+//!
+//! teloxide doesn't know `setMessageReaction` yet, so it is called directly.
+
+use std::collections::HashMap;
+
+use anyhow::{bail, Context, Result};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use teloxide::Bot;
+
+/// The emoji Telegram accepts as a reaction.
+const ALLOWED: &[&str] = &[
+    "👍",
+    "👎",
+    "❤",
+    "🔥",
+    "🥰",
+    "👏",
+    "😁",
+    "🤔",
+    "🤯",
+    "😱",
+    "🤬",
+    "😢",
+    "🎉",
+    "🤩",
+    "🤮",
+    "💩",
+    "🙏",
+    "👌",
+    "🕊",
+    "🤡",
+    "🥱",
+    "🥴",
+    "😍",
+    "🐳",
+    "❤‍🔥",
+    "🌚",
+    "🌭",
+    "💯",
+    "🤣",
+    "⚡",
+    "🍌",
+    "🏆",
+    "💔",
+    "🤨",
+    "😐",
+    "🍓",
+    "🍾",
+    "💋",
+    "🖕",
+    "😈",
+    "😴",
+    "😭",
+    "🤓",
+    "👻",
+    "👨‍💻",
+    "👀",
+    "🎃",
+    "🙈",
+    "😇",
+    "😨",
+    "🤝",
+    "✍",
+    "🤗",
+    "🫡",
+    "🎅",
+    "🎄",
+    "☃",
+    "💅",
+    "🤪",
+    "🗿",
+    "🆒",
+    "💘",
+    "🙉",
+    "🦄",
+    "😘",
+    "💊",
+    "🙊",
+    "😎",
+    "👾",
+    "🤷‍♂",
+    "🤷",
+    "🤷‍♀",
+    "😡",
+];
+
+/// Keys of the per outcome tables besides `default`.
+const MEDIA_TYPES: &[&str] = &["photo", "video", "audio", "other"];
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Saved,
+    Skipped,
+    Failed,
+}
+
+/// Emoji by outcome, then by media type or `default`.
+pub type Reactions = HashMap<Outcome, HashMap<String, String>>;
+
+pub fn validate(reactions: &Reactions) -> Result<()> {
+    for by_type in reactions.values() {
+        for (media_type, emoji) in by_type {
+            if media_type != "default" && !MEDIA_TYPES.contains(&media_type.as_str()) {
+                bail!(
+                    "Unknown media type {media_type} in reactions, expected default or one of: {}",
+                    MEDIA_TYPES.join(", ")
+                );
+            }
+            // the heart is often typed with a variation selector
+            if !ALLOWED.contains(&emoji.trim_end_matches('\u{fe0f}')) {
+                bail!(
+                    "{emoji} can't be used as a reaction, Telegram only allows: {}",
+                    ALLOWED.join(" ")
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn emoji<'a>(reactions: &'a Reactions, outcome: Outcome, media_type: &str) -> Option<&'a str> {
+    let by_type = reactions.get(&outcome)?;
+    by_type
+        .get(media_type)
+        .or_else(|| by_type.get("default"))
+        .map(|emoji| emoji.trim_end_matches('\u{fe0f}'))
+}
+
+pub struct Reactor {
+    client: reqwest::Client,
+    /// Includes the bot token, never log it.
+    url: Url,
+}
+
+impl Reactor {
+    pub fn new(bot: &Bot) -> Result<Self> {
+        let url = bot
+            .api_url()
+            .join(&format!("bot{}/setMessageReaction", bot.token()))
+            .context("Invalid Bot API url")?;
+        Ok(Self {
+            client: bot.client().clone(),
+            url,
+        })
+    }
+
+    pub async fn react(&self, chat_id: i64, message_id: i32, emoji: &str) -> Result<()> {
+        let response: serde_json::Value = self
+            .client
+            .post(self.url.clone())
+            .json(&serde_json::json!({
+                "chat_id": chat_id,
+                "message_id": message_id,
+                "reaction": [{ "type": "emoji", "emoji": emoji }],
+            }))
+            .send()
+            .await
+            .map_err(reqwest::Error::without_url)?
+            .json()
+            .await
+            .map_err(reqwest::Error::without_url)?;
+        if response["ok"] != true {
+            bail!("{}", response["description"].as_str().unwrap_or("failed"));
+        }
+        Ok(())
+    }
+}
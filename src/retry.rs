@@ -0,0 +1,197 @@
+use std::{
+    fmt::Display,
+    future::Future,
+    io::{self, ErrorKind},
+    time::Duration,
+};
+
+use rand::Rng;
+use teloxide::{DownloadError, RequestError};
+
+/// Longest pause between two attempts, however many attempts were made.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    /// Fraction of each backoff that is randomized, so operations failing at
+    /// the same moment don't all retry in lockstep.
+    pub jitter: f64,
+}
+
+impl RetryPolicy {
+    /// Exponential backoff: `base_delay * 2^attempt`, capped at [`MAX_BACKOFF`],
+    /// then shortened by a random amount of up to `jitter` of it.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(MAX_BACKOFF);
+        if self.jitter <= 0.0 {
+            return delay;
+        }
+        delay.mul_f64(1.0 - rand::rng().random_range(0.0..=self.jitter))
+    }
+}
+
+pub enum Verdict {
+    Retry,
+    RetryAfter(Duration),
+    Fail,
+}
+
+/// Runs `op` until it succeeds, `classify` says the error isn't worth
+/// retrying, or `policy.max_retries` retries were made.
+pub async fn retry<T, E, Fut>(
+    policy: RetryPolicy,
+    what: &str,
+    classify: impl Fn(&E) -> Verdict,
+    mut op: impl FnMut() -> Fut,
+) -> Result<T, E>
+where
+    E: Display,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempt = 0;
+    loop {
+        let error = match op().await {
+            Ok(value) => return Ok(value),
+            Err(error) => error,
+        };
+        let delay = match classify(&error) {
+            _ if attempt >= policy.max_retries => return Err(error),
+            Verdict::Fail => return Err(error),
+            Verdict::Retry => policy.backoff(attempt),
+            Verdict::RetryAfter(delay) => delay,
+        };
+        attempt += 1;
+        log::warn!(
+            "{what} failed ({error}), retry {attempt}/{} in {delay:?}",
+            policy.max_retries
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Flood control and network hiccups are retried, API errors are not.
+pub fn classify_request_error(error: &RequestError) -> Verdict {
+    match error {
+        RequestError::RetryAfter(delay) => Verdict::RetryAfter(*delay),
+        RequestError::Network(_) | RequestError::Io(_) => Verdict::Retry,
+        _ => Verdict::Fail,
+    }
+}
+
+/// Handling a post is worth another go when a transient Telegram, network or
+/// filesystem error is anywhere in the cause chain.
+pub fn classify_message_error(error: &anyhow::Error) -> Verdict {
+    for cause in error.chain() {
+        if let Some(error) = cause.downcast_ref::<RequestError>() {
+            return classify_request_error(error);
+        }
+        if let Some(error) = cause.downcast_ref::<DownloadError>() {
+            return match error {
+                DownloadError::Network(_) => Verdict::Retry,
+                DownloadError::Io(error) => classify_io_error(error),
+            };
+        }
+        if let Some(error) = cause.downcast_ref::<io::Error>() {
+            return classify_io_error(error);
+        }
+    }
+    Verdict::Fail
+}
+
+/// A hook that ran and failed may succeed the next time, one that couldn't be
+/// started only when that was transient: a missing program stays missing.
+pub fn classify_hook_error(error: &anyhow::Error) -> Verdict {
+    match error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<io::Error>())
+    {
+        Some(error) => classify_io_error(error),
+        None => Verdict::Retry,
+    }
+}
+
+/// A hiccup of a network mount is retried, a full disk or missing permissions
+/// won't fix themselves.
+pub fn classify_io_error(error: &io::Error) -> Verdict {
+    // EIO, what NFS reports when the server doesn't answer in time
+    const EIO: i32 = 5;
+    match error.kind() {
+        ErrorKind::TimedOut
+        | ErrorKind::Interrupted
+        | ErrorKind::WouldBlock
+        | ErrorKind::ResourceBusy
+        | ErrorKind::StaleNetworkFileHandle
+        // EROFS, a network mount is briefly read-only while it's remounted
+        | ErrorKind::ReadOnlyFilesystem => Verdict::Retry,
+        ErrorKind::StorageFull | ErrorKind::PermissionDenied => Verdict::Fail,
+        _ if error.raw_os_error() == Some(EIO) => Verdict::Retry,
+        _ => Verdict::Fail,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retried(error: io::Error) -> bool {
+        matches!(classify_io_error(&error), Verdict::Retry)
+    }
+
+    #[test]
+    fn transient_filesystem_errors_are_retried() {
+        assert!(retried(ErrorKind::ReadOnlyFilesystem.into()));
+        assert!(retried(ErrorKind::TimedOut.into()));
+        assert!(retried(ErrorKind::StaleNetworkFileHandle.into()));
+        assert!(retried(io::Error::from_raw_os_error(5)));
+    }
+
+    #[test]
+    fn full_disks_and_missing_permissions_are_not_retried() {
+        assert!(!retried(ErrorKind::StorageFull.into()));
+        assert!(!retried(ErrorKind::PermissionDenied.into()));
+        assert!(!retried(ErrorKind::NotFound.into()));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn errno_values_are_classified() {
+        // EROFS, ENOSPC and EACCES
+        assert!(retried(io::Error::from_raw_os_error(30)));
+        assert!(!retried(io::Error::from_raw_os_error(28)));
+        assert!(!retried(io::Error::from_raw_os_error(13)));
+    }
+
+    #[test]
+    fn io_errors_are_found_in_the_cause_chain() {
+        let error = anyhow::Error::new(io::Error::from(ErrorKind::ReadOnlyFilesystem))
+            .context("Failed to write file");
+        assert!(matches!(classify_message_error(&error), Verdict::Retry));
+        let error = anyhow::Error::new(DownloadError::Io(ErrorKind::StorageFull.into()))
+            .context("Failed to download file");
+        assert!(matches!(classify_message_error(&error), Verdict::Fail));
+        assert!(matches!(
+            classify_message_error(&anyhow::anyhow!("Unsupported media")),
+            Verdict::Fail
+        ));
+    }
+
+    #[test]
+    fn hooks_are_only_retried_when_they_may_succeed() {
+        let failed = anyhow::anyhow!("hook failed with exit status: 1");
+        assert!(matches!(classify_hook_error(&failed), Verdict::Retry));
+        let missing =
+            anyhow::Error::new(io::Error::from(ErrorKind::NotFound)).context("Failed to run hook");
+        assert!(matches!(classify_hook_error(&missing), Verdict::Fail));
+        let denied = anyhow::Error::new(io::Error::from(ErrorKind::PermissionDenied))
+            .context("Failed to run hook");
+        assert!(matches!(classify_hook_error(&denied), Verdict::Fail));
+        let busy = anyhow::Error::new(io::Error::from(ErrorKind::ResourceBusy))
+            .context("Failed to run hook");
+        assert!(matches!(classify_hook_error(&busy), Verdict::Retry));
+    }
+}
@@ -0,0 +1,53 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use tokio::{io::AsyncWriteExt, sync::Mutex};
+
+/// Global download counter behind the `{seq}` filename token, kept in a small
+/// text file so it keeps counting across restarts.
+pub struct SeqCounter {
+    path: PathBuf,
+    last: Mutex<u64>,
+}
+
+impl SeqCounter {
+    pub async fn open(path: PathBuf) -> Result<Self> {
+        let last = match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => contents
+                .trim()
+                .parse()
+                .with_context(|| format!("Corrupt seq file {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .context("Failed to create the directory of seq_file")?;
+        }
+        Ok(Self {
+            path,
+            last: Mutex::new(last),
+        })
+    }
+
+    /// Hands out the next number. It's on disk before it's returned, so a crash
+    /// can skip a number but never hand it out twice.
+    pub async fn next(&self) -> Result<u64> {
+        let mut last = self.last.lock().await;
+        let next = *last + 1;
+        // written next to the counter and renamed over it, so the file is never
+        // seen half-written
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+        let mut file = tokio::fs::File::create(&temp_path).await?;
+        file.write_all(next.to_string().as_bytes()).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&temp_path, &self.path)
+            .await
+            .with_context(|| format!("Failed to update {}", self.path.display()))?;
+        *last = next;
+        Ok(next)
+    }
+}
@@ -0,0 +1,294 @@
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Serialize;
+use teloxide::types::{InlineKeyboardMarkup, Message, MessageEntity, MessageEntityKind, MessageId};
+
+/// Metadata written next to a downloaded file when `write_metadata` is set.
+#[derive(Serialize)]
+pub struct Metadata<'a> {
+    pub unique_id: &'a str,
+    pub file_id: &'a str,
+    pub chat_id: i64,
+    pub message_id: i32,
+    pub media_group_id: Option<&'a str>,
+    pub date: DateTime<Utc>,
+    pub caption: Option<&'a str>,
+    pub tags: Vec<String>,
+    /// ISO 639-3 code of the caption language, `null` when the caption is too
+    /// short to tell. Left out unless `detect_language` is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<Option<&'static str>>,
+    /// Perceptual hash of photos as hex, with `compute_phash`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phash: Option<&'a str>,
+    /// Output of the `ocr_command` for photos.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ocr_text: Option<&'a str>,
+    /// SHA-256 of the file as hex, with `strict_pipeline` or the
+    /// `content_addressed` layout.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<&'a str>,
+    /// Run of the bot that saved the file, with `metadata_run_id`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<&'a str>,
+    /// Link to the post, `null` when the chat has none. Left out unless
+    /// `metadata_message_link` is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_link: Option<Option<String>>,
+}
+
+impl<'a> Metadata<'a> {
+    pub fn new(
+        message: &'a Message,
+        unique_id: &'a str,
+        file_id: &'a str,
+        detect_language: bool,
+    ) -> Self {
+        Self {
+            unique_id,
+            file_id,
+            chat_id: message.chat.id.0,
+            message_id: message.id.0,
+            media_group_id: message.media_group_id(),
+            date: message.date,
+            caption: message.caption(),
+            tags: hashtags(message),
+            language: detect_language.then(|| message.caption().and_then(caption_language)),
+            phash: None,
+            ocr_text: None,
+            sha256: None,
+            run_id: None,
+            message_link: None,
+        }
+    }
+}
+
+/// The post a file came from as it was when downloaded, written next to it
+/// when `write_context` is set.
+#[derive(Serialize)]
+pub struct Context<'a> {
+    pub chat_id: i64,
+    pub chat_title: Option<&'a str>,
+    pub message_id: i32,
+    pub date: DateTime<Utc>,
+    pub edit_date: Option<DateTime<Utc>>,
+    pub author_signature: Option<&'a str>,
+    pub forwarded_from_chat_id: Option<i64>,
+    pub text: Option<&'a str>,
+    pub entities: Option<&'a [MessageEntity]>,
+    pub reply_markup: Option<&'a InlineKeyboardMarkup>,
+    /// Always `null`: reactions aren't part of a channel post when it arrives,
+    /// Telegram only reports them in later updates.
+    pub reactions: Option<()>,
+}
+
+impl<'a> Context<'a> {
+    pub fn new(message: &'a Message) -> Self {
+        Self {
+            chat_id: message.chat.id.0,
+            chat_title: message.chat.title(),
+            message_id: message.id.0,
+            date: message.date,
+            edit_date: message.edit_date().copied(),
+            author_signature: message.author_signature(),
+            forwarded_from_chat_id: message.forward_from_chat().map(|chat| chat.id.0),
+            text: message.caption().or_else(|| message.text()),
+            entities: message.caption_entities().or_else(|| message.entities()),
+            reply_markup: message.reply_markup(),
+            reactions: None,
+        }
+    }
+}
+
+/// `t.me/<username>/<id>` for public chats, `t.me/c/<internal id>/<id>` for
+/// private channels and groups, `None` for private chats. Posts the bot got
+/// forwarded link to the original.
+pub fn message_link(message: &Message) -> Option<String> {
+    let url = match (
+        message.forward_from_chat(),
+        message.forward_from_message_id(),
+    ) {
+        (Some(chat), Some(id)) => Message::url_of(chat.id, chat.username(), MessageId(id)),
+        _ => message.url(),
+    };
+    url.map(String::from)
+}
+
+/// Name of the manifest written into album folders when `album_manifest` is set.
+pub const ALBUM_MANIFEST: &str = "album.json";
+
+#[derive(Serialize)]
+pub struct AlbumManifest {
+    pub media_group_id: String,
+    pub title: String,
+    /// Captions of all parts, without duplicates, in message order.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub caption: Option<String>,
+    pub files: Vec<AlbumFile>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct AlbumFile {
+    pub page: u32,
+    /// Path relative to the album folder.
+    pub file: String,
+    pub unique_id: String,
+}
+
+/// Captions shorter than this are mostly hashtags or emoji, detection on them
+/// is a guess.
+const MIN_DETECTION_CHARS: usize = 10;
+
+fn caption_language(caption: &str) -> Option<&'static str> {
+    if caption.chars().filter(|c| c.is_alphabetic()).count() < MIN_DETECTION_CHARS {
+        return None;
+    }
+    whatlang::detect(caption)
+        .filter(|info| info.is_reliable())
+        .map(|info| info.lang().code())
+}
+
+/// `photo.jpg` gets `photo.jpg.meta.json`. The full media file name is kept
+/// and `.meta` marks the file as a sidecar, so a downloaded `data.json` and its
+/// `data.json.meta.json` never collide.
+pub fn sidecar_path(media_path: &Path) -> PathBuf {
+    let mut path = media_path.as_os_str().to_owned();
+    path.push(".meta.json");
+    PathBuf::from(path)
+}
+
+/// `photo.jpg` gets `photo.jpg.context.json`.
+pub fn context_path(media_path: &Path) -> PathBuf {
+    let mut path = media_path.as_os_str().to_owned();
+    path.push(".context.json");
+    PathBuf::from(path)
+}
+
+/// Line of the `caption_log` for a saved file: `<file name>\t<caption>`, with
+/// tabs, newlines and backslashes escaped so every download stays on one line.
+pub fn caption_log_line(file_name: &str, caption: &str) -> String {
+    format!("{}\t{}\n", escape_line(file_name), escape_line(caption))
+}
+
+fn escape_line(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Minimal Kodi/Jellyfin `.nfo` for a video. The title is the first line of the
+/// caption, the plot all of it.
+pub fn nfo<Tz: TimeZone>(caption: Option<&str>, fallback_title: &str, date: DateTime<Tz>) -> String
+where
+    Tz::Offset: std::fmt::Display,
+{
+    let title = caption
+        .and_then(|caption| caption.lines().map(str::trim).find(|line| !line.is_empty()))
+        .unwrap_or(fallback_title);
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n\
+         <movie>\n  \
+         <title>{}</title>\n  \
+         <plot>{}</plot>\n  \
+         <premiered>{}</premiered>\n  \
+         <dateadded>{}</dateadded>\n\
+         </movie>\n",
+        escape_xml(title),
+        escape_xml(caption.unwrap_or("")),
+        date.format("%Y-%m-%d"),
+        date.format("%Y-%m-%d %H:%M:%S"),
+    )
+}
+
+pub fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // not allowed in XML 1.0 at all
+            c if c.is_control() && !matches!(c, '\n' | '\r' | '\t') => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Media centers look for `movie.nfo` next to `movie.mp4`.
+pub fn nfo_path(media_path: &Path) -> PathBuf {
+    media_path.with_extension("nfo")
+}
+
+/// Hashtags of the caption without the `#`. Uses the caption entities when
+/// Telegram sent them, otherwise falls back to scanning the text.
+pub fn hashtags(message: &Message) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+    let found: Vec<String> = match message.parse_caption_entities() {
+        Some(entities) if message.caption_entities().is_some() => entities
+            .iter()
+            .filter(|entity| *entity.kind() == MessageEntityKind::Hashtag)
+            .map(|entity| entity.text().trim_start_matches('#').to_owned())
+            .collect(),
+        _ => message.caption().map_or_else(Vec::new, parse_hashtags),
+    };
+    for tag in found {
+        if !tag.is_empty() && !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    tags
+}
+
+fn parse_hashtags(text: &str) -> Vec<String> {
+    text.split_whitespace()
+        .filter_map(|word| word.strip_prefix('#'))
+        .map(|word| {
+            word.chars()
+                .take_while(|c| c.is_alphanumeric() || *c == '_')
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sidecars_keep_the_full_media_file_name() {
+        assert_eq!(
+            sidecar_path(Path::new("media/photo.jpg")),
+            Path::new("media/photo.jpg.meta.json")
+        );
+        assert_eq!(
+            context_path(Path::new("media/photo.jpg")),
+            Path::new("media/photo.jpg.context.json")
+        );
+    }
+
+    #[test]
+    fn json_documents_dont_collide_with_their_sidecar() {
+        let document = Path::new("media/data.json");
+        let sidecars = [sidecar_path(document), context_path(document)];
+        assert!(!sidecars.iter().any(|sidecar| sidecar == document));
+        // a document named like another file's sidecar gets a sidecar of its own
+        let document = Path::new("media/data.json.meta.json");
+        assert_ne!(sidecar_path(document), document);
+        assert_ne!(
+            sidecar_path(document),
+            sidecar_path(Path::new("media/data.json"))
+        );
+    }
+}
@@ -0,0 +1,365 @@
+use std::{
+    collections::HashSet,
+    io,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::{bail, Context, Result};
+use futures::{stream, StreamExt};
+use reqwest::{Method, StatusCode, Url};
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize, Serializer};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt, DuplexStream},
+    task::JoinHandle,
+};
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    retry::{self, RetryPolicy},
+    serialize_redacted,
+};
+
+/// Bytes buffered between the download and the upload when streaming to a
+/// remote storage.
+const UPLOAD_BUFFER_SIZE: usize = 256 * 1024;
+
+#[derive(Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    #[default]
+    Local,
+    Webdav,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct WebDavConfig {
+    /// Collection the media directory is mapped to, e.g. `https://nas/dav/media/`.
+    #[serde(serialize_with = "serialize_url")]
+    pub url: Url,
+    pub username: Option<String>,
+    #[serde(serialize_with = "serialize_redacted")]
+    pub password: Option<SecretString>,
+}
+
+/// Where downloaded files end up. Paths passed in are always local-style paths
+/// inside `media_directory`; remote backends map them relative to it.
+pub enum Storage {
+    Local(LocalStorage),
+    WebDav(Arc<WebDavStorage>),
+}
+
+pub struct LocalStorage {
+    /// Filesystem operations are retried with it so a momentary hiccup of a
+    /// network mount doesn't drop the download.
+    pub retry: RetryPolicy,
+    /// Permissions set on created files and directories, the umask decides
+    /// when unset.
+    pub file_mode: Option<u32>,
+    pub dir_mode: Option<u32>,
+}
+
+impl LocalStorage {
+    async fn set_mode(path: &Path, mode: Option<u32>) -> Result<()> {
+        let Some(mode) = mode else {
+            return Ok(());
+        };
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+                .await
+                .with_context(|| format!("Failed to set permissions of {}", path.display()))?;
+        }
+        #[cfg(not(unix))]
+        let _ = (path, mode);
+        Ok(())
+    }
+}
+
+/// Completion of a file being stored, see [`Upload::finish`]. Dropping it
+/// without finishing cancels a remote upload, so an interrupted download never
+/// ends up stored as if it were complete.
+pub struct Upload {
+    task: Option<JoinHandle<Result<()>>>,
+    completed: Arc<AtomicBool>,
+}
+
+impl Upload {
+    fn local() -> Self {
+        Self {
+            task: None,
+            completed: Default::default(),
+        }
+    }
+
+    /// Shuts down `writer` (the writer returned by [`Storage::create`], or a
+    /// wrapper of it) and waits until the file is fully stored.
+    pub async fn finish(mut self, writer: &mut (impl AsyncWrite + Unpin)) -> Result<()> {
+        self.completed.store(true, Ordering::Release);
+        writer.shutdown().await?;
+        match self.task.take() {
+            Some(task) => task.await?,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+}
+
+fn serialize_url<S: Serializer>(url: &Url, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(url.as_str())
+}
+
+pub type Writer = Box<dyn AsyncWrite + Unpin + Send>;
+
+impl Storage {
+    pub fn new(
+        kind: StorageKind,
+        webdav: Option<&WebDavConfig>,
+        media_directory: &str,
+        client: reqwest::Client,
+        local: LocalStorage,
+    ) -> Result<Self> {
+        Ok(match kind {
+            StorageKind::Local => {
+                if cfg!(not(unix)) && (local.file_mode.is_some() || local.dir_mode.is_some()) {
+                    log::warn!("file_mode and dir_mode are only supported on Unix, ignoring them");
+                }
+                Storage::Local(local)
+            }
+            StorageKind::Webdav => {
+                let config = webdav.context("storage = \"webdav\" requires a [webdav] section")?;
+                Storage::WebDav(Arc::new(WebDavStorage {
+                    client,
+                    base_url: config.url.clone(),
+                    username: config.username.clone(),
+                    password: config.password.clone(),
+                    root: PathBuf::from(media_directory),
+                    created_collections: Default::default(),
+                }))
+            }
+        })
+    }
+
+    pub async fn create_dir_all(&self, dir: &Path) -> Result<()> {
+        match self {
+            Storage::Local(local) => {
+                retry::retry(
+                    local.retry,
+                    "create_dir_all",
+                    retry::classify_io_error,
+                    || tokio::fs::create_dir_all(dir),
+                )
+                .await
+                .context("Create dir all failed")?;
+                LocalStorage::set_mode(dir, local.dir_mode).await
+            }
+            Storage::WebDav(webdav) => webdav.create_collections(dir).await,
+        }
+    }
+
+    pub async fn create(&self, path: &Path) -> Result<(Writer, Upload)> {
+        match self {
+            Storage::Local(local) => {
+                let file =
+                    retry::retry(local.retry, "create file", retry::classify_io_error, || {
+                        tokio::fs::File::create(path)
+                    })
+                    .await
+                    .context(format!("Failed to create file: {}", path.display()))?;
+                LocalStorage::set_mode(path, local.file_mode).await?;
+                Ok((Box::new(file), Upload::local()))
+            }
+            Storage::WebDav(webdav) => {
+                let url = webdav.url(path, false)?;
+                // the download writes into one end while the upload streams the other,
+                // so at most UPLOAD_BUFFER_SIZE bytes are held in memory
+                let (writer, reader) = tokio::io::duplex(UPLOAD_BUFFER_SIZE);
+                let completed = Arc::new(AtomicBool::new(false));
+                let task = tokio::spawn(webdav.clone().put(url, reader, completed.clone()));
+                Ok((
+                    Box::new(writer),
+                    Upload {
+                        task: Some(task),
+                        completed,
+                    },
+                ))
+            }
+        }
+    }
+
+    /// Stores a small file that is already fully in memory.
+    pub async fn write_file(&self, path: &Path, contents: &[u8]) -> Result<()> {
+        let (mut writer, upload) = self.create(path).await?;
+        writer.write_all(contents).await?;
+        upload.finish(&mut writer).await
+    }
+
+    /// Removes a file that was only partially written. Remote uploads are
+    /// already cancelled by dropping their [`Upload`].
+    pub async fn discard(&self, path: &Path) -> Result<()> {
+        match self {
+            Storage::Local(_) => match tokio::fs::remove_file(path).await {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    Err(e).with_context(|| format!("Failed to remove file: {}", path.display()))
+                }
+                _ => Ok(()),
+            },
+            Storage::WebDav(_) => Ok(()),
+        }
+    }
+
+    pub async fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        match self {
+            Storage::Local(local) => {
+                retry::retry(local.retry, "rename", retry::classify_io_error, || {
+                    tokio::fs::rename(from, to)
+                })
+                .await
+                .with_context(|| format!("Failed to rename file: {}", from.display()))
+            }
+            Storage::WebDav(webdav) => webdav.move_file(from, to).await,
+        }
+    }
+}
+
+pub struct WebDavStorage {
+    client: reqwest::Client,
+    base_url: Url,
+    username: Option<String>,
+    password: Option<SecretString>,
+    root: PathBuf,
+    created_collections: Mutex<HashSet<PathBuf>>,
+}
+
+impl WebDavStorage {
+    fn url(&self, path: &Path, collection: bool) -> Result<Url> {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        let mut url = self.base_url.clone();
+        {
+            let mut segments = url
+                .path_segments_mut()
+                .map_err(|_| anyhow::anyhow!("WebDAV url can't be a base: {}", self.base_url))?;
+            segments.pop_if_empty();
+            for component in relative.components() {
+                match component {
+                    Component::Normal(segment) => {
+                        segments.push(segment.to_str().context("Non unicode path")?);
+                    }
+                    Component::CurDir => {}
+                    _ => bail!("Unsupported path for WebDAV: {}", path.display()),
+                }
+            }
+            if collection {
+                segments.push("");
+            }
+        }
+        Ok(url)
+    }
+
+    fn request(&self, method: Method, url: Url) -> reqwest::RequestBuilder {
+        let request = self.client.request(method, url);
+        match &self.username {
+            Some(username) => request.basic_auth(
+                username,
+                self.password
+                    .as_ref()
+                    .map(|password| password.expose_secret()),
+            ),
+            None => request,
+        }
+    }
+
+    async fn put(
+        self: Arc<Self>,
+        url: Url,
+        reader: DuplexStream,
+        completed: Arc<AtomicBool>,
+    ) -> Result<()> {
+        // the writer also hits EOF when it's dropped halfway through a download,
+        // fail the request in that case instead of storing a truncated file
+        let end_of_body = stream::once(async move {
+            (!completed.load(Ordering::Acquire)).then(|| Err(io::Error::other("upload cancelled")))
+        })
+        .filter_map(|end| async move { end });
+        let body = ReaderStream::new(reader).chain(end_of_body);
+
+        let response = self
+            .request(Method::PUT, url.clone())
+            .body(reqwest::Body::wrap_stream(body))
+            .send()
+            .await
+            .context("WebDAV PUT failed")?;
+        if !response.status().is_success() {
+            bail!("WebDAV PUT {url} failed: {}", response.status());
+        }
+        Ok(())
+    }
+
+    async fn create_collections(&self, dir: &Path) -> Result<()> {
+        let relative = dir.strip_prefix(&self.root).unwrap_or(dir);
+        let mut current = self.root.clone();
+        for component in relative.components() {
+            current.push(component);
+            if self.created_collections.lock().unwrap().contains(&current) {
+                continue;
+            }
+
+            let response = self
+                .request(
+                    Method::from_bytes(b"MKCOL").expect("valid method"),
+                    self.url(&current, true)?,
+                )
+                .send()
+                .await
+                .context("WebDAV MKCOL failed")?;
+            // 405 means the collection already exists
+            if !response.status().is_success()
+                && response.status() != StatusCode::METHOD_NOT_ALLOWED
+            {
+                bail!(
+                    "WebDAV MKCOL {} failed: {}",
+                    current.display(),
+                    response.status()
+                );
+            }
+            self.created_collections
+                .lock()
+                .unwrap()
+                .insert(current.clone());
+        }
+        Ok(())
+    }
+
+    async fn move_file(&self, from: &Path, to: &Path) -> Result<()> {
+        let response = self
+            .request(
+                Method::from_bytes(b"MOVE").expect("valid method"),
+                self.url(from, false)?,
+            )
+            .header("Destination", self.url(to, false)?.as_str())
+            .header("Overwrite", "T")
+            .send()
+            .await
+            .context("WebDAV MOVE failed")?;
+        if !response.status().is_success() {
+            bail!(
+                "WebDAV MOVE {} failed: {}",
+                from.display(),
+                response.status()
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,54 @@
+use anyhow::{Context, Result};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+use tracing_subscriber::layer::SubscriberExt;
+
+/// OpenTelemetry export of the `tracing` spans around update handling and
+/// downloads. Without it the spans are never collected and cost next to nothing.
+pub struct Telemetry {
+    provider: SdkTracerProvider,
+}
+
+impl Telemetry {
+    /// Starts exporting spans over OTLP/HTTP to `endpoint`
+    /// (e.g. `http://localhost:4318/v1/traces`).
+    pub async fn init(endpoint: String) -> Result<Self> {
+        // the exporter uses a blocking http client, which must not be created
+        // (or dropped) on the async runtime
+        let provider = tokio::task::spawn_blocking(move || {
+            let exporter = SpanExporter::builder()
+                .with_http()
+                .with_endpoint(endpoint)
+                .build()?;
+            anyhow::Ok(
+                SdkTracerProvider::builder()
+                    .with_batch_exporter(exporter)
+                    .with_resource(
+                        Resource::builder()
+                            .with_service_name(env!("CARGO_PKG_NAME"))
+                            .build(),
+                    )
+                    .build(),
+            )
+        })
+        .await??;
+
+        let subscriber = tracing_subscriber::registry().with(
+            tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME"))),
+        );
+        tracing::subscriber::set_global_default(subscriber)
+            .context("Failed to install tracing subscriber")?;
+
+        Ok(Self { provider })
+    }
+
+    /// Flushes the spans that haven't been exported yet.
+    pub async fn shutdown(self) {
+        match tokio::task::spawn_blocking(move || self.provider.shutdown()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => log::error!("Failed to shut down OpenTelemetry export: {e}"),
+            Err(e) => log::error!("Failed to shut down OpenTelemetry export: {e}"),
+        }
+    }
+}
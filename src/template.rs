@@ -0,0 +1,158 @@
+use anyhow::{bail, Result};
+
+/// Tokens understood by `filename_template`.
+pub const FILENAME_TOKENS: &[&str] = &[
+    "title",
+    "unique_id",
+    "page",
+    "date",
+    "message_date",
+    "download_date",
+    "channel",
+    "seq",
+    "performer",
+    "track_title",
+    "ocr",
+    "run_id",
+];
+
+/// Tokens understood by the arguments of `post_download_command`.
+pub const HOOK_TOKENS: &[&str] = &["path", "unique_id", "caption", "message_link"];
+
+/// Tokens understood by the arguments of `ocr_command`.
+pub const OCR_TOKENS: &[&str] = &["path"];
+
+/// Tokens understood by the arguments of a `conversions` command.
+pub const CONVERSION_TOKENS: &[&str] = &["input", "output"];
+
+/// Tokens that are enough on their own to keep two downloads from ending up
+/// with the same filename.
+pub const UNIQUE_FILENAME_TOKENS: &[&str] = &["unique_id", "seq"];
+
+/// Iterates over the `{token}` names used in `template`.
+pub fn tokens(template: &str) -> impl Iterator<Item = &str> {
+    template
+        .split('{')
+        .skip(1)
+        .filter_map(|part| part.split_once('}').map(|(token, _)| token))
+}
+
+/// Substitutes every `{token}` with the value returned by `value`, in a single
+/// pass so substituted text is never interpreted as a token itself.
+/// Tokens for which `value` returns `None` are kept verbatim.
+pub fn render(template: &str, value: impl Fn(&str) -> Option<String>) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let after_brace = &rest[start + 1..];
+        match after_brace
+            .split_once('}')
+            .and_then(|(token, tail)| value(token).map(|value| (value, tail)))
+        {
+            Some((value, tail)) => {
+                rendered.push_str(&value);
+                rest = tail;
+            }
+            None => {
+                rendered.push('{');
+                rest = after_brace;
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+pub fn validate_tokens(template: &str, known: &[&str], option: &str) -> Result<()> {
+    if let Some(unknown) = tokens(template).find(|token| !known.contains(token)) {
+        bail!(
+            "Unknown token {{{unknown}}} in {option}, expected one of: {}",
+            known.join(", ")
+        );
+    }
+    Ok(())
+}
+
+pub fn validate_filename_template(
+    template: &str,
+    allow_nonunique: bool,
+    option: &str,
+) -> Result<()> {
+    validate_tokens(template, FILENAME_TOKENS, option)?;
+
+    if !tokens(template).any(|token| UNIQUE_FILENAME_TOKENS.contains(&token)) {
+        if !allow_nonunique {
+            bail!(
+                "{option} \"{template}\" has no {{unique_id}} token, so different \
+                 files would overwrite each other. Add {{unique_id}} or set \
+                 allow_nonunique_template = true"
+            );
+        }
+        log::warn!(
+            "{option} \"{template}\" has no {{unique_id}} token: files with the same \
+             name WILL overwrite each other"
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_substitutes_known_tokens() {
+        let rendered = render("{title}_{unique_id}.{unknown}", |token| match token {
+            "title" => Some("Holiday".to_owned()),
+            "unique_id" => Some("AgAD".to_owned()),
+            _ => None,
+        });
+        assert_eq!(rendered, "Holiday_AgAD.{unknown}");
+    }
+
+    #[test]
+    fn render_doesnt_expand_substituted_text() {
+        let rendered = render("{title}-{unique_id}", |token| match token {
+            "title" => Some("{unique_id}".to_owned()),
+            "unique_id" => Some("AgAD".to_owned()),
+            _ => None,
+        });
+        assert_eq!(rendered, "{unique_id}-AgAD");
+    }
+
+    #[test]
+    fn render_keeps_unclosed_braces() {
+        let rendered = render("{title} {unique_id", |_| Some("x".to_owned()));
+        assert_eq!(rendered, "x {unique_id");
+    }
+
+    #[test]
+    fn render_formats_both_dates() {
+        let rendered = render(
+            "{message_date}_{download_date}_{unique_id}",
+            |token| match token {
+                "message_date" => Some("2024-01-02".to_owned()),
+                "download_date" => Some("2024-03-04".to_owned()),
+                "unique_id" => Some("AgAD".to_owned()),
+                _ => None,
+            },
+        );
+        assert_eq!(rendered, "2024-01-02_2024-03-04_AgAD");
+    }
+
+    #[test]
+    fn unknown_tokens_are_rejected() {
+        let error = validate_filename_template("{titel}_{unique_id}", false, "filename_template")
+            .unwrap_err();
+        assert!(error.to_string().contains("{titel}"), "{error}");
+    }
+
+    #[test]
+    fn templates_without_a_unique_token_are_rejected() {
+        assert!(validate_filename_template("{title}_{date}", false, "filename_template").is_err());
+        validate_filename_template("{title}_{date}", true, "filename_template").unwrap();
+        validate_filename_template("{title}_{unique_id}", false, "filename_template").unwrap();
+        validate_filename_template("{channel}_{seq}", false, "filename_template").unwrap();
+    }
+}